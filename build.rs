@@ -0,0 +1,32 @@
+// Build metadata for `--version`, /version and latency_probe_build_info.
+// LATENCY_PROBE_COMMIT can be set by builds without a git checkout (e.g. a
+// Docker build context that leaves out .git).
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let commit = std::env::var("LATENCY_PROBE_COMMIT").ok().or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=LATENCY_PROBE_COMMIT={}", commit.unwrap_or_else(|| "unknown".to_string()));
+
+    // e.g. "rustc 1.90.0 (1159e78c4 2025-09-14)" -> "1.90.0"
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).split_whitespace().nth(1).map(String::from))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=LATENCY_PROBE_RUSTC={}", rustc_version);
+
+    println!("cargo:rerun-if-env-changed=LATENCY_PROBE_COMMIT");
+    // A new commit moves HEAD or the branch it points to
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
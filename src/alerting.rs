@@ -0,0 +1,104 @@
+// Webhook alerting: an event bus consumer that notifies the owning team when
+// a target goes down or recovers.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, RwLock};
+use crate::config::ProbeConfig;
+use crate::events::{ProbeEvent, TargetState};
+
+#[derive(Debug, Serialize)]
+struct AlertPayload {
+    status: &'static str,
+    target: String,
+    // The address that went down or recovered, for a target with probe_all_addresses
+    address: Option<IpAddr>,
+    probe_type: &'static str,
+    host: Option<String>,
+    owner: Option<String>,
+    team: Option<String>,
+    runbook_url: Option<String>,
+    error: Option<String>,
+    timestamp: u64,
+}
+
+pub async fn run(config: Arc<RwLock<ProbeConfig>>, mut rx: broadcast::Receiver<ProbeEvent>) {
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(10)).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("alerting disabled, could not build HTTP client: {:?}", e);
+            return;
+        }
+    };
+    // Most recent error per target, attached to the "firing" notification
+    let mut last_errors: HashMap<(String, Option<IpAddr>, &'static str), String> = HashMap::new();
+
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                crate::metrics::events_dropped("alerting", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        match event {
+            ProbeEvent::Failed { target, address, probe_type, error, .. } => {
+                last_errors.insert((target, address, probe_type), error);
+            }
+            ProbeEvent::StateChanged { target, address, probe_type, from, to, .. } => {
+                // First successful probe after startup is not news
+                if from == TargetState::Unknown && to == TargetState::Up {
+                    continue;
+                }
+                let cfg = config.read().await;
+                let Some(alerting) = cfg.alerting.as_ref() else {
+                    continue;
+                };
+                let meta = cfg
+                    .targets
+                    .iter()
+                    .find(|t| t.name == target && t.probe_type() == probe_type);
+                let Some(webhook) = alerting.webhook_for(meta) else {
+                    continue;
+                };
+                let webhook = webhook.to_string();
+
+                let firing = to == TargetState::Down;
+                let error = if firing { last_errors.get(&(target.clone(), address, probe_type)).cloned() } else { None };
+                let payload = AlertPayload {
+                    status: if firing { "firing" } else { "resolved" },
+                    target,
+                    address,
+                    probe_type,
+                    host: meta.map(|t| t.host.clone()),
+                    owner: meta.and_then(|t| t.owner.clone()),
+                    team: meta.and_then(|t| t.team.clone()),
+                    runbook_url: meta.and_then(|t| t.runbook_url.clone()),
+                    error,
+                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                };
+                drop(cfg);
+
+                // Deliver in the background so a slow webhook can't make us lag
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let result = client
+                        .post(&webhook)
+                        .json(&payload)
+                        .send()
+                        .await
+                        .and_then(|r| r.error_for_status());
+                    if let Err(e) = result {
+                        tracing::error!("alert for {} to {} failed: {:?}", payload.target, webhook, e);
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+}
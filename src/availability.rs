@@ -0,0 +1,100 @@
+// Rolling availability per target: an event bus consumer that counts probe
+// outcomes in short time buckets and exports the success ratio over each
+// configured window, the same number a recording rule over
+// probe_success_total and probe_failure_total would give.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+use crate::config::ProbeConfig;
+use crate::events::ProbeEvent;
+use crate::prober;
+use crate::util::window_label;
+
+// Outcomes are counted per bucket, so a window's edge is this precise
+const BUCKET: Duration = Duration::from_secs(10);
+
+// How often the gauges are recomputed
+const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    // Bucket number since startup
+    index: u64,
+    successes: u64,
+    total: u64,
+}
+
+pub async fn run(config: Arc<RwLock<ProbeConfig>>, mut rx: broadcast::Receiver<ProbeEvent>) {
+    let start = Instant::now();
+    // Oldest first; only buckets that saw a probe are kept
+    let mut outcomes: HashMap<(String, Option<IpAddr>, &'static str), VecDeque<Bucket>> = HashMap::new();
+    let mut update = tokio::time::interval(UPDATE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let (target, address, probe_type, success) = match event {
+                    Ok(ProbeEvent::Completed { target, address, probe_type, .. }) => (target, address, probe_type, true),
+                    Ok(ProbeEvent::Failed { target, address, probe_type, .. }) => (target, address, probe_type, false),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        crate::metrics::events_dropped("availability", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let index = bucket_index(start);
+                let buckets = outcomes.entry((target, address, probe_type)).or_default();
+                if buckets.back().is_none_or(|b| b.index != index) {
+                    buckets.push_back(Bucket { index, successes: 0, total: 0 });
+                }
+                if let Some(bucket) = buckets.back_mut() {
+                    bucket.total += 1;
+                    bucket.successes += u64::from(success);
+                }
+            }
+            _ = update.tick() => {
+                let (windows, targets) = {
+                    let config = config.read().await;
+                    let windows = config.availability.as_ref().map(|a| a.windows_sec.clone()).unwrap_or_default();
+                    (windows, config.targets.clone())
+                };
+                let longest = windows.iter().copied().max().unwrap_or(0);
+                let now = bucket_index(start);
+                let mut ratios = Vec::new();
+                outcomes.retain(|(target, address, probe_type), buckets| {
+                    // Removed or reconfigured, or an address DNS rotated away
+                    if !prober::is_current(&targets, target, *address, probe_type) {
+                        return false;
+                    }
+                    // Drop what no window reaches any more
+                    let oldest = (now + 1).saturating_sub(longest.div_ceil(BUCKET.as_secs()));
+                    while buckets.front().is_some_and(|b| b.index < oldest) {
+                        buckets.pop_front();
+                    }
+                    for &window_sec in &windows {
+                        // The current, partly filled bucket counts as one
+                        let first = (now + 1).saturating_sub(window_sec.div_ceil(BUCKET.as_secs()));
+                        let (successes, total) = buckets
+                            .iter()
+                            .filter(|b| b.index >= first)
+                            .fold((0, 0), |(s, t), b| (s + b.successes, t + b.total));
+                        // No probes in the window: no ratio rather than a made-up one
+                        if total > 0 {
+                            ratios.push((target.clone(), *address, *probe_type, window_label(window_sec), successes as f64 / total as f64));
+                        }
+                    }
+                    !buckets.is_empty()
+                });
+                crate::metrics::set_success_ratios(&ratios);
+            }
+        }
+    }
+}
+
+fn bucket_index(start: Instant) -> u64 {
+    start.elapsed().as_secs() / BUCKET.as_secs()
+}
@@ -0,0 +1,138 @@
+// Periodic latency baseline recalibration. Baselines are persisted to the
+// state dir so chronic drift is detected across restarts, and every run logs
+// the targets whose typical latency moved more than the configured threshold.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use crate::config::{BaselineConfig, ProbeConfig};
+use crate::history::{self, percentile};
+use crate::timestamp::monotonic_ns;
+
+const BASELINE_FILE: &str = "baselines.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Baseline {
+    pub samples: usize,
+    pub min_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    // Unix seconds
+    pub computed_at: u64,
+}
+
+/// Keyed by "target/probe_type", or "target@address/probe_type" per address
+type Baselines = BTreeMap<String, Baseline>;
+
+pub fn state_dir() -> PathBuf {
+    PathBuf::from(std::env::var("STATE_DIR").unwrap_or_else(|_| "state".to_string()))
+}
+
+pub async fn run(config: Arc<RwLock<ProbeConfig>>) {
+    let path = state_dir().join(BASELINE_FILE);
+    let mut previous = match load(&path).await {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::debug!("no previous baselines loaded from {}: {:?}", path.display(), e);
+            Baselines::new()
+        }
+    };
+
+    loop {
+        // Re-read each round so enabling/tuning via config reload takes effect
+        let settings = config.read().await.baseline.clone();
+        let Some(settings) = settings else {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        };
+        tokio::time::sleep(Duration::from_secs(settings.recalibrate_interval_sec.max(1))).await;
+
+        let current = compute(&settings);
+        report(&previous, &current, &settings);
+        if let Err(e) = save(&path, &current).await {
+            tracing::error!("Error saving baselines to {}: {:?}", path.display(), e);
+        }
+        // Targets without enough fresh samples keep their old baseline
+        for (key, baseline) in current {
+            previous.insert(key, baseline);
+        }
+    }
+}
+
+fn compute(settings: &BaselineConfig) -> Baselines {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    // Only samples taken since the previous recalibration count
+    let window_ns = Duration::from_secs(settings.recalibrate_interval_sec).as_nanos();
+    let since = monotonic_ns().saturating_sub(window_ns);
+    history::snapshot()
+        .into_iter()
+        .filter_map(|((target, address, probe_type), samples)| {
+            let mut latencies: Vec<f64> = samples
+                .iter()
+                .filter(|s| s.timestamp_ns >= since)
+                .filter_map(|s| s.latency_ms)
+                .collect();
+            if latencies.len() < settings.min_samples {
+                return None;
+            }
+            latencies.sort_by(|a, b| a.total_cmp(b));
+            let baseline = Baseline {
+                samples: latencies.len(),
+                min_ms: latencies[0],
+                p50_ms: percentile(&latencies, 0.50)?,
+                p90_ms: percentile(&latencies, 0.90)?,
+                p99_ms: percentile(&latencies, 0.99)?,
+                computed_at: now,
+            };
+            let key = match address {
+                Some(address) => format!("{}@{}/{}", target, address, probe_type),
+                None => format!("{}/{}", target, probe_type),
+            };
+            Some((key, baseline))
+        })
+        .collect()
+}
+
+fn report(previous: &Baselines, current: &Baselines, settings: &BaselineConfig) {
+    let mut shifted = 0;
+    for (key, new) in current {
+        let Some(old) = previous.get(key) else {
+            continue;
+        };
+        let change = |old: f64, new: f64| if old > 0.0 { (new - old) / old * 100.0 } else { 0.0 };
+        let p50_change = change(old.p50_ms, new.p50_ms);
+        let p90_change = change(old.p90_ms, new.p90_ms);
+        if p50_change.abs() >= settings.shift_threshold_percent || p90_change.abs() >= settings.shift_threshold_percent {
+            shifted += 1;
+            tracing::warn!(
+                "baseline shift for {}: p50 {:.3}ms -> {:.3}ms ({:+.1}%), p90 {:.3}ms -> {:.3}ms ({:+.1}%)",
+                key, old.p50_ms, new.p50_ms, p50_change, old.p90_ms, new.p90_ms, p90_change
+            );
+        }
+    }
+    tracing::info!(
+        "baseline recalibrated for {} targets, {} shifted by more than {}%",
+        current.len(), shifted, settings.shift_threshold_percent
+    );
+}
+
+async fn load(path: &std::path::Path) -> Result<Baselines> {
+    let content = tokio::fs::read_to_string(path).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+async fn save(path: &std::path::Path, baselines: &Baselines) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    // Write-then-rename so a crash never leaves a truncated file behind
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(baselines)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+// What this binary was built from, filled in by build.rs; shown by
+// `--version`, /version and latency_probe_build_info.
+
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const COMMIT: &str = env!("LATENCY_PROBE_COMMIT");
+pub const RUSTC: &str = env!("LATENCY_PROBE_RUSTC");
+
+/// `--version` output, e.g. "0.0.1 (commit 1a2b3c4d5e6f, rustc 1.90.0)"
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (commit ",
+    env!("LATENCY_PROBE_COMMIT"),
+    ", rustc ",
+    env!("LATENCY_PROBE_RUSTC"),
+    ")",
+);
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub rustc: &'static str,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo { version: VERSION, commit: COMMIT, rustc: RUSTC }
+}
@@ -0,0 +1,97 @@
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use anyhow::Result;
+use std::net::{IpAddr, SocketAddr};
+use crate::service::ServiceOptions;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "latency-probe",
+    about = "A Rust probe for monitor latency on multiple target host",
+    version = crate::build_info::VERSION,
+    long_version = crate::build_info::LONG_VERSION,
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub run: RunArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options of the probe itself. Each falls back to the environment variable
+/// named in its help, so existing env-based deployments keep working.
+#[derive(Debug, Clone, clap::Args)]
+pub struct RunArgs {
+    /// Local config file, JSON or YAML
+    #[arg(long, short = 'c', env = "TARGET_CONFIG", default_value = "targets.json", value_name = "FILE", global = true)]
+    pub config: String,
+    /// Address the Prometheus /metrics endpoint listens on
+    #[arg(long, env = "METRICS_ADDR", default_value = "0.0.0.0:9100", global = true)]
+    pub metrics_addr: SocketAddr,
+    /// Web config file enabling TLS and basic auth on the metrics endpoint, as for Prometheus exporters
+    #[arg(long, env = "WEB_CONFIG_FILE", value_name = "FILE", global = true)]
+    pub web_config_file: Option<String>,
+    /// Probe interval in milliseconds, instead of the config's probe_interval_ms
+    #[arg(long, env = "PROBE_INTERVAL_MS", value_name = "MS", global = true)]
+    pub interval: Option<u64>,
+    /// Log level (error, warn, info, debug, trace), instead of the config's log_level
+    #[arg(long, env = "LOG_LEVEL", global = true)]
+    pub log_level: Option<tracing::Level>,
+    #[command(flatten)]
+    pub service: ServiceOptions,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the probe (default when no subcommand is given)
+    Run,
+    /// Print shell completions for the given shell to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page (roff) to stdout
+    Man,
+    /// Answer `echo` probes from other latency-probe instances
+    Server(ServerArgs),
+    /// Validate the config file and exit, without probing anything
+    Check,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ServerArgs {
+    /// Port to listen on (UDP and TCP)
+    #[arg(long, default_value_t = 9000)]
+    pub port: u16,
+    /// Address to listen on; `::` also accepts IPv4 where the system allows it
+    #[arg(long, default_value = "::")]
+    pub bind: IpAddr,
+    /// Send back at most this many bytes of each datagram or frame (default: all of it);
+    /// at least the 36-byte echo header
+    #[arg(long, value_parser = parse_reflect_bytes)]
+    pub reflect_bytes: Option<usize>,
+    /// Only answer over UDP
+    #[arg(long)]
+    pub no_tcp: bool,
+}
+
+/// Replies cut short of the header can't be matched to their probe
+fn parse_reflect_bytes(value: &str) -> Result<usize, String> {
+    let bytes: usize = value.parse().map_err(|e| format!("{}", e))?;
+    if bytes < crate::prober::echo::HEADER_LEN {
+        return Err(format!("must be at least {} bytes, the echo header", crate::prober::echo::HEADER_LEN));
+    }
+    Ok(bytes)
+}
+
+pub fn print_completions(shell: Shell) {
+    let mut cmd = Cli::command();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+}
+
+pub fn print_man() -> Result<()> {
+    let man = clap_mangen::Man::new(Cli::command());
+    man.render(&mut std::io::stdout())?;
+    Ok(())
+}
@@ -0,0 +1,54 @@
+// Cap on probes running at once (max_concurrent_probes). Without it every
+// round of every target spawns its probes right away, and slow targets pile
+// up tasks, sockets and memory until a small probe host runs out.
+
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use crate::metrics;
+
+struct Limit {
+    max: usize,
+    permits: Arc<Semaphore>,
+}
+
+// None when unlimited. A new limit gets a new semaphore, so probes admitted
+// under the old one finish on its permits and briefly run alongside those
+// of the new one.
+static LIMIT: Lazy<Mutex<Option<Limit>>> = Lazy::new(|| Mutex::new(None));
+
+/// A probe's place under the limit, held until dropped
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Apply `max_concurrent_probes`; called on start and after every reload
+pub fn set_limit(limit: Option<usize>) {
+    let mut current = LIMIT.lock().unwrap();
+    if current.as_ref().map(|l| l.max) != limit {
+        // Zero is rejected on load; it would shed every probe
+        *current = limit.filter(|&max| max > 0).map(|max| Limit { max, permits: Arc::new(Semaphore::new(max)) });
+    }
+}
+
+/// Wait for a probe slot. None when none freed up by `deadline`, usually
+/// the start of the target's next round: the probe is shed rather than
+/// queued behind its own next round.
+pub async fn admit(probe_type: &'static str, deadline: Instant) -> Option<Slot> {
+    let Some(permits) = LIMIT.lock().unwrap().as_ref().map(|l| l.permits.clone()) else {
+        return Some(Slot { _permit: None });
+    };
+    if let Ok(permit) = permits.clone().try_acquire_owned() {
+        return Some(Slot { _permit: Some(permit) });
+    }
+    let _queued = metrics::ProbeQueued::start(probe_type);
+    match tokio::time::timeout_at(deadline, permits.acquire_owned()).await {
+        Ok(Ok(permit)) => Some(Slot { _permit: Some(permit) }),
+        // Timed out, or the semaphore was closed, which nothing does
+        _ => {
+            metrics::probe_shed(probe_type);
+            None
+        }
+    }
+}
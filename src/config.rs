@@ -2,11 +2,26 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::{RwLock, watch};
 use anyhow::Result;
+use once_cell::sync::Lazy;
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_appconfigdata::Client as AppConfigClient;
+use crate::config_source::ConfigSource;
+use crate::config_source::consul::ConsulKv;
+use crate::config_source::etcd::EtcdKv;
+use crate::config_source::http::HttpUrl;
+use crate::config_source::kubernetes::Kubernetes;
+use crate::config_source::s3::S3Object;
+use crate::config_source::ssm::SsmParameters;
 use crate::prober::ProbeKind;
-use crate::util::parse_host_port;
-use std::path::Path;
+use crate::prober::echo::{EchoTransport, PayloadPattern};
+use crate::prober::http::{BodyRegex, ConnectionMode, HttpVersion, MeasureUntil, StatusCodeRange};
+use crate::prober::tcp_connect::{TcpClose, TcpMode};
+use crate::util::AddressFamily;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -16,17 +31,688 @@ pub struct TargetConfig {
     pub host: String,
     pub port: Option<u16>,
     // Remove the url field - we'll construct it from host + port
+    // Overrides default_timeout_ms for probes that honour a timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    // Overrides probe_interval_ms for this target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<u64>,
+    // Start probes this far into each interval instead of at the offset
+    // probe_spread would give the target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset_ms: Option<u64>,
+    // Probe less often while the target keeps failing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<BackoffConfig>,
+    // false keeps the target in the config without probing it, e.g. during maintenance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    // Extra labels on this target's latency and failure series, e.g. {"dc": "fra1"}
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    // Ownership metadata, exported as target info and used for alert routing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runbook_url: Option<String>,
+
+    // any (default), ipv4, ipv6, prefer_ipv4 or prefer_ipv6
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_family: Option<AddressFamily>,
+    // Probe every address the host resolves to, each with its own `address`
+    // label, instead of only the first one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe_all_addresses: Option<bool>,
+    // Set on the copies probe_all_addresses makes, one per address; never
+    // read from a config file
+    #[serde(skip)]
+    pub address: Option<IpAddr>,
+    // IP TTL / IPv6 hop limit for ICMP, TCP and UDP probes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    // DSCP class (0-63, e.g. 46 for EF) marked on probe packets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+    // Local address and network interface (SO_BINDTODEVICE, Linux only) TCP
+    // probes connect from, to measure a specific uplink of a multi-homed host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_address: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+
+    // Options of the probe kind; a block for any other kind is a config error
+    #[serde(default, skip_serializing_if = "IcmpOptions::is_empty")]
+    pub icmp: IcmpOptions,
+    #[serde(default, skip_serializing_if = "TcpOptions::is_empty")]
+    pub tcp: TcpOptions,
+    #[serde(default, skip_serializing_if = "EchoOptions::is_empty")]
+    pub echo: EchoOptions,
+    #[serde(default, skip_serializing_if = "HttpOptions::is_empty")]
+    pub http: HttpOptions,
+}
+
+// Option blocks of TargetConfig and the probe kind each belongs to
+const KIND_BLOCKS: &[(&str, ProbeKind)] = &[
+    ("icmp", ProbeKind::Icmp),
+    ("tcp", ProbeKind::TcpConnect),
+    ("echo", ProbeKind::Echo),
+    ("http", ProbeKind::Http),
+];
+
+/// `icmp` targets
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct IcmpOptions {
+    // Echo payload in bytes (default 2), e.g. 1400 to exercise MTU problems
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_size: Option<usize>,
+}
+
+/// `tcpconnect` targets
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct TcpOptions {
+    // Connect every probe (default) or keep one connection open and time
+    // small writes on it; payload is what gets written (default "\r\n")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<TcpMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    // Teardown: "fin" (default) or "rst" to leave no TIME_WAIT behind;
+    // include_teardown adds the close to the measured time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub close: Option<TcpClose>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_teardown: Option<bool>,
+    // Send payload with TCP Fast Open and compare it with a regular
+    // connection (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fast_open: Option<bool>,
+}
+
+/// `echo` targets
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct EchoOptions {
+    // Whole UDP payload in bytes (default and minimum 36, the header)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_size: Option<usize>,
+    // What fills the datagram after its header (default counting)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_pattern: Option<PayloadPattern>,
+    // udp (default) or tcp, for paths that drop UDP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<EchoTransport>,
+}
+
+/// `http` targets
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct HttpOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    // Acceptable status codes; empty accepts any response
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expected_status: Vec<StatusCodeRange>,
+    // Response body validation; both must hold when both are set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_contains: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_regex: Option<BodyRegex>,
+    // Redirect policy: follow (default true), up to max_redirects (default 10),
+    // and whether time spent on redirect hops counts toward latency (default true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_redirects: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_redirects: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_redirect_time: Option<bool>,
+    // Force "1.1" or "2"; by default HTTP/2 is used when the server offers it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_version: Option<HttpVersion>,
+    // Ask for compressed responses (true) or explicitly refuse them (false)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<bool>,
+    // Where the clock stops (default full_body)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measure: Option<MeasureUntil>,
+    // Reuse a kept-alive connection (default) or open a new one per probe;
+    // the two are exported under different probe_type labels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_mode: Option<ConnectionMode>,
+    // Egress proxy (http://, https://, socks5:// or socks5h:// URL); without
+    // one the standard HTTP(S)_PROXY environment variables apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_password: Option<SecretValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<HttpAuth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    // Connect to this address instead of resolving the URL's host; Host header
+    // and SNI still carry the hostname
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolve_to: Option<IpAddr>,
+}
+
+impl IcmpOptions {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl TcpOptions {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl EchoOptions {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl HttpOptions {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct ProbeConfig {
+    // Schema version; documents without one are version 1
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     pub probe_interval_ms: u64,
     pub default_timeout_ms: u64,
+    #[serde(default)]
     pub targets: Vec<TargetConfig>,
+    // Target fields every target and group starts from, e.g. timeout_ms,
+    // labels or http.tls; kind blocks only reach targets of that kind
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub defaults: serde_json::Map<String, serde_json::Value>,
+    // Targets sharing settings; expanded into `targets` when the config is loaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<TargetGroup>,
     #[serde(default = "default_log_level")]
     pub log_level: String,
     #[serde(default = "default_enable_latency_history")]
     pub enable_latency_history: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerting: Option<AlertingConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<BaselineConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_summary: Option<LatencySummaryConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub availability: Option<AvailabilityConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_rollup: Option<LatencyRollupConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe_spread: Option<ProbeSpreadConfig>,
+    // Probes allowed to run at once; unlimited when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_probes: Option<usize>,
+}
+
+/// Targets that share most of their settings. Each member starts from
+/// `defaults` and overrides what it needs, usually just `host` and `port`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct TargetGroup {
+    pub name: String,
+    // Any target fields, e.g. kind, timeout_ms, owner or headers
+    #[serde(default)]
+    pub defaults: serde_json::Map<String, serde_json::Value>,
+    pub targets: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl TargetGroup {
+    /// The group's targets with the defaults applied. Members without a name
+    /// are called `<group>-<host>`, or `<group>-<host>-<port>` when they set a port.
+    fn expand(&self) -> Result<Vec<TargetConfig>> {
+        self.targets.iter().enumerate().map(|(i, member)| {
+            let mut target = serde_json::Value::Object(self.defaults.clone());
+            merge_config_document(&mut target, serde_json::Value::Object(member.clone()));
+            if let Some(fields) = target.as_object_mut()
+                && !member.contains_key("name")
+            {
+                let host = fields.get("host").and_then(|h| h.as_str()).unwrap_or_default();
+                let name = match member.get("port") {
+                    Some(port) => format!("{}-{}-{}", self.name, host, port),
+                    None => format!("{}-{}", self.name, host),
+                };
+                fields.insert("name".to_string(), name.into());
+            }
+            serde_json::from_value(target)
+                .map_err(|e| anyhow::anyhow!("groups ({:?}): targets[{}]: {}", self.name, i, e))
+        }).collect()
+    }
+}
+
+/// Periodic baseline recalibration; disabled when the block is absent
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct BaselineConfig {
+    #[serde(default = "default_recalibrate_interval_sec")]
+    pub recalibrate_interval_sec: u64,
+    // Report a target when p50 or p90 moved by at least this much
+    #[serde(default = "default_shift_threshold_percent")]
+    pub shift_threshold_percent: f64,
+    // Targets with fewer successful samples are skipped
+    #[serde(default = "default_min_samples")]
+    pub min_samples: usize,
+}
+
+/// Latency quantiles per target as a Prometheus summary, alongside or
+/// instead of the histogram; disabled when the block is absent
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct LatencySummaryConfig {
+    #[serde(default = "default_summary_quantiles")]
+    pub quantiles: Vec<f64>,
+    // Quantiles cover the samples of this sliding window
+    #[serde(default = "default_summary_max_age_sec")]
+    pub max_age_sec: u64,
+}
+
+/// Rolling success ratio per target; disabled when the block is absent
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct AvailabilityConfig {
+    // Each window is exported as its own series, e.g. window="1h"
+    #[serde(default = "default_availability_windows_sec")]
+    pub windows_sec: Vec<u64>,
+}
+
+/// Min, max and average latency per target over sliding windows, for when
+/// the histogram is too many series; disabled when the block is absent
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct LatencyRollupConfig {
+    // Each window is exported as its own series, e.g. window="5m"
+    #[serde(default = "default_rollup_windows_sec")]
+    pub windows_sec: Vec<u64>,
+}
+
+/// Start the probes of a round spread across the interval instead of all at
+/// once; disabled when the block is absent
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ProbeSpreadConfig {
+    #[serde(default)]
+    pub mode: SpreadMode,
+    // Share of the interval the starts are spread over, so the last probes
+    // of a round still have time to finish before the next one
+    #[serde(default = "default_spread_percent")]
+    pub spread_percent: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpreadMode {
+    // Evenly spaced in target order
+    #[default]
+    Even,
+    // A random offset per target, drawn again every round
+    Random,
+}
+
+/// Exponential backoff of a failing target's probes; the full rate returns
+/// with the first success. Disabled when the block is absent.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct BackoffConfig {
+    // Consecutive failed rounds before the interval starts to grow
+    #[serde(default = "default_backoff_after_failures")]
+    pub after_failures: u32,
+    // Each further failed round multiplies the interval by this much
+    #[serde(default = "default_backoff_multiplier")]
+    pub multiplier: f64,
+    #[serde(default = "default_backoff_max_interval_ms")]
+    pub max_interval_ms: u64,
+}
+
+impl BackoffConfig {
+    /// Time between probes of a target with `interval` after `failures`
+    /// failed rounds in a row
+    pub fn interval(&self, interval: Duration, failures: u32) -> Duration {
+        // A healthy target is never backed off, whatever after_failures says
+        if failures == 0 || failures < self.after_failures {
+            return interval;
+        }
+        let exponent = (failures - self.after_failures.max(1) + 1).min(64) as i32;
+        // At least 1, so a multiplier below 1 (negative, NaN), which validation
+        // rejects, can't shrink the interval or turn it negative
+        let growth = self.multiplier.powi(exponent).max(1.0);
+        let max = Duration::from_millis(self.max_interval_ms).max(interval);
+        // In seconds, where an overflowing growth is infinite and capped by
+        // min rather than a panic
+        Duration::from_secs_f64((interval.as_secs_f64() * growth).min(max.as_secs_f64()))
+    }
+}
+
+/// Config schema version this build reads. Bump it with every incompatible
+/// schema change and append the step that upgrades older documents to MIGRATIONS.
+pub const CONFIG_VERSION: u32 = 2;
+
+// MIGRATIONS[i] rewrites a version i + 1 document into version i + 2
+const MIGRATIONS: &[fn(&mut serde_json::Value)] = &[migrate_kind_options];
+
+/// Version 2 moved the options of each probe kind from the target itself
+/// into a block named after the kind, e.g. `tcp_mode` to `tcp.mode`
+fn migrate_kind_options(document: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    // (old name, block, new name); payload_size is decided by the kind
+    const MOVED: &[(&str, &str, &str)] = &[
+        ("tcp_mode", "tcp", "mode"),
+        ("tcp_payload", "tcp", "payload"),
+        ("tcp_close", "tcp", "close"),
+        ("include_teardown", "tcp", "include_teardown"),
+        ("tcp_fast_open", "tcp", "fast_open"),
+        ("payload_pattern", "echo", "payload_pattern"),
+        ("echo_transport", "echo", "transport"),
+        ("method", "http", "method"),
+        ("headers", "http", "headers"),
+        ("body", "http", "body"),
+        ("expected_status", "http", "expected_status"),
+        ("body_contains", "http", "body_contains"),
+        ("body_regex", "http", "body_regex"),
+        ("follow_redirects", "http", "follow_redirects"),
+        ("max_redirects", "http", "max_redirects"),
+        ("include_redirect_time", "http", "include_redirect_time"),
+        ("http_version", "http", "http_version"),
+        ("compression", "http", "compression"),
+        ("measure", "http", "measure"),
+        ("connection_mode", "http", "connection_mode"),
+        ("proxy", "http", "proxy"),
+        ("proxy_username", "http", "proxy_username"),
+        ("proxy_password", "http", "proxy_password"),
+        ("auth", "http", "auth"),
+        ("tls", "http", "tls"),
+        ("resolve_to", "http", "resolve_to"),
+    ];
+
+    // `kind` comes from the target or, for group members, the group defaults
+    fn migrate_target(target: &mut Value, inherited_kind: Option<&str>) {
+        let Some(fields) = target.as_object_mut() else {
+            return;
+        };
+        let kind = fields.get("kind").and_then(Value::as_str).or(inherited_kind).unwrap_or_default().to_string();
+        let mut moves: Vec<(&str, &str, &str)> = MOVED.to_vec();
+        moves.push(("payload_size", if kind == "echo" { "echo" } else { "icmp" }, "payload_size"));
+        for (old, block, new) in moves {
+            if let Some(value) = fields.remove(old) {
+                let block = fields.entry(block).or_insert_with(|| Value::Object(Default::default()));
+                if let Some(block) = block.as_object_mut() {
+                    block.insert(new.to_string(), value);
+                }
+            }
+        }
+    }
+
+    for target in document.get_mut("targets").and_then(Value::as_array_mut).into_iter().flatten() {
+        migrate_target(target, None);
+    }
+    for group in document.get_mut("groups").and_then(Value::as_array_mut).into_iter().flatten() {
+        let kind = group["defaults"]["kind"].as_str().map(str::to_string);
+        if let Some(defaults) = group.get_mut("defaults") {
+            migrate_target(defaults, None);
+        }
+        for member in group.get_mut("targets").and_then(Value::as_array_mut).into_iter().flatten() {
+            migrate_target(member, kind.as_deref());
+        }
+    }
+}
+
+fn default_config_version() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+struct VersionOnly {
+    #[serde(default = "default_config_version")]
+    version: u32,
+}
+
+fn check_config_version(version: u32) -> Result<()> {
+    if version == 0 {
+        anyhow::bail!("version: config versions start at 1");
+    }
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "version: config version {} is newer than this latency-probe understands (up to {}); upgrade latency-probe",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Bring a config document of any supported version up to CONFIG_VERSION
+fn migrate_config_document(document: &mut serde_json::Value) -> Result<()> {
+    let version = match document.get("version") {
+        None => default_config_version(),
+        Some(v) => v.as_u64().and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("version: expected a positive integer, found {}", v))?,
+    };
+    check_config_version(version)?;
+    for migrate in &MIGRATIONS[version as usize - 1..] {
+        migrate(document);
+    }
+    if let Some(fields) = document.as_object_mut() {
+        fields.insert("version".to_string(), CONFIG_VERSION.into());
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` (or `${VAR:-default}`) in a config document with the
+/// environment variable's value before it is parsed, so per-region values can
+/// be injected without templating the file. `$${` stands for a literal `${`.
+/// Variables that are unset and have no default are reported together.
+pub(crate) fn interpolate_env(content: &str) -> Result<Cow<'_, str>> {
+    if !content.contains("${") {
+        return Ok(Cow::Borrowed(content));
+    }
+    let mut output = String::with_capacity(content.len());
+    let mut missing = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("${") {
+        // `$${` escapes the reference
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        output.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            anyhow::bail!("unterminated ${{ in config");
+        };
+        let reference = &rest[start + 2..start + end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            anyhow::bail!("invalid environment variable reference ${{{}}} in config", reference);
+        }
+        match (std::env::var(name), default) {
+            (Ok(value), _) => output.push_str(&value),
+            (Err(_), Some(default)) => output.push_str(default),
+            (Err(_), None) if !missing.iter().any(|m| m == name) => missing.push(name.to_string()),
+            (Err(_), None) => {}
+        }
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+    if !missing.is_empty() {
+        anyhow::bail!("config references unset environment variables: {}", missing.join(", "));
+    }
+    Ok(Cow::Owned(output))
+}
+
+fn default_recalibrate_interval_sec() -> u64 {
+    3600
+}
+
+fn default_shift_threshold_percent() -> f64 {
+    20.0
+}
+
+fn default_min_samples() -> usize {
+    30
+}
+
+fn default_summary_quantiles() -> Vec<f64> {
+    vec![0.5, 0.9, 0.99]
+}
+
+fn default_summary_max_age_sec() -> u64 {
+    600
+}
+
+fn default_availability_windows_sec() -> Vec<u64> {
+    vec![300, 3600, 86400]
+}
+
+fn default_rollup_windows_sec() -> Vec<u64> {
+    vec![60, 300]
+}
+
+fn default_spread_percent() -> f64 {
+    90.0
+}
+
+fn default_backoff_after_failures() -> u32 {
+    3
+}
+
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
+
+fn default_backoff_max_interval_ms() -> u64 {
+    60_000
+}
+
+/// Credential written inline, as `{"env": "VAR"}`, `{"file": "/path"}` or
+/// `{"vault": "secret/data/app", "field": "token"}`. References are resolved
+/// when the probe runs, so secrets stay out of the config file and rotated
+/// values are picked up without a reload.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum SecretValue {
+    Literal(String),
+    Env { env: String },
+    File { file: String },
+    Vault { vault: String, field: String },
+}
+
+impl SecretValue {
+    pub async fn resolve(&self) -> Result<String> {
+        match self {
+            SecretValue::Literal(value) => Ok(value.clone()),
+            SecretValue::Env { env } => std::env::var(env)
+                .map_err(|_| anyhow::anyhow!("environment variable {} is not set", env)),
+            // Files written by hand or by a secrets agent usually end in a newline
+            SecretValue::File { file } => Ok(fs::read_to_string(file).await
+                .map_err(|e| anyhow::anyhow!("cannot read secret file {}: {}", file, e))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+            SecretValue::Vault { vault, field } => crate::secrets::vault_field(vault, field).await,
+        }
+    }
+}
+
+// Keep inline secrets out of logs
+impl std::fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretValue::Literal(_) => f.write_str("\"***\""),
+            SecretValue::Env { env } => write!(f, "env:{}", env),
+            SecretValue::File { file } => write!(f, "file:{}", file),
+            SecretValue::Vault { vault, field } => write!(f, "vault:{}#{}", vault, field),
+        }
+    }
+}
+
+/// HTTP probe credentials
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HttpAuth {
+    Basic {
+        username: SecretValue,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<SecretValue>,
+    },
+    Bearer {
+        token: SecretValue,
+    },
+    ApiKey {
+        header: String,
+        value: SecretValue,
+    },
+}
+
+/// TLS settings for HTTPS probes
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct TlsConfig {
+    // Accept any server certificate; the handshake is still timed
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    // PEM bundle trusted instead of the built-in Mozilla roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    // PEM client certificate chain and private key for mutual TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+    // "1.2" (default) or "1.3"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<String>,
+}
+
+/// Webhook notifications on target state changes
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct AlertingConfig {
+    // Used when no route matches; no notification is sent if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_webhook: Option<String>,
+    #[serde(default)]
+    pub routes: Vec<AlertRoute>,
+}
+
+/// First route whose owner/team match the target wins; unset fields match anything
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct AlertRoute {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    pub webhook: String,
+}
+
+impl AlertingConfig {
+    pub fn webhook_for(&self, target: Option<&TargetConfig>) -> Option<&str> {
+        let matches = |want: &Option<String>, have: Option<&String>| match want {
+            Some(w) => have == Some(w),
+            None => true,
+        };
+        self.routes
+            .iter()
+            .find(|r| {
+                matches(&r.owner, target.and_then(|t| t.owner.as_ref()))
+                    && matches(&r.team, target.and_then(|t| t.team.as_ref()))
+            })
+            .map(|r| r.webhook.as_str())
+            .or(self.default_webhook.as_deref())
+    }
 }
 
 fn default_log_level() -> String {
@@ -38,6 +724,36 @@ fn default_enable_latency_history() -> bool {
 }
 
 impl ProbeConfig {
+    /// Move the targets of every group into `targets`, after the ones listed there
+    fn expand_groups(&mut self) -> Result<()> {
+        for group in std::mem::take(&mut self.groups) {
+            self.targets.extend(group.expand()?);
+        }
+        Ok(())
+    }
+
+    /// Fill in what targets don't set from `defaults`. Group defaults were
+    /// applied already and take precedence; applying twice changes nothing.
+    pub(crate) fn apply_defaults(&mut self) -> Result<()> {
+        if self.defaults.is_empty() {
+            return Ok(());
+        }
+        for (i, target) in self.targets.iter_mut().enumerate() {
+            let own_block = KIND_BLOCKS.iter().find(|(_, kind)| *kind == target.kind).map(|(block, _)| *block);
+            let defaults: serde_json::Map<_, _> = self.defaults.iter()
+                .filter(|(key, _)| {
+                    !KIND_BLOCKS.iter().any(|(block, _)| block == key) || own_block == Some(key.as_str())
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            let mut merged = serde_json::Value::Object(defaults);
+            merge_config_document(&mut merged, serde_json::to_value(&*target)?);
+            *target = serde_json::from_value(merged)
+                .map_err(|e| anyhow::anyhow!("defaults: applied to targets[{}] ({:?}): {}", i, target.name, e))?;
+        }
+        Ok(())
+    }
+
     /// Get the log level as a tracing::Level
     pub fn get_tracing_level(&self) -> Result<tracing::Level> {
         match self.log_level.to_lowercase().as_str() {
@@ -54,29 +770,211 @@ impl ProbeConfig {
     pub fn validate_log_level(&self) -> Result<()> {
         self.get_tracing_level().map(|_| ())
     }
+
+    /// Fail with every problem of the config; loads and reloads apply no
+    /// config that has any
+    pub fn validate(&self) -> Result<()> {
+        match self.problems() {
+            problems if problems.is_empty() => Ok(()),
+            problems => anyhow::bail!("{}", problems.join("; ")),
+        }
+    }
+
+    /// Mistakes that parse fine but make the config unusable or ambiguous,
+    /// located by their path in the document
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.validate_log_level() {
+            problems.push(format!("log_level: {}", e));
+        }
+        if self.probe_interval_ms == 0 {
+            problems.push("probe_interval_ms: must be greater than 0".to_string());
+        }
+        if let Some(summary) = &self.latency_summary {
+            for (i, q) in summary.quantiles.iter().enumerate() {
+                if !(0.0..=1.0).contains(q) {
+                    problems.push(format!("latency_summary.quantiles[{}]: {} is not between 0 and 1", i, q));
+                }
+            }
+            if summary.max_age_sec == 0 {
+                problems.push("latency_summary.max_age_sec: must be greater than 0".to_string());
+            }
+        }
+        if let Some(availability) = &self.availability {
+            for (i, window) in availability.windows_sec.iter().enumerate() {
+                if *window == 0 {
+                    problems.push(format!("availability.windows_sec[{}]: must be greater than 0", i));
+                }
+            }
+        }
+        if let Some(rollup) = &self.latency_rollup {
+            for (i, window) in rollup.windows_sec.iter().enumerate() {
+                if *window == 0 {
+                    problems.push(format!("latency_rollup.windows_sec[{}]: must be greater than 0", i));
+                }
+            }
+        }
+        if self.max_concurrent_probes == Some(0) {
+            problems.push("max_concurrent_probes: must be greater than 0".to_string());
+        }
+        if let Some(spread) = &self.probe_spread
+            && !(spread.spread_percent > 0.0 && spread.spread_percent <= 100.0)
+        {
+            problems.push("probe_spread.spread_percent: must be greater than 0 and at most 100".to_string());
+        }
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (i, target) in self.targets.iter().enumerate() {
+            let at = format!("targets[{}] ({:?})", i, target.name);
+            if target.name.is_empty() {
+                problems.push(format!("{}: name is empty", at));
+            }
+            if target.host.is_empty() {
+                problems.push(format!("{}: host is empty", at));
+            }
+            if target.port == Some(0) {
+                problems.push(format!("{}: port 0 is not a valid port", at));
+            }
+            if let Some(dscp) = target.dscp
+                && dscp > 63
+            {
+                problems.push(format!("{}: dscp must be between 0 and 63", at));
+            }
+            if target.interval_ms == Some(0) {
+                problems.push(format!("{}: interval_ms must be greater than 0", at));
+            }
+            if let Some(offset_ms) = target.offset_ms
+                && offset_ms >= target.interval_ms.unwrap_or(self.probe_interval_ms)
+            {
+                problems.push(format!("{}: offset_ms must be less than the interval", at));
+            }
+            if let Some(backoff) = &target.backoff {
+                if backoff.after_failures == 0 {
+                    problems.push(format!("{}: backoff.after_failures must be greater than 0", at));
+                }
+                if backoff.multiplier.is_nan() || backoff.multiplier < 1.0 {
+                    problems.push(format!("{}: backoff.multiplier must be at least 1", at));
+                }
+                if backoff.max_interval_ms == 0 {
+                    problems.push(format!("{}: backoff.max_interval_ms must be greater than 0", at));
+                }
+            }
+            // Persistent and Fast Open probes have their own connection handling
+            if target.tcp.fast_open == Some(true) {
+                if target.tcp.mode == Some(TcpMode::Persistent) {
+                    problems.push(format!("{}: tcp.fast_open does not apply to tcp.mode persistent", at));
+                }
+                if target.tcp.close.is_some() || target.tcp.include_teardown.is_some() {
+                    problems.push(format!("{}: tcp.close and tcp.include_teardown do not apply with tcp.fast_open", at));
+                }
+            }
+            for ((block, kind), empty) in KIND_BLOCKS.iter().zip([
+                target.icmp.is_empty(),
+                target.tcp.is_empty(),
+                target.echo.is_empty(),
+                target.http.is_empty(),
+            ]) {
+                if !empty && target.kind != *kind {
+                    let kind_name = serde_json::to_value(kind).ok().and_then(|k| k.as_str().map(str::to_string)).unwrap_or_default();
+                    problems.push(format!("{}: {} options only apply to kind {}", at, block, kind_name));
+                }
+            }
+            for name in target.labels.keys() {
+                if let Err(e) = crate::metrics::check_label_name(name) {
+                    problems.push(format!("{}: labels: {}", at, e));
+                }
+            }
+            // State and series are keyed by name, whatever the kind
+            match seen.get(target.name.as_str()) {
+                Some(first) => problems.push(format!("{}: duplicate target name, first used by targets[{}]", at, first)),
+                None => {
+                    seen.insert(&target.name, i);
+                }
+            }
+        }
+        problems
+    }
 }
 
 pub struct ConfigManager {
     pub config: Arc<RwLock<ProbeConfig>>,
     pub targets: Arc<RwLock<Vec<TargetConfig>>>,
+    // notified after every config change that was applied
+    reloaded: Arc<watch::Sender<()>>,
 
     // for shutdown if needed
     _shutdown: watch::Receiver<()>,
 }
 
 impl ConfigManager {
-    pub async fn start() -> Result<Self> {
-        // Check if we should use AppConfig or local file
+    /// Receiver that is marked changed whenever a new config is applied
+    pub fn subscribe_reloads(&self) -> watch::Receiver<()> {
+        self.reloaded.subscribe()
+    }
+
+    pub async fn start(config_file: &str) -> Result<Self> {
+        // CONFIG_SOURCE picks the source; USE_APP_CONFIG predates it
         let use_app_config = std::env::var("USE_APP_CONFIG")
             .unwrap_or_else(|_| "false".to_string())
             .parse::<bool>()
             .unwrap_or(false);
+        let source = match std::env::var("CONFIG_SOURCE") {
+            Ok(source) => source.to_lowercase(),
+            Err(_) if use_app_config => "appconfig".to_string(),
+            Err(_) => "file".to_string(),
+        };
 
-        if use_app_config {
-            Self::start_with_app_config().await
-        } else {
-            Self::start_with_local_file().await
+        match source.as_str() {
+            // The config "file" may also be a URL
+            "file" if HttpUrl::is_url(config_file) => Self::start_with_source(HttpUrl::new(config_file)?).await,
+            "file" => Self::start_with_local_file(config_file).await,
+            "appconfig" => Self::start_with_app_config().await,
+            "consul" => Self::start_with_source(ConsulKv::from_env()?).await,
+            "etcd" => Self::start_with_source(EtcdKv::from_env()?).await,
+            "kubernetes" => Self::start_with_source(Kubernetes::from_env()?).await,
+            "s3" => Self::start_with_source(S3Object::from_env().await?).await,
+            "ssm" => Self::start_with_source(SsmParameters::from_env().await?).await,
+            other => anyhow::bail!("Unknown CONFIG_SOURCE {:?}, expected file, appconfig, consul, etcd, kubernetes, s3 or ssm", other),
+        }
+    }
+
+    /// Load the initial config from `source`, then apply its changes as they come
+    async fn start_with_source(mut source: impl ConfigSource) -> Result<Self> {
+        println!("Starting with {}", source.name());
+
+        let initial = source.load().await?;
+        initial.validate()?;
+        record_reload(&initial, source.name());
+        let config = Arc::new(RwLock::new(initial.clone()));
+        let targets = Arc::new(RwLock::new(initial.targets.clone()));
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let reloaded = Arc::new(watch::channel(()).0);
+
+        {
+            let config = config.clone();
+            let targets = targets.clone();
+            let reloaded = reloaded.clone();
+            tokio::spawn(async move {
+                loop {
+                    match source.changed().await {
+                        Ok(Some(new_cfg)) => apply_update(&config, &targets, &reloaded, new_cfg, source.name()).await,
+                        Ok(None) => tracing::debug!("{} unchanged", source.name()),
+                        Err(e) => {
+                            tracing::error!("Error watching {}: {:?}", source.name(), e);
+                            record_reload_error(source.name(), &e);
+                            tokio::time::sleep(SOURCE_RETRY_DELAY).await;
+                        }
+                    }
+                }
+            });
         }
+
+        Ok(ConfigManager {
+            config,
+            targets,
+            reloaded,
+            _shutdown: shutdown_rx,
+        })
     }
 
     async fn start_with_app_config() -> Result<Self> {
@@ -93,43 +991,57 @@ impl ConfigManager {
         // Env vars or default
         let app_id = std::env::var("APP_CONFIG_APPLICATION_ID")?;
         let env_id = std::env::var("APP_CONFIG_ENVIRONMENT_ID")?;
-        let profile_id = std::env::var("APP_CONFIG_PROFILE_ID")?;
+        // Comma-separated; later profiles override earlier ones
+        let profile_ids = std::env::var("APP_CONFIG_PROFILE_ID")?;
         let poll_interval_sec: u64 = std::env::var("APP_CONFIG_POLL_INTERVAL_SECONDS")
             .unwrap_or_else(|_| "60".to_string())
             .parse()
             .unwrap_or(60);
 
         // Load initial
-        let initial = Self::fetch_app_config(&client, &app_id, &env_id, &profile_id).await?;
+        let mut profiles = AppConfigProfiles::new(client, &app_id, &env_id, &profile_ids, poll_interval_sec)?;
+        let initial = profiles.load().await?;
+        record_reload(&initial, "AppConfig");
         let config = Arc::new(RwLock::new(initial.clone()));
         let targets = Arc::new(RwLock::new(initial.targets.clone()));
 
         // optional: shutdown signal channel (not used here)
         let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let reloaded = Arc::new(watch::channel(()).0);
 
         // Spawn background task to poll
         {
             let config_clone = config.clone();
             let targets_clone = targets.clone();
+            let reloaded_clone = reloaded.clone();
             tokio::spawn(async move {
+                let mut healthy = true;
                 loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(poll_interval_sec)).await;
-                    match Self::fetch_app_config(&client, &app_id, &env_id, &profile_id).await {
-                        Ok(new_cfg) => {
-                            // check if changed
-                            let mut c = config_clone.write().await;
-                            if *c != new_cfg {
-                                tracing::info!("AppConfig updated");
-                                *c = new_cfg.clone();
-                                // update targets list
-                                let mut t = targets_clone.write().await;
-                                *t = new_cfg.targets.clone();
-                            }
+                    tokio::time::sleep(profiles.next_poll_interval()).await;
+                    let polled = profiles.poll().await;
+                    let failing = profiles.failing || polled.is_err();
+                    match polled {
+                        // Recovered without a change to apply
+                        Ok(None) if !healthy && !failing => {
+                            let active = config_clone.read().await.clone();
+                            record_reload(&active, "AppConfig");
+                        }
+                        Ok(None) => {
+                            tracing::debug!("AppConfig unchanged");
+                        }
+                        // The failing profile's error is already recorded
+                        Ok(Some(new_cfg)) if profiles.failing => {
+                            swap_config(&config_clone, &targets_clone, &reloaded_clone, new_cfg, "AppConfig").await;
+                        }
+                        Ok(Some(new_cfg)) => {
+                            apply_update(&config_clone, &targets_clone, &reloaded_clone, new_cfg, "AppConfig").await;
                         }
                         Err(e) => {
                             tracing::error!("Error polling AppConfig: {:?}", e);
+                            record_reload_error("AppConfig", &e);
                         }
                     }
+                    healthy = !failing;
                 }
             });
         }
@@ -137,18 +1049,17 @@ impl ConfigManager {
         Ok(ConfigManager {
             config,
             targets,
+            reloaded,
             _shutdown: shutdown_rx,
         })
     }
 
-    async fn start_with_local_file() -> Result<Self> {
-        let config_file = std::env::var("TARGET_CONFIG")
-            .unwrap_or_else(|_| "targets.json".to_string());
-        
+    async fn start_with_local_file(config_file: &str) -> Result<Self> {
         println!("Starting with local file: {}", config_file);
 
-        // Load initial config from file
-        let initial = Self::load_file_config(&config_file).await?;
+        // Load initial config from the file(s)
+        let initial = ConfigFiles::read(config_file)?.merged()?;
+        record_reload(&initial, LOCAL_FILE_SOURCE);
         let config = Arc::new(RwLock::new(initial.clone()));
         let targets = Arc::new(RwLock::new(initial.targets.clone()));
 
@@ -159,96 +1070,718 @@ impl ConfigManager {
 
         // optional: shutdown signal channel (not used here)
         let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let reloaded = Arc::new(watch::channel(()).0);
 
         // Spawn background task to watch file for changes
-        {
-            let config_clone = config.clone();
-            let targets_clone = targets.clone();
-            let config_file_clone = config_file.clone();
-            
-            tokio::spawn(async move {
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(poll_interval_sec)).await;
-                    match Self::load_file_config(&config_file_clone).await {
-                        Ok(new_cfg) => {
-                            // check if changed
-                            let mut c = config_clone.write().await;
-                            if *c != new_cfg {
-                                tracing::info!("Local config file updated");
-                                *c = new_cfg.clone();
-                                // update targets list
-                                let mut t = targets_clone.write().await;
-                                *t = new_cfg.targets.clone();
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Error reading config file {}: {:?}", config_file_clone, e);
-                        }
-                    }
-                }
-            });
-        }
+        tokio::spawn(watch_local_file(
+            config_file.to_string(),
+            config.clone(),
+            targets.clone(),
+            reloaded.clone(),
+            tokio::time::Duration::from_secs(poll_interval_sec),
+        ));
 
         Ok(ConfigManager {
             config,
             targets,
+            reloaded,
             _shutdown: shutdown_rx,
         })
     }
 
-    async fn load_file_config(file_path: &str) -> Result<ProbeConfig> {
-        if !Path::new(file_path).exists() {
-            return Err(anyhow::anyhow!("Config file not found: {}", file_path));
+    /// Everything wrong with a config file (or the files `--config` names),
+    /// for `latency-probe check`
+    pub fn check_file(file_path: &str) -> Result<ProbeConfig, Vec<String>> {
+        let config = ConfigFiles::read(file_path)
+            .and_then(|files| files.merge())
+            .map_err(|e| vec![format!("{:#}", e)])?;
+        match config.problems() {
+            problems if problems.is_empty() => Ok(config),
+            problems => Err(problems),
         }
-        
-        let content = fs::read_to_string(file_path).await?;
-        let config: ProbeConfig = serde_json::from_str(&content)?;
+    }
+
+    /// Parse and validate a config document; `file_path` (or a key named like
+    /// one) picks the format
+    pub(crate) fn parse_file_config(file_path: &str, content: &str) -> Result<ProbeConfig> {
+        let config = Self::parse_document(file_path, content)?;
+        config.validate()?;
         Ok(config)
     }
 
-    async fn fetch_app_config(
-        client: &AppConfigClient,
-        app_id: &str,
-        env_id: &str,
-        profile_id: &str,
-    ) -> Result<ProbeConfig> {
-        // Start session
-        let session_resp = client
-            .start_configuration_session()
-            .application_identifier(app_id)
-            .environment_identifier(env_id)
-            .configuration_profile_identifier(profile_id)
-            .send()
-            .await?;
+    /// Parse a local config document, migrating it first if it is older than
+    /// CONFIG_VERSION. Current documents are parsed directly so errors keep
+    /// their line and column.
+    fn parse_document(file_path: &str, content: &str) -> Result<ProbeConfig> {
+        let content = &*interpolate_env(content)?;
+        let format = ConfigFormat::for_file(file_path)?;
+        let version = match format {
+            ConfigFormat::Json => serde_json::from_str::<VersionOnly>(content)?.version,
+            ConfigFormat::Yaml => serde_yaml::from_str::<VersionOnly>(content)?.version,
+        };
+        check_config_version(version)?;
+        let mut config: ProbeConfig = if version == CONFIG_VERSION {
+            match format {
+                ConfigFormat::Json => serde_json::from_str(content)?,
+                ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            }
+        } else {
+            let mut document: serde_json::Value = match format {
+                ConfigFormat::Json => serde_json::from_str(content)?,
+                ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            };
+            migrate_config_document(&mut document)?;
+            tracing::info!("Migrated config file {} from version {} to {}", file_path, version, CONFIG_VERSION);
+            serde_json::from_value(document)?
+        };
+        config.expand_groups()?;
+        config.apply_defaults()?;
+        Ok(config)
+    }
+}
 
-        let token = session_resp
-            .initial_configuration_token()
-            .ok_or_else(|| anyhow::anyhow!("No initial token from AppConfigData"))?;
+// Source name of local config files in logs, metrics and /config
+const LOCAL_FILE_SOURCE: &str = "Local config file";
+
+// Pause before asking a config source again after it failed
+pub(crate) const SOURCE_RETRY_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+// Filesystem events arriving within this long of each other are handled as
+// one change; editors tend to write a file in several steps
+const FILE_WATCH_DEBOUNCE: tokio::time::Duration = tokio::time::Duration::from_millis(200);
 
-        let latest = client
+// Watched files are still re-read this often, to catch changes whose events
+// were lost (network filesystems, renames into a directory, a full queue)
+const FILE_WATCH_RESYNC: tokio::time::Duration = tokio::time::Duration::from_secs(300);
+
+/// Reload the local file(s) whenever they change. Filesystem events are used
+/// where available, with a slow re-read as a fallback for lost ones; otherwise
+/// (or with CONFIG_WATCH=poll) the files are re-read every `poll_interval`.
+/// Either way they are only parsed when their content changed.
+async fn watch_local_file(
+    path: String,
+    config: Arc<RwLock<ProbeConfig>>,
+    targets: Arc<RwLock<Vec<TargetConfig>>>,
+    reloaded: Arc<watch::Sender<()>>,
+    poll_interval: tokio::time::Duration,
+) {
+    let (tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let use_events = std::env::var("CONFIG_WATCH").unwrap_or_default().to_lowercase() != "poll";
+    // Directories being watched; includes can add or remove some on every reload
+    let mut watched: Option<(BTreeSet<PathBuf>, notify::RecommendedWatcher)> = None;
+    let mut dirs = ConfigFiles::read(&path).map(|files| files.dirs).unwrap_or_default();
+    // Unknown at first: the files may have changed since they were loaded
+    let mut last: Option<Vec<(PathBuf, String)>> = None;
+    // Last read error, so one broken file is reported once rather than on
+    // every change elsewhere in its directory
+    let mut last_error: Option<String> = None;
+    loop {
+        if use_events && watched.as_ref().is_none_or(|(current, _)| *current != dirs) {
+            watched = match file_watcher(&dirs, tx.clone()) {
+                Ok(watcher) => Some((dirs.clone(), watcher)),
+                Err(e) => {
+                    tracing::warn!("Cannot watch config file {} ({}), re-reading it every {:?}", path, e, poll_interval);
+                    None
+                }
+            };
+        }
+        if watched.is_some() {
+            tokio::select! {
+                event = events.recv() => {
+                    if event.is_none() {
+                        return;
+                    }
+                    loop {
+                        tokio::time::sleep(FILE_WATCH_DEBOUNCE).await;
+                        let mut more = false;
+                        while events.try_recv().is_ok() {
+                            more = true;
+                        }
+                        if !more {
+                            break;
+                        }
+                    }
+                }
+                _ = tokio::time::sleep(FILE_WATCH_RESYNC) => {}
+            }
+        } else {
+            tokio::time::sleep(poll_interval).await;
+        }
+        let files = match ConfigFiles::read(&path) {
+            Ok(files) => files,
+            Err(e) => {
+                let message = format!("{:#}", e);
+                if last_error.as_ref() != Some(&message) {
+                    tracing::error!("Error reading config file {}: {:?}", path, e);
+                    record_reload_error(LOCAL_FILE_SOURCE, &e);
+                    last_error = Some(message);
+                }
+                // Whatever is read next must be applied, even if it is the
+                // content from before the error
+                last = None;
+                continue;
+            }
+        };
+        last_error = None;
+        dirs = files.dirs.clone();
+        let contents: Vec<(PathBuf, String)> = files.files.iter().map(|f| (f.path.clone(), f.content.clone())).collect();
+        if last.as_ref() == Some(&contents) {
+            continue;
+        }
+        match files.merged() {
+            Ok(new_cfg) => apply_update(&config, &targets, &reloaded, new_cfg, LOCAL_FILE_SOURCE).await,
+            Err(e) => {
+                tracing::error!("Error reading config file {}: {:?}", path, e);
+                record_reload_error(LOCAL_FILE_SOURCE, &e);
+            }
+        }
+        last = Some(contents);
+    }
+}
+
+/// Signal every change in the config files' directories. Editors and Kubernetes
+/// ConfigMap volumes replace a file (or a symlink to it) rather than
+/// writing to it, which a watch on the file itself would lose track of.
+fn file_watcher(dirs: &BTreeSet<PathBuf>, changed: tokio::sync::mpsc::UnboundedSender<()>) -> notify::Result<notify::RecommendedWatcher> {
+    use notify::Watcher;
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|e| !e.kind.is_access()) {
+            let _ = changed.send(());
+        }
+    })?;
+    for dir in dirs {
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+/// One document read for a local config
+struct ConfigFile {
+    // As named in --config or the include, for messages and watching
+    path: PathBuf,
+    // Resolved, to notice a file reached twice
+    canonical: PathBuf,
+    content: String,
+    // Migrated to CONFIG_VERSION, `include` removed
+    document: serde_json::Value,
+}
+
+/// The documents a local config is assembled from. `--config` may name
+/// several files or directories, comma-separated, and any document may
+/// `include` more files or directories relative to its own. Directories
+/// contribute their .json, .yaml and .yml files in name order.
+struct ConfigFiles {
+    // In merge order: included documents come before the one including them
+    files: Vec<ConfigFile>,
+    // Directories whose changes can change the config
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl ConfigFiles {
+    fn read(spec: &str) -> Result<Self> {
+        let mut files = ConfigFiles { files: Vec::new(), dirs: BTreeSet::new() };
+        let entries: Vec<&str> = spec.split(',').map(str::trim).filter(|e| !e.is_empty()).collect();
+        // A single file keeps its errors free of the file name, as before
+        let single = entries.len() == 1 && !Path::new(entries[0]).is_dir();
+        for entry in entries {
+            files.read_entry(Path::new(entry), &mut Vec::new(), !single)?;
+        }
+        if files.files.is_empty() {
+            anyhow::bail!("No config files found in {}", spec);
+        }
+        Ok(files)
+    }
+
+    /// Read a file, or every config file in a directory
+    fn read_entry(&mut self, path: &Path, including: &mut Vec<PathBuf>, name_errors: bool) -> Result<()> {
+        if !path.is_dir() {
+            return self.read_file(path, including, name_errors);
+        }
+        self.dirs.insert(path.to_path_buf());
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read config directory {}: {}", path.display(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.is_file() && !p.file_name().is_some_and(|n| n.to_string_lossy().starts_with('.')))
+            .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("json" | "yaml" | "yml")))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            self.read_file(&entry, including, true)?;
+        }
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &Path, including: &mut Vec<PathBuf>, name_errors: bool) -> Result<()> {
+        let canonical = std::fs::canonicalize(path)
+            .map_err(|_| anyhow::anyhow!("Config file not found: {}", path.display()))?;
+        if including.contains(&canonical) {
+            anyhow::bail!("{} includes itself", path.display());
+        }
+        if self.files.iter().any(|f| f.canonical == canonical) {
+            anyhow::bail!("{} is loaded more than once", path.display());
+        }
+        self.dirs.insert(match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        });
+
+        let parsed = std::fs::read_to_string(path).map_err(anyhow::Error::from).and_then(|content| {
+            let file_path = path.to_string_lossy();
+            let interpolated = interpolate_env(&content)?;
+            let mut document: serde_json::Value = match ConfigFormat::for_file(&file_path)? {
+                ConfigFormat::Json => serde_json::from_str(&interpolated)?,
+                ConfigFormat::Yaml => serde_yaml::from_str(&interpolated)?,
+            };
+            migrate_config_document(&mut document)?;
+            Ok((content, document))
+        });
+        let (content, mut document) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) if name_errors => return Err(e.context(format!("in {}", path.display()))),
+            Err(e) => return Err(e),
+        };
+
+        let includes = match document.as_object_mut().and_then(|d| d.remove("include")) {
+            None => Vec::new(),
+            Some(serde_json::Value::String(include)) => vec![include],
+            Some(serde_json::Value::Array(includes)) => includes
+                .into_iter()
+                .map(|i| i.as_str().map(str::to_string))
+                .collect::<Option<_>>()
+                .ok_or_else(|| anyhow::anyhow!("{}: include must be a path or a list of paths", path.display()))?,
+            Some(_) => anyhow::bail!("{}: include must be a path or a list of paths", path.display()),
+        };
+        including.push(canonical.clone());
+        let base = path.parent().unwrap_or(Path::new(""));
+        for include in includes {
+            self.read_entry(&base.join(include), including, true)?;
+        }
+        including.pop();
+
+        self.files.push(ConfigFile { path: path.to_path_buf(), canonical, content, document });
+        Ok(())
+    }
+
+    /// The merged config, if it has no problems
+    fn merged(&self) -> Result<ProbeConfig> {
+        let config = self.merge()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Merge every document into one config. Settings from later documents
+    /// override earlier ones, but a target (by name and kind) may only be
+    /// defined in one file.
+    fn merge(&self) -> Result<ProbeConfig> {
+        // Parsed directly so errors keep their line and column
+        if let [file] = self.files.as_slice() {
+            return ConfigManager::parse_document(&file.path.to_string_lossy(), &file.content);
+        }
+
+        let mut merged = serde_json::Value::Object(Default::default());
+        let mut targets = Vec::new();
+        let mut defined_in: HashMap<(String, String), &Path> = HashMap::new();
+        for file in &self.files {
+            let mut document = file.document.clone();
+            let mut file_targets = match document.as_object_mut().and_then(|d| d.remove("targets")) {
+                None => Vec::new(),
+                Some(serde_json::Value::Array(file_targets)) => file_targets,
+                Some(_) => anyhow::bail!("{}: targets must be a list", file.path.display()),
+            };
+            // Expanded here so their targets are checked for conflicts too
+            if let Some(groups) = document.as_object_mut().and_then(|d| d.remove("groups")) {
+                let groups: Vec<TargetGroup> = serde_json::from_value(groups)
+                    .map_err(|e| anyhow::anyhow!("{}: groups: {}", file.path.display(), e))?;
+                for group in groups {
+                    for target in group.expand().map_err(|e| e.context(format!("in {}", file.path.display())))? {
+                        file_targets.push(serde_json::to_value(target)?);
+                    }
+                }
+            }
+            for target in file_targets {
+                let name = target.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+                let kind = target.get("kind").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+                if let Some(other) = defined_in.insert((name.clone(), kind.clone()), &file.path)
+                    && other != file.path
+                {
+                    anyhow::bail!(
+                        "target {} ({}) is defined in both {} and {}",
+                        name, kind, other.display(), file.path.display()
+                    );
+                }
+                targets.push(target);
+            }
+            merge_config_document(&mut merged, document);
+        }
+        if let Some(fields) = merged.as_object_mut() {
+            fields.insert("targets".to_string(), targets.into());
+        }
+        let mut config: ProbeConfig = serde_json::from_value(merged)?;
+        config.apply_defaults()?;
+        Ok(config)
+    }
+}
+
+/// Syntax of the local config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// CONFIG_FORMAT if set, otherwise YAML for .yaml/.yml files and JSON for anything else
+    fn for_file(file_path: &str) -> Result<Self> {
+        match std::env::var("CONFIG_FORMAT").unwrap_or_default().to_lowercase().as_str() {
+            "json" => return Ok(Self::Json),
+            "yaml" | "yml" => return Ok(Self::Yaml),
+            "" => {}
+            other => anyhow::bail!("Unknown CONFIG_FORMAT {:?}, expected json or yaml", other),
+        }
+        let extension = Path::new(file_path).extension().and_then(|e| e.to_str()).unwrap_or_default();
+        Ok(match extension.to_lowercase().as_str() {
+            "yaml" | "yml" => Self::Yaml,
+            _ => Self::Json,
+        })
+    }
+}
+
+/// Outcome of the config loads so far, served on `/config`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadStatus {
+    // Where the active config came from, e.g. "Local config file"
+    pub source: String,
+    // FNV-1a hash of the active config, to tell which version is running
+    pub hash: String,
+    // Unix time of the last successful load, whether or not it changed anything
+    pub last_success: u64,
+    // Whether the most recent load succeeded; if not, the previous config stays active
+    pub last_reload_successful: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error_time: Option<u64>,
+    pub targets_by_kind: BTreeMap<String, usize>,
+}
+
+static RELOAD_STATUS: Lazy<std::sync::Mutex<ReloadStatus>> = Lazy::new(Default::default);
+
+pub fn reload_status() -> ReloadStatus {
+    RELOAD_STATUS.lock().unwrap().clone()
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Stable across runs and builds, unlike std's hasher
+fn config_hash(config: &ProbeConfig) -> String {
+    let bytes = serde_json::to_vec(config).unwrap_or_default();
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+/// Record a config that was loaded successfully from `source`
+fn record_reload(config: &ProbeConfig, source: &str) {
+    let mut status = RELOAD_STATUS.lock().unwrap();
+    status.source = source.to_string();
+    status.hash = config_hash(config);
+    status.last_success = unix_time();
+    status.last_reload_successful = true;
+    status.targets_by_kind.clear();
+    for target in &config.targets {
+        *status.targets_by_kind.entry(target.kind.as_str().to_string()).or_default() += 1;
+    }
+    crate::metrics::observe_config_reload(&status, true);
+}
+
+/// Record a config load from `source` that failed; the active config is kept
+fn record_reload_error(source: &str, error: &anyhow::Error) {
+    let mut status = RELOAD_STATUS.lock().unwrap();
+    status.last_reload_successful = false;
+    status.last_error = Some(format!("{}: {:#}", source, error));
+    status.last_error_time = Some(unix_time());
+    crate::metrics::observe_config_reload(&status, false);
+}
+
+/// Swap in a newly loaded config if it differs from the active one
+async fn apply_update(
+    config: &RwLock<ProbeConfig>,
+    targets: &RwLock<Vec<TargetConfig>>,
+    reloaded: &watch::Sender<()>,
+    new_cfg: ProbeConfig,
+    source: &str,
+) {
+    // Sources assembling a config from parts (targets_only_config) haven't
+    // validated the whole
+    if let Err(e) = new_cfg.validate() {
+        tracing::error!("Rejected config from {}: {:#}", source, e);
+        record_reload_error(source, &e);
+        return;
+    }
+    record_reload(&new_cfg, source);
+    swap_config(config, targets, reloaded, new_cfg, source).await;
+}
+
+/// Make `new_cfg` the active config if it differs from it, without
+/// recording the reload
+async fn swap_config(
+    config: &RwLock<ProbeConfig>,
+    targets: &RwLock<Vec<TargetConfig>>,
+    reloaded: &watch::Sender<()>,
+    new_cfg: ProbeConfig,
+    source: &str,
+) {
+    {
+        let mut c = config.write().await;
+        if *c == new_cfg {
+            return;
+        }
+        tracing::info!("{} updated", source);
+        *c = new_cfg.clone();
+        // update targets list
+        let mut t = targets.write().await;
+        *t = new_cfg.targets;
+    }
+    reloaded.send_replace(());
+}
+
+/// One AppConfigData session per configured profile. Each profile holds a
+/// partial or complete config document; they are merged in order, so a site
+/// profile can override settings and targets from a global one.
+struct AppConfigProfiles {
+    sessions: Vec<AppConfigSession>,
+    // Whether a profile failed in the last poll, so the reload that still
+    // applies the other profiles' changes is not recorded as successful
+    failing: bool,
+}
+
+impl AppConfigProfiles {
+    fn new(client: AppConfigClient, app_id: &str, env_id: &str, profile_ids: &str, poll_interval_sec: u64) -> Result<Self> {
+        let sessions: Vec<_> = profile_ids
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|profile_id| AppConfigSession::new(
+                client.clone(),
+                app_id.to_string(),
+                env_id.to_string(),
+                profile_id.to_string(),
+                poll_interval_sec,
+            ))
+            .collect();
+        if sessions.is_empty() {
+            return Err(anyhow::anyhow!("APP_CONFIG_PROFILE_ID does not name any profile"));
+        }
+        Ok(Self { sessions, failing: false })
+    }
+
+    /// Initial load: every profile must return a document
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        for session in &mut self.sessions {
+            session.poll().await?;
+        }
+        self.merged()
+    }
+
+    fn next_poll_interval(&self) -> tokio::time::Duration {
+        self.sessions
+            .iter()
+            .map(AppConfigSession::next_poll_interval)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Poll every profile; `None` means no profile changed. A failing profile
+    /// keeps contributing its last known document.
+    async fn poll(&mut self) -> Result<Option<ProbeConfig>> {
+        let mut changed = false;
+        self.failing = false;
+        for session in &mut self.sessions {
+            match session.poll().await {
+                Ok(c) => changed |= c,
+                Err(e) => {
+                    tracing::error!("Error polling AppConfig profile {}: {:?}", session.profile_id, e);
+                    record_reload_error("AppConfig", &e.context(format!("profile {}", session.profile_id)));
+                    self.failing = true;
+                }
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        self.merged().map(Some)
+    }
+
+    fn merged(&self) -> Result<ProbeConfig> {
+        let mut merged = serde_json::Value::Object(Default::default());
+        for session in &self.sessions {
+            let document = session.document.clone().ok_or_else(|| {
+                anyhow::anyhow!("AppConfig profile {} returned an empty configuration", session.profile_id)
+            })?;
+            merge_config_document(&mut merged, document);
+        }
+        let mut cfg: ProbeConfig = serde_json::from_value(merged)?;
+        cfg.expand_groups()?;
+        cfg.apply_defaults()?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+}
+
+/// Deep-merge `overlay` into `base`. Objects merge key by key; `targets` arrays
+/// merge by (name, kind) so an overlay can replace or add individual targets.
+fn merge_config_document(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    use serde_json::Value;
+
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match (key.as_str(), base.get_mut(&key), value) {
+                    ("targets", Some(Value::Array(existing)), Value::Array(new_targets)) => {
+                        for target in new_targets {
+                            let key_of = |t: &Value| (t.get("name").cloned(), t.get("kind").cloned());
+                            match existing.iter_mut().find(|t| key_of(t) == key_of(&target)) {
+                                Some(slot) => *slot = target,
+                                None => existing.push(target),
+                            }
+                        }
+                    }
+                    (_, Some(slot), value) => merge_config_document(slot, value),
+                    (_, None, value) => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Long-lived AppConfigData session. The session is started once and every poll
+/// uses the token handed back by the previous one, so unchanged configurations
+/// come back empty instead of being re-downloaded.
+struct AppConfigSession {
+    client: AppConfigClient,
+    app_id: String,
+    env_id: String,
+    profile_id: String,
+    poll_interval_sec: u64,
+    token: Option<String>,
+    // Minimum interval AppConfig asked us to wait before the next poll
+    server_poll_interval_sec: u64,
+    // Last configuration document received for this profile
+    document: Option<serde_json::Value>,
+}
+
+// AppConfigData rejects required minimum poll intervals below 15 seconds
+const APPCONFIG_MIN_POLL_INTERVAL_SECONDS: u64 = 15;
+
+impl AppConfigSession {
+    fn new(client: AppConfigClient, app_id: String, env_id: String, profile_id: String, poll_interval_sec: u64) -> Self {
+        Self {
+            client,
+            app_id,
+            env_id,
+            profile_id,
+            poll_interval_sec: poll_interval_sec.max(APPCONFIG_MIN_POLL_INTERVAL_SECONDS),
+            token: None,
+            server_poll_interval_sec: 0,
+            document: None,
+        }
+    }
+
+    fn next_poll_interval(&self) -> tokio::time::Duration {
+        tokio::time::Duration::from_secs(self.poll_interval_sec.max(self.server_poll_interval_sec))
+    }
+
+    /// Fetch the latest document; returns whether it changed since the last poll
+    async fn poll(&mut self) -> Result<bool> {
+        let start = std::time::Instant::now();
+        let result = self.fetch().await;
+        crate::metrics::observe_appconfig_fetch(start.elapsed().as_secs_f64() * 1000.0, result.is_ok());
+        result
+    }
+
+    async fn fetch(&mut self) -> Result<bool> {
+        let token = match self.token.take() {
+            Some(token) => token,
+            None => self.start_session().await?,
+        };
+
+        let latest = match self.client
             .get_latest_configuration()
-            .configuration_token(token)
+            .configuration_token(&token)
             .send()
-            .await?;
+            .await
+        {
+            Ok(latest) => latest,
+            // Tokens expire after 24 hours, e.g. while AppConfig was
+            // unreachable; only a new session gets a valid one
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_bad_request_exception()) => {
+                tracing::warn!("AppConfig rejected the poll token for profile {}, starting a new session", self.profile_id);
+                return Err(e.into());
+            }
+            Err(e) => {
+                // Keep the token so the next poll retries the same request
+                self.token = Some(token);
+                return Err(e.into());
+            }
+        };
+
+        self.token = latest.next_poll_configuration_token().map(str::to_string);
+        self.server_poll_interval_sec = latest.next_poll_interval_in_seconds().max(0) as u64;
 
         let cfg_bytes = latest
             .configuration()
             .map(|c| c.as_ref())
             .unwrap_or_default();
+        if cfg_bytes.is_empty() {
+            return Ok(false);
+        }
 
-        let cfg: ProbeConfig = serde_json::from_slice(cfg_bytes)?;
-        Ok(cfg)
+        let mut document: serde_json::Value = serde_json::from_str(&interpolate_env(std::str::from_utf8(cfg_bytes)?)?)?;
+        // Profiles may be at different versions; merge them at the current one
+        migrate_config_document(&mut document)?;
+        let changed = self.document.as_ref() != Some(&document);
+        self.document = Some(document);
+        Ok(changed)
+    }
+
+    async fn start_session(&self) -> Result<String> {
+        let session_resp = self.client
+            .start_configuration_session()
+            .application_identifier(&self.app_id)
+            .environment_identifier(&self.env_id)
+            .configuration_profile_identifier(&self.profile_id)
+            .required_minimum_poll_interval_in_seconds(self.poll_interval_sec as i32)
+            .send()
+            .await?;
+
+        session_resp
+            .initial_configuration_token()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("No initial token from AppConfigData"))
     }
 }
 
 impl TargetConfig {
-    pub fn get_host_port(&self, default_port: u16) -> (String, u16) {
-        parse_host_port(&self.host, self.port.unwrap_or(default_port))
+    /// Value used for the `probe_type` label; cold HTTP measurements are kept
+    /// apart from kept-alive ones, persistent TCP RTTs from connect times
+    pub fn probe_type(&self) -> &'static str {
+        match (&self.kind, self.http.connection_mode, self.tcp.mode) {
+            (ProbeKind::Http, Some(ConnectionMode::Cold), _) => "http_cold",
+            (ProbeKind::TcpConnect, _, Some(TcpMode::Persistent)) => "tcp_persistent",
+            (ProbeKind::TcpConnect, _, _) if self.tcp.fast_open == Some(true) => "tcp_fastopen",
+            (ProbeKind::Echo, _, _) if self.echo.transport == Some(EchoTransport::Tcp) => "echo_tcp",
+            (kind, _, _) => kind.as_str(),
+        }
     }
 
     // Updated method to just concatenate host + port
     pub fn get_http_url(&self) -> String {
-        let port = self.port.unwrap_or(80);
+        let port = self.port.unwrap_or(self.kind.default_port());
         format!("{}:{}", self.host, port)
     }
 }
@@ -0,0 +1,91 @@
+// Config stored under a Consul KV key, watched with blocking queries:
+// https://developer.hashicorp.com/consul/api-docs/features/blocking
+
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use crate::config::{ConfigManager, ProbeConfig};
+use std::time::Duration;
+use super::{client_builder, ConfigSource, REQUEST_TIMEOUT};
+
+// How long Consul holds a blocking query open without a change
+const BLOCKING_WAIT: Duration = Duration::from_secs(300);
+
+pub struct ConsulKv {
+    client: Client,
+    // e.g. http://127.0.0.1:8500/v1/kv/latency-probe/targets.json
+    url: String,
+    key: String,
+    token: Option<String>,
+    // X-Consul-Index of the last response; 0 before the first one
+    index: u64,
+}
+
+impl ConsulKv {
+    /// CONSUL_HTTP_ADDR (default http://127.0.0.1:8500), CONSUL_HTTP_TOKEN
+    /// and CONSUL_CONFIG_KEY, whose extension picks JSON or YAML like a local file
+    pub fn from_env() -> Result<Self> {
+        let addr = std::env::var("CONSUL_HTTP_ADDR").unwrap_or_else(|_| "http://127.0.0.1:8500".to_string());
+        let addr = if addr.contains("://") { addr } else { format!("http://{}", addr) };
+        let key = std::env::var("CONSUL_CONFIG_KEY").context("CONSUL_CONFIG_KEY must name the key holding the config")?;
+        let key = key.trim_matches('/').to_string();
+        Ok(Self {
+            client: client_builder().build()?,
+            url: format!("{}/v1/kv/{}", addr.trim_end_matches('/'), key),
+            key,
+            token: std::env::var("CONSUL_HTTP_TOKEN").ok(),
+            index: 0,
+        })
+    }
+
+    /// Fetch the key; with `block`, only return once its index moved past
+    /// the last one seen (or the wait expired). None if nothing changed.
+    async fn fetch(&mut self, block: bool) -> Result<Option<ProbeConfig>> {
+        let mut request = self.client.get(&self.url).query(&[("raw", "")]).timeout(REQUEST_TIMEOUT);
+        if block {
+            let wait = format!("{}s", BLOCKING_WAIT.as_secs());
+            // Consul adds up to 1/16 of the wait as jitter
+            request = request
+                .query(&[("index", self.index.to_string().as_str()), ("wait", wait.as_str())])
+                .timeout(BLOCKING_WAIT + BLOCKING_WAIT / 16 + REQUEST_TIMEOUT);
+        }
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            anyhow::bail!("Consul key {} does not exist", self.key);
+        }
+        let response = response.error_for_status()?;
+        let index = response.headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let previous = std::mem::replace(&mut self.index, index);
+        // The index also moves for writes elsewhere in the datacenter, so an
+        // unchanged value is still filtered out by apply_update
+        if block && index == previous {
+            return Ok(None);
+        }
+        // An index that went backwards (e.g. after a snapshot restore) restarts the watch
+        if index < previous {
+            self.index = 0;
+        }
+        let body = response.text().await?;
+        Ok(Some(ConfigManager::parse_file_config(&self.key, &body)?))
+    }
+}
+
+impl ConfigSource for ConsulKv {
+    fn name(&self) -> &'static str {
+        "Consul KV"
+    }
+
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        self.fetch(false).await?.context("Consul returned no value")
+    }
+
+    async fn changed(&mut self) -> Result<Option<ProbeConfig>> {
+        self.fetch(true).await
+    }
+}
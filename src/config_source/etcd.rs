@@ -0,0 +1,149 @@
+// Config stored under an etcd v3 key, read and watched through etcd's
+// JSON gateway: https://etcd.io/docs/v3.5/dev-guide/api_grpc_gateway/
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::{Client, Response};
+use std::time::Duration;
+use serde_json::{Value, json};
+use crate::config::{ConfigManager, ProbeConfig};
+use super::{client_builder, ConfigSource, JsonLines, REQUEST_TIMEOUT};
+
+pub struct EtcdKv {
+    client: Client,
+    // e.g. http://127.0.0.1:2379
+    endpoint: String,
+    key: String,
+    credentials: Option<(String, String)>,
+    token: Option<String>,
+    // Revision the current config was read at; the watch starts after it
+    revision: i64,
+    // Open watch stream
+    watch: Option<JsonLines>,
+}
+
+impl EtcdKv {
+    /// ETCD_ENDPOINT (default http://127.0.0.1:2379), ETCD_USERNAME/ETCD_PASSWORD
+    /// and ETCD_CONFIG_KEY, whose extension picks JSON or YAML like a local file
+    pub fn from_env() -> Result<Self> {
+        let endpoint = std::env::var("ETCD_ENDPOINT").unwrap_or_else(|_| "http://127.0.0.1:2379".to_string());
+        let endpoint = if endpoint.contains("://") { endpoint } else { format!("http://{}", endpoint) };
+        let key = std::env::var("ETCD_CONFIG_KEY").context("ETCD_CONFIG_KEY must name the key holding the config")?;
+        let credentials = match (std::env::var("ETCD_USERNAME"), std::env::var("ETCD_PASSWORD")) {
+            (Ok(user), Ok(password)) => Some((user, password)),
+            _ => None,
+        };
+        Ok(Self {
+            client: client_builder().build()?,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            key,
+            credentials,
+            token: None,
+            revision: 0,
+            watch: None,
+        })
+    }
+
+    /// POST to the gateway; `timeout` is None for watches, which stay open
+    async fn post(&mut self, path: &str, body: Value, timeout: Option<Duration>) -> Result<Response> {
+        if self.token.is_none()
+            && let Some((user, password)) = &self.credentials
+        {
+            let auth: Value = self.client
+                .post(format!("{}/v3/auth/authenticate", self.endpoint))
+                .json(&json!({ "name": user, "password": password }))
+                .timeout(REQUEST_TIMEOUT)
+                .send().await?
+                .error_for_status()?
+                .json().await?;
+            self.token = auth["token"].as_str().map(str::to_string);
+        }
+        let mut request = self.client.post(format!("{}{}", self.endpoint, path)).json(&body);
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", token);
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?;
+        if response.status().is_client_error() {
+            // Most likely an expired token; authenticate again next time
+            self.token = None;
+        }
+        Ok(response.error_for_status()?)
+    }
+
+    /// Turn a key-value from a range or watch response into a config
+    fn parse_kv(&mut self, kv: &Value) -> Result<ProbeConfig> {
+        let value = BASE64.decode(kv["value"].as_str().unwrap_or_default())?;
+        if let Some(revision) = revision(&kv["mod_revision"]) {
+            self.revision = self.revision.max(revision);
+        }
+        ConfigManager::parse_file_config(&self.key, &String::from_utf8(value)?)
+    }
+}
+
+// The gateway encodes int64 fields as strings
+fn revision(value: &Value) -> Option<i64> {
+    value.as_str().and_then(|v| v.parse().ok()).or_else(|| value.as_i64())
+}
+
+impl ConfigSource for EtcdKv {
+    fn name(&self) -> &'static str {
+        "etcd"
+    }
+
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        let key = BASE64.encode(&self.key);
+        let range: Value = self.post("/v3/kv/range", json!({ "key": key }), Some(REQUEST_TIMEOUT)).await?.json().await?;
+        if let Some(revision) = revision(&range["header"]["revision"]) {
+            self.revision = revision;
+        }
+        let kv = range["kvs"].get(0).with_context(|| format!("etcd key {} does not exist", self.key))?;
+        self.parse_kv(kv)
+    }
+
+    async fn changed(&mut self) -> Result<Option<ProbeConfig>> {
+        if self.watch.is_none() {
+            let request = json!({ "create_request": {
+                "key": BASE64.encode(&self.key),
+                "start_revision": (self.revision + 1).to_string(),
+            }});
+            self.watch = Some(JsonLines::new(self.post("/v3/watch", request, None).await?));
+        }
+
+        let watch = self.watch.as_mut().context("no open etcd watch")?;
+        let Some(message) = watch.next().await? else {
+            self.watch = None;
+            anyhow::bail!("etcd closed the watch stream");
+        };
+        if let Some(error) = message.get("error") {
+            self.watch = None;
+            anyhow::bail!("etcd watch failed: {}", error);
+        }
+        let result = &message["result"];
+        if result["canceled"].as_bool().unwrap_or(false) {
+            self.watch = None;
+            // The revision we wanted to resume from was compacted away, so
+            // read the key again and watch from there
+            if revision(&result["compact_revision"]).is_some_and(|r| r > 0) {
+                return self.load().await.map(Some);
+            }
+            anyhow::bail!("etcd cancelled the watch: {}", result["cancel_reason"]);
+        }
+
+        // Only the latest event matters; created and progress messages have none
+        let Some(event) = result["events"].as_array().and_then(|events| events.last()) else {
+            return Ok(None);
+        };
+        if event["type"].as_str() == Some("DELETE") {
+            if let Some(revision) = revision(&event["kv"]["mod_revision"]) {
+                self.revision = revision;
+            }
+            tracing::warn!("etcd key {} was deleted, keeping the current config", self.key);
+            return Ok(None);
+        }
+        self.parse_kv(&event["kv"]).map(Some)
+    }
+}
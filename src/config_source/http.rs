@@ -0,0 +1,98 @@
+// Config served over HTTP(S), polled with conditional GETs so an unchanged
+// document costs a 304 rather than a download
+
+use anyhow::{Context, Result};
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+use crate::config::{ConfigManager, ProbeConfig};
+use super::{client_builder, ConfigSource, REQUEST_TIMEOUT};
+
+pub struct HttpUrl {
+    client: Client,
+    url: String,
+    // Bearer token from CONFIG_BEARER_TOKEN, or the file that holds it
+    token: Option<String>,
+    token_file: Option<String>,
+    poll_interval: tokio::time::Duration,
+    // Validators of the last document read
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl HttpUrl {
+    pub fn is_url(config: &str) -> bool {
+        config.starts_with("http://") || config.starts_with("https://")
+    }
+
+    /// `url` polled every CONFIG_POLL_INTERVAL_SECONDS, authenticated with
+    /// CONFIG_BEARER_TOKEN or the contents of CONFIG_BEARER_TOKEN_FILE
+    pub fn new(url: &str) -> Result<Self> {
+        let poll_interval_sec: u64 = std::env::var("CONFIG_POLL_INTERVAL_SECONDS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .unwrap_or(30);
+        Ok(Self {
+            client: client_builder().build()?,
+            url: url.to_string(),
+            token: std::env::var("CONFIG_BEARER_TOKEN").ok(),
+            token_file: std::env::var("CONFIG_BEARER_TOKEN_FILE").ok(),
+            poll_interval: tokio::time::Duration::from_secs(poll_interval_sec),
+            etag: None,
+            last_modified: None,
+        })
+    }
+
+    /// Download the document unless the server says it is unchanged. None if unchanged.
+    async fn fetch(&mut self) -> Result<Option<ProbeConfig>> {
+        let mut request = self.client.get(&self.url).timeout(REQUEST_TIMEOUT);
+        // Read per request so a rotated token file is picked up
+        let token = match &self.token_file {
+            Some(file) => Some(tokio::fs::read_to_string(file).await?.trim().to_string()),
+            None => self.token.clone(),
+        };
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        // A YAML content type wins over the URL's extension
+        let is_yaml = header(CONTENT_TYPE).is_some_and(|t| t.contains("yaml"));
+        let path = response.url().path().to_string();
+        let body = response.text().await?;
+        let config = ConfigManager::parse_file_config(if is_yaml { "config.yaml" } else { &path }, &body)?;
+        // Only remembered once parsed, so a broken document is reported on every poll
+        self.etag = etag;
+        self.last_modified = last_modified;
+        Ok(Some(config))
+    }
+}
+
+impl ConfigSource for HttpUrl {
+    fn name(&self) -> &'static str {
+        "HTTP"
+    }
+
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        self.etag = None;
+        self.last_modified = None;
+        self.fetch().await?.with_context(|| format!("{} returned no document", self.url))
+    }
+
+    async fn changed(&mut self) -> Result<Option<ProbeConfig>> {
+        tokio::time::sleep(self.poll_interval).await;
+        self.fetch().await
+    }
+}
@@ -0,0 +1,305 @@
+// Config from the Kubernetes API: a ConfigMap holding a config document,
+// LatencyProbeTarget custom resources holding one target each, or both.
+// Both are followed with watches:
+// https://kubernetes.io/docs/reference/using-api/api-concepts/#efficient-detection-of-changes
+
+use anyhow::{Context, Result};
+use reqwest::{Certificate, Client, StatusCode};
+use serde_json::Value;
+use tokio::time::{Duration, Instant};
+use crate::config::{ConfigManager, ProbeConfig, TargetConfig, SOURCE_RETRY_DELAY};
+use super::{client_builder, ConfigSource, JsonLines, named_target, targets_only_config, REQUEST_TIMEOUT};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+// API group, version and plural of the LatencyProbeTarget CRD
+const TARGET_CRD_PATH: &str = "apis/latency-probe.haondec.io/v1";
+const TARGET_CRD_PLURAL: &str = "latencyprobetargets";
+
+// Ask the API server to end watches after this long so they are reopened
+// regularly, even through proxies that drop idle streams silently
+const WATCH_TIMEOUT_SECS: u64 = 300;
+
+// A watch that keeps failing, or ending right away, is reopened after
+// SOURCE_RETRY_DELAY, doubling up to this
+const MAX_WATCH_RETRY_DELAY: Duration = Duration::from_secs(120);
+
+/// One watched collection
+struct Watch {
+    // e.g. api/v1/namespaces/default/configmaps
+    path: String,
+    // Field selector narrowing the collection, if any
+    selector: Option<String>,
+    // Where the watch resumes; from the last list or event
+    resource_version: String,
+    stream: Option<JsonLines>,
+    // When the current stream was opened
+    opened_at: Instant,
+    // Delay before reopening after the last failure; zero while healthy
+    retry_delay: Duration,
+    // The watch is not reopened before this; kept across calls, since a
+    // select over two watches drops the one still waiting
+    retry_at: Option<Instant>,
+}
+
+impl Watch {
+    fn new(path: String, selector: Option<String>) -> Self {
+        Self {
+            path,
+            selector,
+            resource_version: String::new(),
+            stream: None,
+            opened_at: Instant::now(),
+            retry_delay: Duration::ZERO,
+            retry_at: None,
+        }
+    }
+
+    /// Close the stream and hold off reopening it, longer each time in a row
+    fn failed(&mut self) {
+        self.stream = None;
+        self.retry_delay = (self.retry_delay * 2).clamp(SOURCE_RETRY_DELAY, MAX_WATCH_RETRY_DELAY);
+        self.retry_at = Some(Instant::now() + self.retry_delay);
+    }
+
+    /// The next ADDED, MODIFIED or DELETED event, or None when the watch
+    /// ended or only moved its resource version forward
+    async fn next(&mut self, api: &Api) -> Result<Option<Value>> {
+        let result = self.read(api).await;
+        if result.is_err() {
+            self.failed();
+        }
+        result
+    }
+
+    async fn read(&mut self, api: &Api) -> Result<Option<Value>> {
+        if self.stream.is_none() {
+            if let Some(retry_at) = self.retry_at.take() {
+                tokio::time::sleep_until(retry_at).await;
+            }
+            let timeout = WATCH_TIMEOUT_SECS.to_string();
+            let mut query = vec![
+                ("watch", "true"),
+                ("allowWatchBookmarks", "true"),
+                ("timeoutSeconds", timeout.as_str()),
+                ("resourceVersion", self.resource_version.as_str()),
+            ];
+            if let Some(selector) = &self.selector {
+                query.push(("fieldSelector", selector));
+            }
+            // A watch refused outright (403, 404, ...) answers with a Status
+            // document rather than events
+            let response = api.get(&self.path, &query, Duration::from_secs(WATCH_TIMEOUT_SECS) + REQUEST_TIMEOUT).await?.error_for_status()?;
+            self.stream = Some(JsonLines::new(response));
+            self.opened_at = Instant::now();
+        }
+
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(None);
+        };
+        let Some(event) = stream.next().await? else {
+            // Ended by the server's timeout; one that ends as soon as it is
+            // opened is a failure, or it would be reopened in a tight loop
+            if self.opened_at.elapsed() < SOURCE_RETRY_DELAY {
+                self.failed();
+            } else {
+                self.stream = None;
+            }
+            return Ok(None);
+        };
+        if event["type"] == "ERROR" {
+            // Usually 410 Gone: the resource version is too old, so start
+            // again from a fresh list
+            self.resource_version.clear();
+            anyhow::bail!("Kubernetes watch on {} failed: {}", self.path, event["object"]["message"]);
+        }
+        if let Some(version) = event["object"]["metadata"]["resourceVersion"].as_str() {
+            self.resource_version = version.to_string();
+        }
+        self.retry_delay = Duration::ZERO;
+        Ok((event["type"] != "BOOKMARK").then_some(event))
+    }
+}
+
+/// Connection to the API server
+struct Api {
+    client: Client,
+    // e.g. https://10.96.0.1:443
+    server: String,
+    token: Option<String>,
+    // Service account tokens are rotated, so they are read per request
+    token_file: Option<String>,
+}
+
+impl Api {
+    /// K8S_API_SERVER/K8S_TOKEN when set, otherwise the pod's service account
+    fn from_env() -> Result<Self> {
+        if let Ok(server) = std::env::var("K8S_API_SERVER") {
+            return Ok(Self {
+                client: client_builder().build()?,
+                server: server.trim_end_matches('/').to_string(),
+                token: std::env::var("K8S_TOKEN").ok(),
+                token_file: None,
+            });
+        }
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("not running in a Kubernetes pod; set K8S_API_SERVER")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let host = if host.contains(':') { format!("[{}]", host) } else { host };
+        let ca = std::fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT_DIR))?;
+        Ok(Self {
+            client: client_builder().add_root_certificate(Certificate::from_pem(&ca)?).build()?,
+            server: format!("https://{}:{}", host, port),
+            token: None,
+            token_file: Some(format!("{}/token", SERVICE_ACCOUNT_DIR)),
+        })
+    }
+
+    /// GET `path`; `timeout` covers reading the whole response, so a watch's
+    /// is longer than the time the server keeps it open
+    async fn get(&self, path: &str, query: &[(&str, &str)], timeout: Duration) -> Result<reqwest::Response> {
+        let mut request = self.client.get(format!("{}/{}", self.server, path)).query(query).timeout(timeout);
+        let token = match &self.token_file {
+            Some(file) => Some(tokio::fs::read_to_string(file).await?.trim().to_string()),
+            None => self.token.clone(),
+        };
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        Ok(request.send().await?)
+    }
+}
+
+pub struct Kubernetes {
+    api: Api,
+    configmap: Option<(String, Option<String>)>,
+    configmap_watch: Option<Watch>,
+    target_watch: Option<Watch>,
+    namespace: String,
+}
+
+impl Kubernetes {
+    /// K8S_CONFIGMAP (and K8S_CONFIGMAP_KEY) and/or K8S_TARGET_CRD=true, in
+    /// K8S_NAMESPACE or the pod's own namespace
+    pub fn from_env() -> Result<Self> {
+        let api = Api::from_env()?;
+        let namespace = match std::env::var("K8S_NAMESPACE") {
+            Ok(namespace) => namespace,
+            Err(_) => std::fs::read_to_string(format!("{}/namespace", SERVICE_ACCOUNT_DIR))
+                .context("K8S_NAMESPACE is not set and the pod namespace is unknown")?
+                .trim()
+                .to_string(),
+        };
+        let configmap = std::env::var("K8S_CONFIGMAP")
+            .ok()
+            .map(|name| (name, std::env::var("K8S_CONFIGMAP_KEY").ok()));
+        let use_crd = std::env::var("K8S_TARGET_CRD")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse::<bool>()
+            .unwrap_or(false);
+        if configmap.is_none() && !use_crd {
+            anyhow::bail!("CONFIG_SOURCE=kubernetes needs K8S_CONFIGMAP, K8S_TARGET_CRD=true or both");
+        }
+
+        let configmap_watch = configmap.as_ref().map(|(name, _)| Watch::new(
+            format!("api/v1/namespaces/{}/configmaps", namespace),
+            Some(format!("metadata.name={}", name)),
+        ));
+        let target_watch = use_crd.then(|| Watch::new(
+            format!("{}/namespaces/{}/{}", TARGET_CRD_PATH, namespace, TARGET_CRD_PLURAL),
+            None,
+        ));
+        Ok(Self { api, configmap, configmap_watch, target_watch, namespace })
+    }
+
+    /// Read the ConfigMap document, or the CRD-only defaults
+    async fn base_config(&mut self) -> Result<ProbeConfig> {
+        let (Some((name, key)), Some(watch)) = (&self.configmap, self.configmap_watch.as_mut()) else {
+            return targets_only_config();
+        };
+        let response = self.api.get(&format!("api/v1/namespaces/{}/configmaps/{}", self.namespace, name), &[], REQUEST_TIMEOUT).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            anyhow::bail!("ConfigMap {}/{} does not exist", self.namespace, name);
+        }
+        let configmap: Value = response.error_for_status()?.json().await?;
+        if watch.resource_version.is_empty()
+            && let Some(version) = configmap["metadata"]["resourceVersion"].as_str()
+        {
+            watch.resource_version = version.to_string();
+        }
+
+        let data = configmap["data"].as_object().context("ConfigMap has no data")?;
+        // Without K8S_CONFIGMAP_KEY the ConfigMap must hold a single document
+        let (key, content) = match key {
+            Some(key) => (key.as_str(), data.get(key)),
+            None if data.len() == 1 => data.iter().next().map(|(k, v)| (k.as_str(), Some(v))).unwrap_or_default(),
+            None => anyhow::bail!("ConfigMap {} has several keys; set K8S_CONFIGMAP_KEY", name),
+        };
+        let content = content
+            .and_then(Value::as_str)
+            .with_context(|| format!("ConfigMap {} has no key {}", name, key))?;
+        // The key's extension picks JSON or YAML like a local file name
+        ConfigManager::parse_file_config(key, content)
+    }
+
+    /// List LatencyProbeTarget resources; each spec is one target, named
+    /// after the resource unless it says otherwise
+    async fn crd_targets(&mut self) -> Result<Vec<TargetConfig>> {
+        let Some(watch) = self.target_watch.as_mut() else {
+            return Ok(Vec::new());
+        };
+        let list: Value = self.api.get(&watch.path, &[], REQUEST_TIMEOUT).await?.error_for_status()?.json().await?;
+        if watch.resource_version.is_empty()
+            && let Some(version) = list["metadata"]["resourceVersion"].as_str()
+        {
+            watch.resource_version = version.to_string();
+        }
+
+        let mut targets = Vec::new();
+        for item in list["items"].as_array().into_iter().flatten() {
+            let name = item["metadata"]["name"].as_str().unwrap_or_default();
+            // One broken resource shouldn't take the others down with it
+            match named_target(name, item["spec"].clone()) {
+                Ok(target) => targets.push(target),
+                Err(e) => tracing::error!("Skipping {} {}: {}", TARGET_CRD_PLURAL, name, e),
+            }
+        }
+        targets.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(targets)
+    }
+}
+
+impl ConfigSource for Kubernetes {
+    fn name(&self) -> &'static str {
+        "Kubernetes"
+    }
+
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        let mut config = self.base_config().await?;
+        config.targets.extend(self.crd_targets().await?);
+        // The ConfigMap's defaults apply to the custom resources too
+        config.apply_defaults()?;
+        Ok(config)
+    }
+
+    async fn changed(&mut self) -> Result<Option<ProbeConfig>> {
+        let event = match (self.configmap_watch.as_mut(), self.target_watch.as_mut()) {
+            (Some(configmap), Some(targets)) => tokio::select! {
+                event = configmap.next(&self.api) => event,
+                event = targets.next(&self.api) => event,
+            },
+            (Some(watch), None) | (None, Some(watch)) => watch.next(&self.api).await,
+            (None, None) => return Ok(None),
+        };
+        // Any change is followed by a full read, which is also how a watch
+        // that failed gets its resource version back
+        match event {
+            Ok(Some(_)) => self.load().await.map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => {
+                tracing::warn!("{:#}, reading the config again", e);
+                self.load().await.map(Some)
+            }
+        }
+    }
+}
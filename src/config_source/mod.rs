@@ -0,0 +1,88 @@
+// Remote config stores, each either notifying about changes or cheap to
+// poll. The local file and AppConfig are handled in config.rs itself.
+
+use anyhow::Result;
+use reqwest::Response;
+use serde_json::Value;
+use std::future::Future;
+use std::time::Duration;
+use crate::config::{ConfigManager, ProbeConfig, TargetConfig};
+
+pub mod consul;
+pub mod etcd;
+pub mod http;
+pub mod kubernetes;
+pub mod s3;
+pub mod ssm;
+
+// Limits for requests to config stores, so a hung server fails the read
+// (and is retried) instead of stalling reloads for good
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Settings used when a source only provides targets
+const TARGETS_ONLY_CONFIG: &str = r#"{"probe_interval_ms": 1000, "default_timeout_ms": 1000, "targets": []}"#;
+
+/// Where a ConfigManager gets its config from
+pub trait ConfigSource: Send + 'static {
+    /// Shown in logs
+    fn name(&self) -> &'static str;
+
+    /// The current config
+    fn load(&mut self) -> impl Future<Output = Result<ProbeConfig>> + Send;
+
+    /// Wait for the config to change. Returns None when the source woke up
+    /// without a change (e.g. a blocking query timed out).
+    fn changed(&mut self) -> impl Future<Output = Result<Option<ProbeConfig>>> + Send;
+}
+
+/// Client for a config store. Only connecting has a timeout; requests set
+/// their own, since watches stay open far longer than a plain read.
+fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().connect_timeout(CONNECT_TIMEOUT)
+}
+
+/// The config a source that only provides targets adds them to
+fn targets_only_config() -> Result<ProbeConfig> {
+    ConfigManager::parse_file_config("targets.json", TARGETS_ONLY_CONFIG)
+}
+
+/// A target stored on its own, named after where it is stored unless its
+/// spec says otherwise
+fn named_target(name: &str, mut spec: Value) -> serde_json::Result<TargetConfig> {
+    if let Some(spec) = spec.as_object_mut() {
+        spec.entry("name").or_insert_with(|| Value::from(name));
+    }
+    serde_json::from_value(spec)
+}
+
+/// A streamed response carrying one JSON message per line, as etcd and
+/// Kubernetes watches do
+struct JsonLines {
+    response: Response,
+    // Any partial message read so far
+    buffer: Vec<u8>,
+}
+
+impl JsonLines {
+    fn new(response: Response) -> Self {
+        Self { response, buffer: Vec::new() }
+    }
+
+    /// The next message, or None once the server ended the stream
+    async fn next(&mut self) -> Result<Option<Value>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Ok(Some(serde_json::from_slice(&line)?));
+            }
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Config stored as an S3 object, polled with conditional GETs so an
+// unchanged object costs a 304 rather than a download
+
+use anyhow::{Context, Result};
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::Client;
+use crate::config::{ConfigManager, ProbeConfig};
+use super::ConfigSource;
+
+pub struct S3Object {
+    client: Client,
+    bucket: String,
+    key: String,
+    poll_interval: tokio::time::Duration,
+    // ETag of the last object read, sent back as If-None-Match
+    etag: Option<String>,
+}
+
+impl S3Object {
+    /// S3_CONFIG_URL (s3://bucket/key, whose extension picks JSON or YAML like
+    /// a local file) polled every CONFIG_POLL_INTERVAL_SECONDS
+    pub async fn from_env() -> Result<Self> {
+        let url = std::env::var("S3_CONFIG_URL").context("S3_CONFIG_URL must name the config object")?;
+        let (bucket, key) = url
+            .strip_prefix("s3://")
+            .and_then(|path| path.split_once('/'))
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .with_context(|| format!("S3_CONFIG_URL {} is not of the form s3://bucket/key", url))?;
+        let poll_interval_sec: u64 = std::env::var("CONFIG_POLL_INTERVAL_SECONDS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .unwrap_or(30);
+
+        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+        let aws_cfg = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(region_provider)
+            .load()
+            .await;
+        Ok(Self {
+            client: Client::new(&aws_cfg),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            poll_interval: tokio::time::Duration::from_secs(poll_interval_sec),
+            etag: None,
+        })
+    }
+
+    /// Download the object unless its ETag still matches. None if unchanged.
+    async fn fetch(&mut self) -> Result<Option<ProbeConfig>> {
+        let result = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .set_if_none_match(self.etag.clone())
+            .send()
+            .await;
+        let object = match result {
+            Ok(object) => object,
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 304) => return Ok(None),
+            Err(e) => {
+                return Err(anyhow::Error::new(e.into_service_error()))
+                    .with_context(|| format!("fetching s3://{}/{}", self.bucket, self.key));
+            }
+        };
+        let etag = object.e_tag.clone();
+        let body = object.body.collect().await?.into_bytes();
+        let config = ConfigManager::parse_file_config(&self.key, std::str::from_utf8(&body)?)?;
+        // Only remembered once parsed, so a broken upload is reported on every poll
+        self.etag = etag;
+        Ok(Some(config))
+    }
+}
+
+impl ConfigSource for S3Object {
+    fn name(&self) -> &'static str {
+        "S3"
+    }
+
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        self.etag = None;
+        self.fetch().await?.context("S3 returned no object")
+    }
+
+    async fn changed(&mut self) -> Result<Option<ProbeConfig>> {
+        tokio::time::sleep(self.poll_interval).await;
+        self.fetch().await
+    }
+}
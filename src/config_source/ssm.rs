@@ -0,0 +1,140 @@
+// Config from SSM Parameter Store: one parameter holding a config document,
+// a parameter tree holding one target per parameter, or both
+
+use anyhow::{Context, Result};
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_ssm::Client;
+use aws_sdk_ssm::types::Parameter;
+use crate::config::{ConfigManager, ProbeConfig, interpolate_env};
+use super::{ConfigSource, named_target, targets_only_config};
+
+pub struct SsmParameters {
+    client: Client,
+    // Parameter holding a whole config document
+    parameter: Option<String>,
+    // Path whose parameters each hold one target
+    targets_path: Option<String>,
+    poll_interval: tokio::time::Duration,
+    // Name and version of every parameter last read; unchanged versions
+    // are not parsed again
+    versions: Vec<(String, i64)>,
+}
+
+impl SsmParameters {
+    /// SSM_CONFIG_PARAMETER and/or SSM_TARGETS_PATH, polled every
+    /// CONFIG_POLL_INTERVAL_SECONDS
+    pub async fn from_env() -> Result<Self> {
+        let parameter = std::env::var("SSM_CONFIG_PARAMETER").ok();
+        let targets_path = std::env::var("SSM_TARGETS_PATH").ok();
+        if parameter.is_none() && targets_path.is_none() {
+            anyhow::bail!("CONFIG_SOURCE=ssm needs SSM_CONFIG_PARAMETER, SSM_TARGETS_PATH or both");
+        }
+        let poll_interval_sec: u64 = std::env::var("CONFIG_POLL_INTERVAL_SECONDS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .unwrap_or(30);
+
+        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+        let aws_cfg = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(region_provider)
+            .load()
+            .await;
+        Ok(Self {
+            client: Client::new(&aws_cfg),
+            parameter,
+            targets_path,
+            poll_interval: tokio::time::Duration::from_secs(poll_interval_sec),
+            versions: Vec::new(),
+        })
+    }
+
+    /// Every parameter this source reads, SecureStrings decrypted
+    async fn parameters(&self) -> Result<(Option<Parameter>, Vec<Parameter>)> {
+        let document = match &self.parameter {
+            Some(name) => {
+                let output = self.client
+                    .get_parameter()
+                    .name(name)
+                    .with_decryption(true)
+                    .send()
+                    .await
+                    .map_err(|e| anyhow::Error::new(e.into_service_error()))
+                    .with_context(|| format!("reading SSM parameter {}", name))?;
+                Some(output.parameter.with_context(|| format!("SSM parameter {} has no value", name))?)
+            }
+            None => None,
+        };
+
+        let mut targets = Vec::new();
+        if let Some(path) = &self.targets_path {
+            let mut pages = self.client
+                .get_parameters_by_path()
+                .path(path)
+                .recursive(true)
+                .with_decryption(true)
+                .into_paginator()
+                .send();
+            while let Some(page) = pages.next().await {
+                let page = page
+                    .map_err(|e| anyhow::Error::new(e.into_service_error()))
+                    .with_context(|| format!("listing SSM parameters under {}", path))?;
+                targets.extend(page.parameters.unwrap_or_default());
+            }
+        }
+        targets.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok((document, targets))
+    }
+
+    /// Read every parameter; None if none of their versions changed
+    async fn fetch(&mut self) -> Result<Option<ProbeConfig>> {
+        let (document, targets) = self.parameters().await?;
+        let versions: Vec<(String, i64)> = document.iter()
+            .chain(&targets)
+            .map(|p| (p.name.clone().unwrap_or_default(), p.version))
+            .collect();
+        if versions == self.versions {
+            return Ok(None);
+        }
+
+        let mut config = match &document {
+            Some(parameter) => ConfigManager::parse_file_config(
+                parameter.name().unwrap_or_default(),
+                parameter.value().unwrap_or_default(),
+            )?,
+            None => targets_only_config()?,
+        };
+        for parameter in &targets {
+            let name = parameter.name().unwrap_or_default();
+            // Named after the last path segment unless the value says otherwise
+            let short_name = name.rsplit('/').next().unwrap_or(name);
+            let target = interpolate_env(parameter.value().unwrap_or_default())
+                .and_then(|value| Ok(serde_json::from_str(&value)?))
+                .and_then(|spec| Ok(named_target(short_name, spec)?));
+            // One broken parameter shouldn't take the others down with it
+            match target {
+                Ok(target) => config.targets.push(target),
+                Err(e) => tracing::error!("Skipping SSM parameter {}: {}", name, e),
+            }
+        }
+        // The document's defaults apply to the parameter targets too
+        config.apply_defaults()?;
+        self.versions = versions;
+        Ok(Some(config))
+    }
+}
+
+impl ConfigSource for SsmParameters {
+    fn name(&self) -> &'static str {
+        "SSM Parameter Store"
+    }
+
+    async fn load(&mut self) -> Result<ProbeConfig> {
+        self.versions.clear();
+        self.fetch().await?.context("SSM returned no parameters")
+    }
+
+    async fn changed(&mut self) -> Result<Option<ProbeConfig>> {
+        tokio::time::sleep(self.poll_interval).await;
+        self.fetch().await
+    }
+}
@@ -0,0 +1,158 @@
+// Internal event bus: every probe publishes its lifecycle here and consumers
+// (metrics, alerting, history, exporters, ...) subscribe independently.
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::prober::{failure_of, reason_of, ProbeDetails, ProbeOutcome, ProbeStats, TargetAddress};
+use crate::timestamp::monotonic_ns;
+
+const EVENT_BUS_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    Unknown,
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProbeEvent {
+    Started {
+        target: String,
+        // With probe_all_addresses, the address of the target probed
+        address: Option<IpAddr>,
+        probe_type: &'static str,
+        probe_id: u64,
+        timestamp_ns: u128,
+    },
+    Completed {
+        target: String,
+        address: Option<IpAddr>,
+        probe_type: &'static str,
+        probe_id: u64,
+        latency: Duration,
+        details: Box<ProbeDetails>,
+        stats: ProbeStats,
+        timestamp_ns: u128,
+    },
+    Failed {
+        target: String,
+        address: Option<IpAddr>,
+        probe_type: &'static str,
+        probe_id: u64,
+        reason: &'static str,
+        status_code: Option<u16>,
+        error: String,
+        stats: ProbeStats,
+        timestamp_ns: u128,
+    },
+    StateChanged {
+        target: String,
+        address: Option<IpAddr>,
+        probe_type: &'static str,
+        from: TargetState,
+        to: TargetState,
+    },
+}
+
+static BUS: Lazy<broadcast::Sender<ProbeEvent>> =
+    Lazy::new(|| broadcast::channel(EVENT_BUS_CAPACITY).0);
+
+// Ids of probe runs, unique within the process; they tie an exemplar to a
+// probe's log lines
+static NEXT_PROBE_ID: AtomicU64 = AtomicU64::new(1);
+
+// Last known state per (target, address, probe_type), used to derive
+// StateChanged events
+static STATES: Lazy<Mutex<HashMap<(TargetAddress, &'static str), TargetState>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Subscribe to all probe events published from now on
+pub fn subscribe() -> broadcast::Receiver<ProbeEvent> {
+    BUS.subscribe()
+}
+
+/// Publish an event; it is dropped silently when nobody is subscribed
+pub fn publish(event: ProbeEvent) {
+    let _ = BUS.send(event);
+}
+
+/// Id for a new probe run, for the events it publishes
+pub fn next_probe_id() -> u64 {
+    NEXT_PROBE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Publish the start of probe run `probe_id`
+pub fn publish_started(target: &str, address: Option<IpAddr>, probe_type: &'static str, probe_id: u64) {
+    publish(ProbeEvent::Started {
+        target: target.to_string(),
+        address,
+        probe_type,
+        probe_id,
+        timestamp_ns: monotonic_ns(),
+    });
+}
+
+/// Publish the outcome of a probe, followed by a StateChanged event if the
+/// target flipped between up and down
+pub fn publish_result(
+    target: &str,
+    address: Option<IpAddr>,
+    probe_type: &'static str,
+    probe_id: u64,
+    stats: ProbeStats,
+    result: &anyhow::Result<ProbeOutcome>,
+) {
+    let timestamp_ns = monotonic_ns();
+    let new_state = match result {
+        Ok(outcome) => {
+            publish(ProbeEvent::Completed {
+                target: target.to_string(),
+                address,
+                probe_type,
+                probe_id,
+                latency: outcome.latency,
+                details: Box::new(outcome.details.clone()),
+                stats,
+                timestamp_ns,
+            });
+            TargetState::Up
+        }
+        Err(e) => {
+            let failure = failure_of(e);
+            publish(ProbeEvent::Failed {
+                target: target.to_string(),
+                address,
+                probe_type,
+                probe_id,
+                reason: reason_of(e),
+                status_code: failure.and_then(|f| f.status_code),
+                error: format!("{:#}", e),
+                stats,
+                timestamp_ns,
+            });
+            TargetState::Down
+        }
+    };
+
+    let previous = {
+        let mut states = STATES.lock().unwrap();
+        states
+            .insert(((target.to_string(), address), probe_type), new_state)
+            .unwrap_or(TargetState::Unknown)
+    };
+    if previous != new_state {
+        publish(ProbeEvent::StateChanged {
+            target: target.to_string(),
+            address,
+            probe_type,
+            from: previous,
+            to: new_state,
+        });
+    }
+}
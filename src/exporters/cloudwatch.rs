@@ -0,0 +1,208 @@
+// CloudWatch output, for alarms that live in CloudWatch. Either calls
+// PutMetricData with per-target statistics every flush interval, or writes
+// one Embedded Metric Format document per probe result to stdout for the
+// CloudWatch agent, Lambda or ECS log drivers to extract.
+// https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+
+use anyhow::Result;
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_cloudwatch::Client;
+use aws_sdk_cloudwatch::primitives::DateTime;
+use aws_sdk_cloudwatch::types::{Dimension, MetricDatum, StandardUnit, StatisticSet};
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use crate::events::ProbeEvent;
+use super::{Summaries, SummaryExporter};
+
+// PutMetricData accepts at most 1000 data points per request
+const MAX_DATUMS_PER_REQUEST: usize = 1000;
+
+// CloudWatch allows 30 dimensions per metric, two of which are target and
+// probe_type; the address of a probe_all_addresses target counts as a label
+const MAX_LABEL_DIMENSIONS: usize = 28;
+
+enum Mode {
+    Api { client: Client, flush_interval: Duration },
+    Emf,
+}
+
+pub struct CloudWatch {
+    mode: Mode,
+    namespace: String,
+    // Target labels used as dimensions; None means all of them
+    label_dimensions: Option<Vec<String>>,
+    // API mode only
+    summaries: Summaries,
+}
+
+impl CloudWatch {
+    /// CLOUDWATCH_MODE (api or emf), CLOUDWATCH_NAMESPACE (default
+    /// LatencyProbe), CLOUDWATCH_DIMENSIONS and CLOUDWATCH_FLUSH_INTERVAL_SECONDS
+    /// (default 60); None without CLOUDWATCH_MODE
+    pub async fn from_env() -> Result<Option<Self>> {
+        let Ok(mode) = std::env::var("CLOUDWATCH_MODE") else {
+            return Ok(None);
+        };
+        let mode = match mode.to_lowercase().as_str() {
+            "api" => {
+                let flush_interval_sec: u64 = super::env_number("CLOUDWATCH_FLUSH_INTERVAL_SECONDS", 60)?;
+                let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+                let aws_cfg = aws_config::defaults(aws_config::BehaviorVersion::latest())
+                    .region(region_provider)
+                    .load()
+                    .await;
+                Mode::Api {
+                    client: Client::new(&aws_cfg),
+                    flush_interval: Duration::from_secs(flush_interval_sec.max(1)),
+                }
+            }
+            "emf" => Mode::Emf,
+            other => anyhow::bail!("CLOUDWATCH_MODE {:?} must be api or emf", other),
+        };
+        // Every dimension combination is a separate custom metric, so the
+        // labels can be narrowed to the ones alarms are set on
+        let label_dimensions = std::env::var("CLOUDWATCH_DIMENSIONS").ok().map(|names| {
+            names.split(',').map(str::trim).filter(|n| !n.is_empty()).map(String::from).collect()
+        });
+        Ok(Some(Self {
+            mode,
+            namespace: std::env::var("CLOUDWATCH_NAMESPACE").unwrap_or_else(|_| "LatencyProbe".to_string()),
+            label_dimensions,
+            summaries: Summaries::new(),
+        }))
+    }
+
+    pub async fn run(self, rx: broadcast::Receiver<ProbeEvent>) {
+        super::run_summarised(self, rx).await;
+    }
+
+    /// `target`, `probe_type`, the address if any and the target's labels
+    /// chosen as dimensions
+    fn dimensions(&self, target: &str, address: Option<IpAddr>, probe_type: &str) -> Vec<(String, String)> {
+        let labels = crate::metrics::target_labels(target, address).into_iter().filter(|(name, _)| {
+            name == "address" || self.label_dimensions.as_ref().is_none_or(|names| names.contains(name))
+        });
+        [("target".to_string(), target.to_string()), ("probe_type".to_string(), probe_type.to_string())]
+            .into_iter()
+            .chain(labels.take(MAX_LABEL_DIMENSIONS))
+            .collect()
+    }
+
+    fn emf(&self, event: &ProbeEvent) -> Option<Value> {
+        let (target, address, probe_type, metrics, values) = match event {
+            ProbeEvent::Completed { target, address, probe_type, latency, .. } => (
+                target,
+                address,
+                probe_type,
+                json!([{ "Name": "Latency", "Unit": "Milliseconds" }, { "Name": "Success", "Unit": "Count" }]),
+                json!({ "Latency": latency.as_secs_f64() * 1000.0, "Success": 1 }),
+            ),
+            ProbeEvent::Failed { target, address, probe_type, reason, error, .. } => (
+                target,
+                address,
+                probe_type,
+                json!([{ "Name": "Failure", "Unit": "Count" }]),
+                // reason and error are searchable properties, not dimensions
+                json!({ "Failure": 1, "reason": reason, "error": error }),
+            ),
+            _ => return None,
+        };
+        let Value::Object(mut document) = values else {
+            return None;
+        };
+        let dimensions = self.dimensions(target, *address, probe_type);
+        let names: Vec<&str> = dimensions.iter().map(|(name, _)| name.as_str()).collect();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        document.insert("_aws".to_string(), json!({
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": self.namespace,
+                "Dimensions": [names],
+                "Metrics": metrics,
+            }],
+        }));
+        for (name, value) in dimensions {
+            document.insert(name, Value::String(value));
+        }
+        Some(Value::Object(document))
+    }
+}
+
+impl SummaryExporter for CloudWatch {
+    const NAME: &'static str = "cloudwatch";
+
+    fn flush_interval(&self) -> Duration {
+        match &self.mode {
+            Mode::Api { flush_interval, .. } => *flush_interval,
+            // Documents are written as events arrive, so flushing is a no-op
+            Mode::Emf => Duration::from_secs(3600),
+        }
+    }
+
+    fn summaries(&mut self) -> &mut Summaries {
+        &mut self.summaries
+    }
+
+    /// Summarised in API mode; in EMF mode written out right away
+    fn record(&mut self, event: ProbeEvent) {
+        if let Mode::Emf = self.mode {
+            if let Some(document) = self.emf(&event) {
+                // One line per document, written whole so log lines can't interleave
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "{}", document);
+            }
+            return;
+        }
+        super::summarise(&mut self.summaries, event);
+    }
+
+    async fn flush(&mut self) {
+        let Mode::Api { client, .. } = &self.mode else {
+            return;
+        };
+        let timestamp = DateTime::from(SystemTime::now());
+        let mut data = Vec::new();
+        for ((target, address, probe_type), summary) in &self.summaries {
+            let dimensions: Vec<Dimension> = self.dimensions(target, *address, probe_type)
+                .into_iter()
+                .map(|(name, value)| Dimension::builder().name(name).value(value).build())
+                .collect();
+            let datum = |name: &str, unit: StandardUnit| {
+                MetricDatum::builder()
+                    .metric_name(name)
+                    .set_dimensions(Some(dimensions.clone()))
+                    .unit(unit)
+                    .timestamp(timestamp)
+            };
+            if summary.successes > 0 {
+                data.push(datum("Latency", StandardUnit::Milliseconds)
+                    .statistic_values(StatisticSet::builder()
+                        .sample_count(summary.successes as f64)
+                        .sum(summary.latency_ms_sum)
+                        .minimum(summary.latency_ms_min)
+                        .maximum(summary.latency_ms_max)
+                        .build())
+                    .build());
+            }
+            data.push(datum("Success", StandardUnit::Count).value(summary.successes as f64).build());
+            data.push(datum("Failure", StandardUnit::Count).value(summary.failures as f64).build());
+        }
+        self.summaries.clear();
+
+        for chunk in data.chunks(MAX_DATUMS_PER_REQUEST) {
+            // Statistics are per interval; a failed request is not retried
+            if let Err(e) = client
+                .put_metric_data()
+                .namespace(&self.namespace)
+                .set_metric_data(Some(chunk.to_vec()))
+                .send()
+                .await
+            {
+                tracing::warn!("Cannot put {} metric data to CloudWatch: {:#}", chunk.len(), anyhow::Error::new(e.into_service_error()));
+            }
+        }
+    }
+}
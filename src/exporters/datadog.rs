@@ -0,0 +1,131 @@
+// Datadog output through the metrics API, for hosts without a Datadog agent
+// (with an agent, the StatsD exporter in DogStatsD format is simpler).
+// Results are summarised per target every flush interval and submitted as
+// series tagged with the target's labels.
+// https://docs.datadoghq.com/api/latest/metrics/#submit-metrics
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use crate::events::ProbeEvent;
+use super::{Summaries, SummaryExporter};
+
+// Keeps each request well under the API's 5 MB uncompressed payload limit
+const MAX_SERIES_PER_REQUEST: usize = 1000;
+
+// MetricIntakeType in the v2 series API
+const COUNT: u8 = 1;
+const GAUGE: u8 = 3;
+
+pub struct Datadog {
+    client: reqwest::Client,
+    // https://api.<site>/api/v2/series unless DATADOG_URL is set
+    url: String,
+    api_key: String,
+    prefix: String,
+    host: String,
+    // DATADOG_TAGS, added to every series
+    tags: Vec<String>,
+    flush_interval: Duration,
+    summaries: Summaries,
+    // Series not submitted yet
+    pending: Vec<Value>,
+}
+
+impl Datadog {
+    /// DATADOG_API_KEY, DATADOG_SITE (default datadoghq.com) or DATADOG_URL, DATADOG_PREFIX
+    /// (default latency_probe), DATADOG_TAGS, DATADOG_HOSTNAME and
+    /// DATADOG_FLUSH_INTERVAL_SECONDS (default 15); None without DATADOG_API_KEY
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(api_key) = std::env::var("DATADOG_API_KEY") else {
+            return Ok(None);
+        };
+        let site = std::env::var("DATADOG_SITE").unwrap_or_else(|_| "datadoghq.com".to_string());
+        // DATADOG_URL replaces the whole endpoint, e.g. for a relay
+        let url = std::env::var("DATADOG_URL").unwrap_or_else(|_| format!("https://api.{}/api/v2/series", site));
+        let flush_interval_sec: u64 = super::env_number("DATADOG_FLUSH_INTERVAL_SECONDS", 15)?;
+        // Comma or space separated, as in the agent's DD_TAGS
+        let tags = std::env::var("DATADOG_TAGS")
+            .unwrap_or_default()
+            .split([',', ' '])
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(Some(Self {
+            client: reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?,
+            url,
+            api_key,
+            prefix: std::env::var("DATADOG_PREFIX").unwrap_or_else(|_| "latency_probe".to_string()),
+            host: std::env::var("DATADOG_HOSTNAME").unwrap_or_else(|_| super::hostname()),
+            tags,
+            flush_interval: Duration::from_secs(flush_interval_sec.max(1)),
+            summaries: Summaries::new(),
+            pending: Vec::new(),
+        }))
+    }
+
+    pub async fn run(self, rx: broadcast::Receiver<ProbeEvent>) {
+        super::run_summarised(self, rx).await;
+    }
+
+    async fn submit(&self, series: &[Value]) -> Result<()> {
+        self.client
+            .post(&self.url)
+            .header("DD-API-KEY", &self.api_key)
+            .json(&json!({ "series": series }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl SummaryExporter for Datadog {
+    const NAME: &'static str = "datadog";
+
+    fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    fn summaries(&mut self) -> &mut Summaries {
+        &mut self.summaries
+    }
+
+    async fn flush(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let interval = self.flush_interval.as_secs();
+        for ((target, address, probe_type), summary) in std::mem::take(&mut self.summaries) {
+            let tags: Vec<String> = [format!("target:{}", target), format!("probe_type:{}", probe_type)]
+                .into_iter()
+                .chain(crate::metrics::target_labels(&target, address).into_iter().map(|(k, v)| format!("{}:{}", k, v)))
+                .chain(self.tags.iter().cloned())
+                .collect();
+            let series = |metric: &str, kind: u8, value: f64| json!({
+                "metric": format!("{}.{}", self.prefix, metric),
+                "type": kind,
+                "interval": interval,
+                "points": [{ "timestamp": now, "value": value }],
+                "tags": tags,
+                "resources": [{ "type": "host", "name": self.host }],
+            });
+            if let Some(mean) = summary.mean_latency_ms() {
+                let mut latency = series("latency", GAUGE, mean);
+                latency["unit"] = json!("millisecond");
+                self.pending.push(latency);
+            }
+            self.pending.push(series("success", COUNT, summary.successes as f64));
+            self.pending.push(series("failure", COUNT, summary.failures as f64));
+        }
+
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(MAX_SERIES_PER_REQUEST);
+            if let Err(e) = self.submit(&self.pending[..count]).await {
+                tracing::warn!("Cannot submit {} series to Datadog, retrying at the next flush: {:#}", self.pending.len(), e);
+                super::drop_oldest(&mut self.pending);
+                return;
+            }
+            self.pending.drain(..count);
+        }
+    }
+}
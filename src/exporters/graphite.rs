@@ -0,0 +1,111 @@
+// Graphite output: per-target aggregates pushed to carbon's plaintext
+// protocol every flush interval. Graphite keeps one value per series and
+// timestamp, so probe results are summarised rather than sent one by one.
+
+use anyhow::{Context, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+use crate::events::ProbeEvent;
+use super::{Summaries, SummaryExporter};
+
+// Longest a connect or a write to carbon may take, so a blackholed or
+// stalled server can't hold up the consumer
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Graphite {
+    // carbon plaintext listener, e.g. graphite.internal:2003
+    addr: String,
+    prefix: String,
+    flush_interval: Duration,
+    stream: Option<TcpStream>,
+    summaries: Summaries,
+    // Lines not written yet
+    pending: Vec<String>,
+}
+
+impl Graphite {
+    /// GRAPHITE_ADDR (host:port), GRAPHITE_PREFIX (default probe) and
+    /// GRAPHITE_FLUSH_INTERVAL_SECONDS (default 10); None without GRAPHITE_ADDR
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(addr) = std::env::var("GRAPHITE_ADDR") else {
+            return Ok(None);
+        };
+        let flush_interval_sec: u64 = super::env_number("GRAPHITE_FLUSH_INTERVAL_SECONDS", 10)?;
+        Ok(Some(Self {
+            addr,
+            prefix: std::env::var("GRAPHITE_PREFIX").unwrap_or_else(|_| "probe".to_string()),
+            flush_interval: Duration::from_secs(flush_interval_sec.max(1)),
+            stream: None,
+            summaries: Summaries::new(),
+            pending: Vec::new(),
+        }))
+    }
+
+    pub async fn run(self, rx: broadcast::Receiver<ProbeEvent>) {
+        super::run_summarised(self, rx).await;
+    }
+
+    async fn write(&mut self) -> Result<()> {
+        if self.stream.is_none() {
+            let stream = timeout(IO_TIMEOUT, TcpStream::connect(&self.addr)).await.context("connect timed out")??;
+            self.stream = Some(stream);
+        }
+        if let Some(stream) = self.stream.as_mut() {
+            let lines = self.pending.concat();
+            timeout(IO_TIMEOUT, async {
+                stream.write_all(lines.as_bytes()).await?;
+                stream.flush().await
+            })
+            .await
+            .context("write timed out")??;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl SummaryExporter for Graphite {
+    const NAME: &'static str = "graphite";
+
+    fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    fn summaries(&mut self) -> &mut Summaries {
+        &mut self.summaries
+    }
+
+    async fn flush(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        for ((target, address, probe_type), summary) in std::mem::take(&mut self.summaries) {
+            // Each address of a probe_all_addresses target is a level below the target
+            let target = match address {
+                Some(address) => format!("{}.{}", path_component(&target), path_component(&address.to_string())),
+                None => path_component(&target),
+            };
+            let path = format!("{}.{}.{}", self.prefix, target, path_component(probe_type));
+            if let Some(mean) = summary.mean_latency_ms() {
+                self.pending.push(format!("{}.latency_ms {} {}\n", path, mean, now));
+            }
+            self.pending.push(format!("{}.success {} {}\n", path, summary.successes, now));
+            self.pending.push(format!("{}.failure {} {}\n", path, summary.failures, now));
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+        if let Err(e) = self.write().await {
+            tracing::warn!("Cannot send {} lines to Graphite at {}, retrying at the next flush: {:#}", self.pending.len(), self.addr, e);
+            // Reconnect next time; the connection may be half-dead
+            self.stream = None;
+            super::drop_oldest(&mut self.pending);
+        }
+    }
+}
+
+/// Anything but letters, digits, `-` and `_` would change the metric path
+fn path_component(value: &str) -> String {
+    value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
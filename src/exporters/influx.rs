@@ -0,0 +1,193 @@
+// InfluxDB output: probe results written as line protocol, in batches, to the
+// HTTP write API (v1 /write or v2 /api/v2/write) or an InfluxDB UDP listener.
+// https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/
+
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use crate::events::ProbeEvent;
+
+// Datagrams stay below a typical MTU so they are never fragmented
+const MAX_DATAGRAM_BYTES: usize = 1400;
+
+// Longest wait between retries while the server keeps failing
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+enum Transport {
+    Http {
+        client: reqwest::Client,
+        // Write endpoint including its query, e.g. .../api/v2/write?org=net&bucket=probes
+        url: String,
+        token: Option<String>,
+    },
+    Udp(UdpSocket),
+}
+
+pub struct Influx {
+    transport: Transport,
+    measurement: String,
+    batch_size: usize,
+    flush_interval: Duration,
+    // Lines not written yet
+    pending: Vec<String>,
+    // After a failed write: how long to wait before the next attempt, and
+    // when that is. Retries only happen on the flush tick.
+    retry_delay: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Influx {
+    /// INFLUX_URL (http(s):// write URL or udp://host:port), INFLUX_TOKEN,
+    /// INFLUX_MEASUREMENT (default probe), INFLUX_BATCH_SIZE (default 500) and
+    /// INFLUX_FLUSH_INTERVAL_SECONDS (default 5); None without INFLUX_URL
+    pub async fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = std::env::var("INFLUX_URL") else {
+            return Ok(None);
+        };
+        let transport = if let Some(addr) = url.strip_prefix("udp://") {
+            let bind = if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+            let socket = UdpSocket::bind(bind).await?;
+            socket.connect(addr).await.with_context(|| format!("INFLUX_URL {}", url))?;
+            Transport::Udp(socket)
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Transport::Http {
+                client: reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?,
+                url,
+                token: std::env::var("INFLUX_TOKEN").ok(),
+            }
+        } else {
+            anyhow::bail!("INFLUX_URL {} must start with http://, https:// or udp://", url);
+        };
+        let batch_size: usize = super::env_number("INFLUX_BATCH_SIZE", 500)?;
+        let flush_interval_sec: u64 = super::env_number("INFLUX_FLUSH_INTERVAL_SECONDS", 5)?;
+        Ok(Some(Self {
+            transport,
+            measurement: std::env::var("INFLUX_MEASUREMENT").unwrap_or_else(|_| "probe".to_string()),
+            batch_size: batch_size.max(1),
+            flush_interval: Duration::from_secs(flush_interval_sec.max(1)),
+            pending: Vec::new(),
+            retry_delay: Duration::ZERO,
+            retry_at: None,
+        }))
+    }
+
+    pub async fn run(mut self, mut rx: broadcast::Receiver<ProbeEvent>) {
+        let mut flush = tokio::time::interval(self.flush_interval);
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => {
+                        if let Some(line) = self.line(&event) {
+                            self.pending.push(line);
+                        }
+                        // A full batch is written early, unless writes are failing
+                        if self.pending.len() >= self.batch_size && self.retry_at.is_none() {
+                            self.flush().await;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        crate::metrics::events_dropped("influx", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = flush.tick() => {
+                    if self.retry_at.is_none_or(|at| Instant::now() >= at) {
+                        self.flush().await;
+                    }
+                }
+            }
+        }
+        self.flush().await;
+    }
+
+    fn line(&self, event: &ProbeEvent) -> Option<String> {
+        // The events carry monotonic timestamps; line protocol needs wall-clock time
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let measurement = escape_measurement(&self.measurement);
+        match event {
+            ProbeEvent::Completed { target, address, probe_type, latency, .. } => Some(format!(
+                "{},target={}{},probe_type={} latency_ms={},success=true {}",
+                measurement, escape_tag(target), address_tag(*address), escape_tag(probe_type), latency.as_secs_f64() * 1000.0, now,
+            )),
+            ProbeEvent::Failed { target, address, probe_type, reason, error, .. } => Some(format!(
+                "{},target={}{},probe_type={},reason={} success=false,error=\"{}\" {}",
+                measurement, escape_tag(target), address_tag(*address), escape_tag(probe_type), escape_tag(reason), escape_field(error), now,
+            )),
+            _ => None,
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let result = match &self.transport {
+            Transport::Http { client, url, token } => {
+                let mut request = client.post(url).body(self.pending.join("\n"));
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Token {}", token));
+                }
+                match request.send().await {
+                    Ok(response) => response.error_for_status().map(|_| ()).map_err(anyhow::Error::from),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Transport::Udp(socket) => send_datagrams(socket, &self.pending).await,
+        };
+        match result {
+            Ok(()) => {
+                self.pending.clear();
+                self.retry_delay = Duration::ZERO;
+                self.retry_at = None;
+            }
+            Err(e) => {
+                self.retry_delay = (self.retry_delay * 2).clamp(self.flush_interval, MAX_RETRY_DELAY);
+                self.retry_at = Some(Instant::now() + self.retry_delay);
+                tracing::warn!(
+                    "Cannot write {} lines to InfluxDB, retrying in {}s: {:#}",
+                    self.pending.len(),
+                    self.retry_delay.as_secs(),
+                    e
+                );
+                super::drop_oldest(&mut self.pending);
+            }
+        }
+    }
+}
+
+/// Pack whole lines into datagrams of at most MAX_DATAGRAM_BYTES
+async fn send_datagrams(socket: &UdpSocket, lines: &[String]) -> Result<()> {
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_BYTES {
+            socket.send(datagram.as_bytes()).await?;
+            datagram.clear();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    socket.send(datagram.as_bytes()).await?;
+    Ok(())
+}
+
+fn escape_measurement(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+fn escape_tag(value: &str) -> String {
+    value.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// `,address=...` for a probe_all_addresses target, nothing otherwise
+fn address_tag(address: Option<IpAddr>) -> String {
+    address.map(|address| format!(",address={}", escape_tag(&address.to_string()))).unwrap_or_default()
+}
+
+fn escape_field(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', " ")
+}
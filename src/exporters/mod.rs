@@ -0,0 +1,171 @@
+// Push outputs for environments that can't scrape /metrics. Each exporter is
+// enabled by its own environment variables; all but remote write and the
+// Pushgateway are event bus consumers.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::events::{self, ProbeEvent};
+
+pub mod cloudwatch;
+pub mod datadog;
+pub mod graphite;
+pub mod influx;
+pub mod pushgateway;
+pub mod remote_write;
+pub mod statsd;
+
+// Points, lines or series an exporter keeps while its destination is
+// unreachable; the oldest are dropped first
+const MAX_BUFFERED: usize = 100_000;
+
+/// Drop the oldest entries of `pending` beyond MAX_BUFFERED
+fn drop_oldest<T>(pending: &mut Vec<T>) {
+    if pending.len() > MAX_BUFFERED {
+        let excess = pending.len() - MAX_BUFFERED;
+        pending.drain(..excess);
+    }
+}
+
+/// Results of one target (and address, with probe_all_addresses) and probe
+/// type since the last flush
+#[derive(Default)]
+struct Summary {
+    latency_ms_sum: f64,
+    latency_ms_min: f64,
+    latency_ms_max: f64,
+    successes: u64,
+    failures: u64,
+}
+
+impl Summary {
+    fn mean_latency_ms(&self) -> Option<f64> {
+        (self.successes > 0).then(|| self.latency_ms_sum / self.successes as f64)
+    }
+}
+
+type Summaries = BTreeMap<(String, Option<IpAddr>, &'static str), Summary>;
+
+/// Add a probe result to its target's summary
+fn summarise(summaries: &mut Summaries, event: ProbeEvent) {
+    match event {
+        ProbeEvent::Completed { target, address, probe_type, latency, .. } => {
+            let latency_ms = latency.as_secs_f64() * 1000.0;
+            let summary = summaries.entry((target, address, probe_type)).or_default();
+            if summary.successes == 0 || latency_ms < summary.latency_ms_min {
+                summary.latency_ms_min = latency_ms;
+            }
+            summary.latency_ms_max = summary.latency_ms_max.max(latency_ms);
+            summary.latency_ms_sum += latency_ms;
+            summary.successes += 1;
+        }
+        ProbeEvent::Failed { target, address, probe_type, .. } => {
+            summaries.entry((target, address, probe_type)).or_default().failures += 1;
+        }
+        _ => {}
+    }
+}
+
+/// An exporter that summarises results per target and sends the summaries
+/// every flush interval, for backends that keep one value per series and
+/// timestamp or bill per data point
+trait SummaryExporter: Send {
+    // Used in log messages
+    const NAME: &'static str;
+
+    fn flush_interval(&self) -> Duration;
+
+    fn summaries(&mut self) -> &mut Summaries;
+
+    /// Take a probe result; summarised by default
+    fn record(&mut self, event: ProbeEvent) {
+        summarise(self.summaries(), event);
+    }
+
+    /// Send what was summarised since the last flush
+    fn flush(&mut self) -> impl Future<Output = ()> + Send;
+}
+
+/// Consume probe events into `exporter` and flush it every interval, and
+/// once more when the event bus closes
+async fn run_summarised<E: SummaryExporter>(mut exporter: E, mut rx: broadcast::Receiver<ProbeEvent>) {
+    let mut flush = tokio::time::interval(exporter.flush_interval());
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => exporter.record(event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    crate::metrics::events_dropped(E::NAME, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = flush.tick() => exporter.flush().await,
+        }
+    }
+    exporter.flush().await;
+}
+
+/// Start every exporter that is configured; a misconfigured one is an error
+/// rather than silently missing data
+pub async fn spawn_configured() -> Result<()> {
+    if let Some(statsd) = statsd::Statsd::from_env().await? {
+        tokio::spawn(statsd.run(events::subscribe()));
+    }
+    if let Some(influx) = influx::Influx::from_env().await? {
+        tokio::spawn(influx.run(events::subscribe()));
+    }
+    if let Some(graphite) = graphite::Graphite::from_env()? {
+        tokio::spawn(graphite.run(events::subscribe()));
+    }
+    if let Some(cloudwatch) = cloudwatch::CloudWatch::from_env().await? {
+        tokio::spawn(cloudwatch.run(events::subscribe()));
+    }
+    if let Some(datadog) = datadog::Datadog::from_env()? {
+        tokio::spawn(datadog.run(events::subscribe()));
+    }
+    if let Some(remote_write) = remote_write::RemoteWrite::from_env()? {
+        tokio::spawn(remote_write.run());
+    }
+    if let Some(pushgateway) = pushgateway::Pushgateway::from_env()? {
+        tokio::spawn(pushgateway.run());
+    }
+    Ok(())
+}
+
+/// Final pushes before the process exits
+pub async fn shutdown() {
+    pushgateway::push_at_shutdown().await;
+}
+
+/// The number in environment variable `name`, or `default` when it is unset
+fn env_number<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => value.trim().parse().with_context(|| format!("{} {:?} is not a valid number", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// `HOSTNAME`/`COMPUTERNAME`, or what the OS reports
+fn hostname() -> String {
+    if let Ok(name) = std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME")) {
+        return name;
+    }
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its length and gethostname NUL-terminates within it on success
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..end]).into_owned();
+        }
+    }
+    "latency-probe".to_string()
+}
@@ -0,0 +1,125 @@
+// Prometheus Pushgateway: the whole registry pushed as one group on an
+// interval and once more at shutdown, for short-lived runs (batch jobs, CI)
+// that are gone before anything could scrape them.
+// https://github.com/prometheus/pushgateway#api
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE as BASE64_URL;
+use once_cell::sync::OnceCell;
+use prometheus::{Encoder, TextEncoder};
+use std::time::Duration;
+
+// Kept for the final push from `push_at_shutdown`
+static AT_SHUTDOWN: OnceCell<Pushgateway> = OnceCell::new();
+
+#[derive(Clone)]
+pub struct Pushgateway {
+    client: reqwest::Client,
+    // <PUSHGATEWAY_URL>/metrics/job/<job>/<label>/<value>...
+    url: String,
+    basic_auth: Option<(String, String)>,
+    // None pushes only at shutdown
+    interval: Option<Duration>,
+}
+
+impl Pushgateway {
+    /// PUSHGATEWAY_URL, PUSHGATEWAY_JOB (default latency-probe),
+    /// PUSHGATEWAY_INSTANCE (default hostname), PUSHGATEWAY_GROUPING,
+    /// PUSHGATEWAY_USERNAME/PASSWORD and PUSHGATEWAY_INTERVAL_SECONDS (default
+    /// 15, 0 for shutdown only); None without PUSHGATEWAY_URL
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(base) = std::env::var("PUSHGATEWAY_URL") else {
+            return Ok(None);
+        };
+        let interval_sec: u64 = super::env_number("PUSHGATEWAY_INTERVAL_SECONDS", 15)?;
+        let basic_auth = match (std::env::var("PUSHGATEWAY_USERNAME"), std::env::var("PUSHGATEWAY_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            (Ok(_), Err(_)) => anyhow::bail!("PUSHGATEWAY_USERNAME is set without PUSHGATEWAY_PASSWORD"),
+            _ => None,
+        };
+
+        let job = std::env::var("PUSHGATEWAY_JOB").unwrap_or_else(|_| "latency-probe".to_string());
+        let mut grouping = vec![("instance".to_string(), std::env::var("PUSHGATEWAY_INSTANCE").unwrap_or_else(|_| super::hostname()))];
+        // e.g. PUSHGATEWAY_GROUPING=pipeline=nightly,stage=smoke
+        for pair in std::env::var("PUSHGATEWAY_GROUPING").unwrap_or_default().split(',').filter(|p| !p.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .with_context(|| format!("PUSHGATEWAY_GROUPING entry {:?} is not name=value", pair))?;
+            grouping.retain(|(n, _)| n != name.trim());
+            grouping.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        let mut url = format!("{}/metrics/{}", base.trim_end_matches('/'), path_segment("job", &job));
+        for (name, value) in &grouping {
+            url.push('/');
+            url.push_str(&path_segment(name, value));
+        }
+
+        Ok(Some(Self {
+            client: reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?,
+            url,
+            basic_auth,
+            interval: (interval_sec > 0).then(|| Duration::from_secs(interval_sec)),
+        }))
+    }
+
+    pub async fn run(self) {
+        let _ = AT_SHUTDOWN.set(self.clone());
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.push().await {
+                tracing::warn!("Push to {} failed: {:#}", self.url, e);
+            }
+        }
+    }
+
+    /// Replace the group with the current registry (PUT, so series that are
+    /// gone from the registry are gone from the group too)
+    async fn push(&self) -> Result<()> {
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        encoder.encode(&crate::metrics::gather(), &mut body)?;
+        let mut request = self.client
+            .put(&self.url)
+            .header("Content-Type", encoder.format_type())
+            .body(body);
+        if let Some((username, password)) = &self.basic_auth {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            anyhow::bail!("{}: {}", status, text.trim());
+        }
+        Ok(())
+    }
+}
+
+/// Push the final values, if a Pushgateway is configured
+pub async fn push_at_shutdown() {
+    let Some(pushgateway) = AT_SHUTDOWN.get() else {
+        return;
+    };
+    match pushgateway.push().await {
+        Ok(()) => tracing::info!("Pushed final metrics to {}", pushgateway.url),
+        Err(e) => tracing::warn!("Final push to {} failed: {:#}", pushgateway.url, e),
+    }
+}
+
+/// `name/value`, with the value base64-encoded when it is empty or has
+/// characters that don't survive in a URL path
+fn path_segment(name: &str, value: &str) -> String {
+    let plain = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if plain {
+        format!("{}/{}", name, value)
+    } else {
+        // An empty value is written as a lone padding character
+        let encoded = if value.is_empty() { "=".to_string() } else { BASE64_URL.encode(value) };
+        format!("{}@base64/{}", name, encoded)
+    }
+}
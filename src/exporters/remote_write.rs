@@ -0,0 +1,218 @@
+// Prometheus remote write: every registered metric pushed to a receiver
+// (Prometheus, Mimir, Thanos, VictoriaMetrics, ...) at a fixed interval, for
+// probe hosts that can't be scraped. Unlike the other exporters it reads the
+// metrics registry rather than the event bus, so the series are the same as
+// on /metrics.
+// https://prometheus.io/docs/specs/prw/remote_write_spec/
+
+use anyhow::{Context, Result};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::metrics::Exemplar;
+
+pub struct RemoteWrite {
+    client: reqwest::Client,
+    url: String,
+    // Bearer token, or the file that holds it (re-read every push)
+    token: Option<String>,
+    token_file: Option<String>,
+    basic_auth: Option<(String, String)>,
+    interval: Duration,
+    // Added to every series, like the job and instance labels of a scrape
+    extra_labels: Vec<(String, String)>,
+}
+
+impl RemoteWrite {
+    /// REMOTE_WRITE_URL, with REMOTE_WRITE_BEARER_TOKEN(_FILE) or
+    /// REMOTE_WRITE_USERNAME/PASSWORD, pushed every REMOTE_WRITE_INTERVAL_SECONDS
+    /// (default 15); None without REMOTE_WRITE_URL
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = std::env::var("REMOTE_WRITE_URL") else {
+            return Ok(None);
+        };
+        let interval_sec: u64 = super::env_number("REMOTE_WRITE_INTERVAL_SECONDS", 15)?;
+        let basic_auth = match (std::env::var("REMOTE_WRITE_USERNAME"), std::env::var("REMOTE_WRITE_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            (Ok(_), Err(_)) => anyhow::bail!("REMOTE_WRITE_USERNAME is set without REMOTE_WRITE_PASSWORD"),
+            _ => None,
+        };
+
+        let mut extra_labels = vec![
+            ("job".to_string(), std::env::var("REMOTE_WRITE_JOB").unwrap_or_else(|_| "latency-probe".to_string())),
+            ("instance".to_string(), std::env::var("REMOTE_WRITE_INSTANCE").unwrap_or_else(|_| super::hostname())),
+        ];
+        // e.g. REMOTE_WRITE_LABELS=region=eu-west-1,site=fra1
+        for pair in std::env::var("REMOTE_WRITE_LABELS").unwrap_or_default().split(',').filter(|p| !p.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .with_context(|| format!("REMOTE_WRITE_LABELS entry {:?} is not name=value", pair))?;
+            extra_labels.retain(|(n, _)| n != name.trim());
+            extra_labels.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        Ok(Some(Self {
+            client: reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?,
+            url,
+            token: std::env::var("REMOTE_WRITE_BEARER_TOKEN").ok(),
+            token_file: std::env::var("REMOTE_WRITE_BEARER_TOKEN_FILE").ok(),
+            basic_auth,
+            interval: Duration::from_secs(interval_sec.max(1)),
+            extra_labels,
+        }))
+    }
+
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            ticker.tick().await;
+            // Samples are snapshots: a failed push is not retried, the next
+            // one carries fresh values
+            if let Err(e) = self.push().await {
+                tracing::warn!("Remote write to {} failed: {:#}", self.url, e);
+            }
+        }
+    }
+
+    async fn push(&self) -> Result<()> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        let body = write_request(&crate::metrics::gather(), &self.extra_labels, now_ms);
+        let compressed = snap::raw::Encoder::new().compress_vec(&body)?;
+
+        let mut request = self.client
+            .post(&self.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(compressed);
+        let token = match &self.token_file {
+            Some(file) => Some(tokio::fs::read_to_string(file).await?.trim().to_string()),
+            None => self.token.clone(),
+        };
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        } else if let Some((username, password)) = &self.basic_auth {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            anyhow::bail!("{}: {}", status, text.trim());
+        }
+        Ok(())
+    }
+}
+
+/// Encode the families as a remote write `WriteRequest` protobuf, one
+/// TimeSeries per sample; histograms and summaries are split into their
+/// `_bucket`, `_sum` and `_count` (or quantile) series as a scrape would, and
+/// buckets carry their exemplar if they have one
+fn write_request(families: &[MetricFamily], extra_labels: &[(String, String)], timestamp_ms: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    for family in families {
+        let name = family.name();
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, &str)> = metric.get_label().iter().map(|l| (l.name(), l.value())).collect();
+            let mut series = |suffix: &str, extra: Option<(&str, String)>, value: f64, exemplar: Option<Exemplar>| {
+                let metric_name = format!("{}{}", name, suffix);
+                let mut all: Vec<(&str, &str)> = vec![("__name__", &metric_name)];
+                all.extend(labels.iter().copied());
+                if let Some((label, value)) = &extra {
+                    all.push((label, value));
+                }
+                // The spec forbids empty label values; as in Prometheus, an
+                // empty label is the same as none
+                all.retain(|(_, value)| !value.is_empty());
+                for (label, value) in extra_labels {
+                    if !value.is_empty() && !all.iter().any(|(n, _)| n == label) {
+                        all.push((label, value));
+                    }
+                }
+                // The spec requires labels sorted by name
+                all.sort_by(|a, b| a.0.cmp(b.0));
+                encode_message(&mut out, 1, &time_series(&all, value, timestamp_ms, exemplar.as_ref()));
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => series("", None, metric.get_counter().value(), None),
+                MetricType::GAUGE => series("", None, metric.get_gauge().value(), None),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let exemplar = |bucket| crate::metrics::exemplar(name, metric.get_label(), bucket);
+                    let buckets = histogram.get_bucket();
+                    for (i, bucket) in buckets.iter().enumerate() {
+                        series("_bucket", Some(("le", format_bound(bucket.upper_bound()))), bucket.cumulative_count() as f64, exemplar(i));
+                    }
+                    series("_bucket", Some(("le", "+Inf".to_string())), histogram.sample_count() as f64, exemplar(buckets.len()));
+                    series("_sum", None, histogram.sample_sum(), None);
+                    series("_count", None, histogram.sample_count() as f64, None);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        series("", Some(("quantile", format_bound(quantile.quantile()))), quantile.value(), None);
+                    }
+                    series("_sum", None, summary.sample_sum(), None);
+                    series("_count", None, summary.sample_count() as f64, None);
+                }
+                MetricType::UNTYPED => {}
+            }
+        }
+    }
+    out
+}
+
+fn format_bound(value: f64) -> String {
+    if value.is_infinite() { "+Inf".to_string() } else { value.to_string() }
+}
+
+/// TimeSeries { repeated Label labels = 1; repeated Sample samples = 2;
+/// repeated Exemplar exemplars = 3; }
+fn time_series(labels: &[(&str, &str)], value: f64, timestamp_ms: i64, exemplar: Option<&Exemplar>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in labels {
+        encode_message(&mut out, 1, &label(name, value));
+    }
+    // Sample { double value = 1; int64 timestamp = 2; }
+    let mut sample = Vec::new();
+    sample.push((1 << 3) | 1);
+    sample.extend_from_slice(&value.to_le_bytes());
+    sample.push(2 << 3);
+    encode_varint(&mut sample, timestamp_ms as u64);
+    encode_message(&mut out, 2, &sample);
+    if let Some(exemplar) = exemplar {
+        // Exemplar { repeated Label labels = 1; double value = 2; int64 timestamp = 3; }
+        let mut encoded = Vec::new();
+        for (name, value) in &exemplar.labels {
+            encode_message(&mut encoded, 1, &label(name, value));
+        }
+        encoded.push((2 << 3) | 1);
+        encoded.extend_from_slice(&exemplar.value.to_le_bytes());
+        encoded.push(3 << 3);
+        encode_varint(&mut encoded, exemplar.timestamp_ms as u64);
+        encode_message(&mut out, 3, &encoded);
+    }
+    out
+}
+
+/// Label { string name = 1; string value = 2; }
+fn label(name: &str, value: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_message(&mut out, 1, name.as_bytes());
+    encode_message(&mut out, 2, value.as_bytes());
+    out
+}
+
+/// A length-delimited field: strings, bytes and embedded messages
+fn encode_message(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    encode_varint(out, ((field << 3) | 2) as u64);
+    encode_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn encode_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
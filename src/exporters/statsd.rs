@@ -0,0 +1,132 @@
+// StatsD output: a timing per successful probe and a counter per outcome,
+// sent over UDP as they happen. Tags are written in the dialect the
+// receiving agent understands.
+
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use crate::events::ProbeEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TagFormat {
+    // metric:1|c|#target:a,probe_type:icmp
+    Dogstatsd,
+    // metric,target=a,probe_type=icmp:1|c (Telegraf)
+    Influx,
+    // metric;target=a;probe_type=icmp:1|c
+    Graphite,
+    // prefix.a.icmp.metric:1|c, for servers without tag support
+    None,
+}
+
+impl TagFormat {
+    fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "dogstatsd" | "datadog" => Ok(Self::Dogstatsd),
+            "influx" | "telegraf" => Ok(Self::Influx),
+            "graphite" => Ok(Self::Graphite),
+            "none" => Ok(Self::None),
+            other => anyhow::bail!("STATSD_TAG_FORMAT {:?} must be dogstatsd, influx, graphite or none", other),
+        }
+    }
+}
+
+pub struct Statsd {
+    socket: UdpSocket,
+    // Prepended to every metric name, e.g. "latency_probe"
+    prefix: String,
+    format: TagFormat,
+}
+
+impl Statsd {
+    /// STATSD_ADDR (host:port), STATSD_PREFIX (default latency_probe) and
+    /// STATSD_TAG_FORMAT (default dogstatsd); None without STATSD_ADDR
+    pub async fn from_env() -> Result<Option<Self>> {
+        let Ok(addr) = std::env::var("STATSD_ADDR") else {
+            return Ok(None);
+        };
+        let format = TagFormat::parse(&std::env::var("STATSD_TAG_FORMAT").unwrap_or_else(|_| "dogstatsd".to_string()))?;
+        let prefix = std::env::var("STATSD_PREFIX").unwrap_or_else(|_| "latency_probe".to_string());
+        let bind = if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(&addr).await.with_context(|| format!("STATSD_ADDR {}", addr))?;
+        Ok(Some(Self { socket, prefix, format }))
+    }
+
+    pub async fn run(self, mut rx: broadcast::Receiver<ProbeEvent>) {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    crate::metrics::events_dropped("statsd", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let lines = match &event {
+                ProbeEvent::Completed { target, address, probe_type, latency, .. } => {
+                    let labels = self.target_labels(target, *address);
+                    let tags: Vec<(&str, &str)> = [("target", target.as_str()), ("probe_type", *probe_type)]
+                        .into_iter()
+                        .chain(labels.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+                        .collect();
+                    vec![
+                        self.line("latency", &format!("{:.3}", latency.as_secs_f64() * 1000.0), "ms", &tags),
+                        self.line("success", "1", "c", &tags),
+                    ]
+                }
+                ProbeEvent::Failed { target, address, probe_type, reason, .. } => {
+                    let labels = self.target_labels(target, *address);
+                    let tags: Vec<(&str, &str)> = [("target", target.as_str()), ("probe_type", *probe_type), ("reason", *reason)]
+                        .into_iter()
+                        .chain(labels.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+                        .collect();
+                    vec![self.line("failure", "1", "c", &tags)]
+                }
+                _ => continue,
+            };
+            // One datagram per probe; statsd servers split on newlines
+            if let Err(e) = self.socket.send(lines.join("\n").as_bytes()).await {
+                tracing::debug!("Cannot send to statsd: {}", e);
+            }
+        }
+    }
+
+    /// The target's labels as extra tags; without tag support they would
+    /// add levels to the metric name, so only the address is kept
+    fn target_labels(&self, target: &str, address: Option<IpAddr>) -> Vec<(String, String)> {
+        if self.format == TagFormat::None {
+            return address.map(|address| ("address".to_string(), address.to_string())).into_iter().collect();
+        }
+        crate::metrics::target_labels(target, address)
+    }
+
+    fn line(&self, metric: &str, value: &str, kind: &str, tags: &[(&str, &str)]) -> String {
+        let name = format!("{}.{}", self.prefix, metric);
+        match self.format {
+            TagFormat::Dogstatsd => {
+                let tags: Vec<String> = tags.iter().map(|(k, v)| format!("{}:{}", k, sanitize(v))).collect();
+                format!("{}:{}|{}|#{}", name, value, kind, tags.join(","))
+            }
+            TagFormat::Influx => {
+                let tags: String = tags.iter().map(|(k, v)| format!(",{}={}", k, sanitize(v))).collect();
+                format!("{}{}:{}|{}", name, tags, value, kind)
+            }
+            TagFormat::Graphite => {
+                let tags: String = tags.iter().map(|(k, v)| format!(";{}={}", k, sanitize(v))).collect();
+                format!("{}{}:{}|{}", name, tags, value, kind)
+            }
+            TagFormat::None => {
+                // Dots would add levels to the name, so they are replaced too
+                let path: String = tags.iter().map(|(_, v)| format!(".{}", sanitize(v).replace('.', "_"))).collect();
+                format!("{}{}.{}:{}|{}", self.prefix, path, metric, value, kind)
+            }
+        }
+    }
+}
+
+/// Replace the characters that separate fields in any of the dialects
+fn sanitize(value: &str) -> String {
+    value.replace([':', '|', ',', '#', '=', ';', ' ', '@', '\n'], "_")
+}
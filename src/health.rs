@@ -0,0 +1,52 @@
+// Liveness and readiness for /healthz and /readyz. Ready means the probe is
+// producing data: a config is loaded, the scheduler keeps ticking and at
+// least one cycle of probes has finished.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A scheduler that missed this many ticks in a row counts as stalled
+const STALLED_TICKS: u32 = 3;
+
+// Lower bound of the stall threshold, so short intervals don't flap
+const MIN_STALL_THRESHOLD: Duration = Duration::from_secs(10);
+
+// (time of the last tick, probe interval)
+static LAST_TICK: Lazy<Mutex<Option<(Instant, Duration)>>> = Lazy::new(|| Mutex::new(None));
+
+static CYCLE_COMPLETED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub config_loaded: bool,
+    pub scheduler_running: bool,
+    pub probe_cycle_completed: bool,
+}
+
+/// Called by the scheduler on every tick
+pub fn scheduler_ticked(interval: Duration) {
+    *LAST_TICK.lock().unwrap() = Some((Instant::now(), interval));
+}
+
+/// Called when every probe started by a tick has finished, whatever the results
+pub fn cycle_completed() {
+    CYCLE_COMPLETED.store(true, Ordering::Relaxed);
+}
+
+pub fn readiness() -> Readiness {
+    let config_loaded = crate::config::reload_status().last_success > 0;
+    let scheduler_running = LAST_TICK.lock().unwrap().is_some_and(|(at, interval)| {
+        at.elapsed() <= (interval * STALLED_TICKS).max(MIN_STALL_THRESHOLD)
+    });
+    let probe_cycle_completed = CYCLE_COMPLETED.load(Ordering::Relaxed);
+    Readiness {
+        ready: config_loaded && scheduler_running && probe_cycle_completed,
+        config_loaded,
+        scheduler_running,
+        probe_cycle_completed,
+    }
+}
@@ -0,0 +1,73 @@
+// Recent latency samples per target, fed from the event bus. Used by jobs
+// that need more than the last value (baselines, rollups, status).
+
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use crate::events::ProbeEvent;
+
+// Per (target, address, probe_type); ~68 minutes of samples at a 1s interval
+const HISTORY_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub timestamp_ns: u128,
+    // None for a failed probe
+    pub latency_ms: Option<f64>,
+}
+
+pub type HistoryKey = (String, Option<IpAddr>, &'static str);
+
+static HISTORY: Lazy<Mutex<HashMap<HistoryKey, VecDeque<Sample>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn record(key: HistoryKey, sample: Sample) {
+    let mut history = HISTORY.lock().unwrap();
+    let samples = history.entry(key).or_default();
+    if samples.len() == HISTORY_CAPACITY {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Copy of all samples currently held, oldest first
+pub fn snapshot() -> HashMap<HistoryKey, Vec<Sample>> {
+    HISTORY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.iter().copied().collect()))
+        .collect()
+}
+
+/// Value at quantile `q` (0.0..=1.0) of an ascending slice
+pub fn percentile(sorted: &[f64], q: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let idx = ((sorted.len() - 1) as f64 * q).round() as usize;
+    Some(sorted[idx.min(sorted.len() - 1)])
+}
+
+pub async fn consume_events(mut rx: broadcast::Receiver<ProbeEvent>) {
+    loop {
+        match rx.recv().await {
+            Ok(ProbeEvent::Completed { target, address, probe_type, latency, timestamp_ns, .. }) => {
+                record((target, address, probe_type), Sample {
+                    timestamp_ns,
+                    latency_ms: Some(latency.as_secs_f64() * 1000.0),
+                });
+            }
+            Ok(ProbeEvent::Failed { target, address, probe_type, timestamp_ns, .. }) => {
+                record((target, address, probe_type), Sample { timestamp_ns, latency_ms: None });
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                crate::metrics::events_dropped("history", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
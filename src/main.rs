@@ -1,35 +1,125 @@
+mod alerting;
+mod availability;
+mod baseline;
+mod build_info;
+mod cli;
+mod concurrency;
 mod config;
+mod config_source;
+mod events;
+mod exporters;
+mod health;
+mod history;
 mod scheduler;
+mod secrets;
 mod prober;
+mod rollup;
+mod runtime_collector;
 mod metrics;
+mod openmetrics;
+mod server;
+mod service;
+mod status;
+mod summary;
 mod timestamp;
 mod util;
+mod web_config;
 
-use config::ConfigManager;
+use clap::Parser;
+use cli::{Cli, Command, RunArgs};
+use config::{ConfigManager, ProbeConfig, TargetConfig};
 use scheduler::Scheduler;
-use metrics::{observe_latency, inc_timeout, initialize_metrics};
-use prober::ProbeKind;
+use metrics::initialize_metrics;
+use service::PidFile;
 
+use std::future::Future;
 use std::sync::Arc;
-use tracing::{info, error};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::Instrument;
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Completions { shell } => {
+            cli::print_completions(shell);
+            return Ok(());
+        }
+        Command::Man => return cli::print_man(),
+        Command::Server(args) => return build_runtime()?.block_on(server::run(args, service::shutdown_signal())),
+        Command::Check => return check_config(&cli.run.config),
+        Command::Run => {}
+    }
+
+    let args = cli.run;
+    let service_opts = args.service.clone();
+
+    // Daemonize before the runtime spawns its worker threads
+    #[cfg(unix)]
+    if service_opts.daemonize {
+        service::daemonize(service_opts.log_file.as_deref())?;
+    }
+    // After forking, so it holds the daemon's pid; a Windows service keeps
+    // it until the service stops
+    let _pid_file = service_opts.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    #[cfg(windows)]
+    if service_opts.windows_service {
+        return service::windows::run(args);
+    }
+
+    build_runtime()?.block_on(run(args, service::shutdown_signal()))
+}
+
+/// Print what is wrong with the config file; exits non-zero if anything is
+fn check_config(config_file: &str) -> anyhow::Result<()> {
+    match ConfigManager::check_file(config_file) {
+        Ok(config) => {
+            println!("{}: OK ({} targets)", config_file, config.targets.len());
+            Ok(())
+        }
+        Err(problems) => {
+            for problem in &problems {
+                eprintln!("{}: {}", config_file, problem);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+pub(crate) fn build_runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+}
+
+/// Run the probe until `shutdown` resolves
+pub(crate) async fn run(args: RunArgs, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
     // Load config first to get log level
-    let config_mgr = Arc::new(ConfigManager::start().await?);
-    let log_level = config_mgr.config.read().await.get_tracing_level()?;
+    let config_mgr = Arc::new(ConfigManager::start(&args.config).await?);
+    let log_level = match args.log_level {
+        Some(level) => level,
+        None => config_mgr.config.read().await.get_tracing_level()?,
+    };
 
-    println!("Starting latency-probe");
+    println!("Starting latency-probe {}", build_info::LONG_VERSION);
 
     // Initialize metrics based on configuration
-    let enable_latency_history = config_mgr.config.read().await.enable_latency_history;
-    initialize_metrics(enable_latency_history);
+    let (enable_latency_history, latency_summary) = {
+        let config = config_mgr.config.read().await;
+        (config.enable_latency_history, config.latency_summary.clone())
+    };
+    initialize_metrics(enable_latency_history, latency_summary.as_ref(), &config_mgr.targets.read().await);
     
     if enable_latency_history {
         println!("Latency history tracking enabled");
     } else {
         println!("Latency history tracking disabled - showing current latency only");
     }
+    if let Some(summary) = &latency_summary {
+        println!("Latency summary enabled with quantiles {:?} over {}s", summary.quantiles, summary.max_age_sec);
+    }
     
     // Init tracing with configured log level
     tracing_subscriber::fmt()
@@ -37,86 +127,115 @@ async fn main() -> anyhow::Result<()> {
                          .add_directive(format!("latency-probe={}", log_level.as_str().to_lowercase()).parse()?))
         .init();
 
+    // Subscribe consumers before the first probe runs so no event is missed
+    tokio::spawn(metrics::consume_events(events::subscribe()));
+    tokio::spawn(alerting::run(config_mgr.config.clone(), events::subscribe()));
+    tokio::spawn(history::consume_events(events::subscribe()));
+    tokio::spawn(status::consume_events(events::subscribe()));
+    tokio::spawn(baseline::run(config_mgr.config.clone()));
+    tokio::spawn(availability::run(config_mgr.config.clone(), events::subscribe()));
+    tokio::spawn(rollup::run(config_mgr.config.clone()));
+    exporters::spawn_configured().await?;
+
+    // Keep target info metrics, the latency histogram, the probe limit,
+    // /status and the probers' per-target state in sync with the active config
+    // and with the addresses probe_all_addresses targets resolve to
+    {
+        let config = config_mgr.config.clone();
+        let targets = config_mgr.targets.clone();
+        let mut reloads = config_mgr.subscribe_reloads();
+        let mut resolutions = prober::subscribe_resolutions();
+        tokio::spawn(async move {
+            loop {
+                {
+                    let active = targets.read().await;
+                    metrics::set_target_info(&active);
+                    status::retain_targets(&active);
+                    prober::retain_targets(&active);
+                }
+                {
+                    let config = config.read().await;
+                    metrics::set_latency_history(config.enable_latency_history);
+                    concurrency::set_limit(config.max_concurrent_probes);
+                }
+                tokio::select! {
+                    changed = reloads.changed() => if changed.is_err() {
+                        break;
+                    },
+                    _ = resolutions.changed() => {}
+                }
+            }
+        });
+    }
+
     // Start metrics endpoint
-    let metrics_addr = ([0, 0, 0, 0], 9100).into();
-    tokio::spawn(metrics::serve_metrics(metrics_addr));
+    let web_config = web_config::WebConfig::load(args.web_config_file.as_deref())?;
+    let tls = web_config.tls_acceptor()?;
+    tokio::spawn(metrics::serve_metrics(args.metrics_addr, web_config, tls));
 
     // Scheduler: using interval poll from config or default
-    let probe_interval_ms = config_mgr.config.read().await.probe_interval_ms;
+    let probe_interval_ms = match args.interval {
+        Some(interval) => interval,
+        None => config_mgr.config.read().await.probe_interval_ms,
+    };
     let scheduler = Scheduler::new(probe_interval_ms)?;
+    // Before the first round rather than whenever the reload task gets to it
+    concurrency::set_limit(config_mgr.config.read().await.max_concurrent_probes);
+    let config = config_mgr.config.clone();
+    let probe_loop = scheduler.run(config_mgr.targets.clone(), config_mgr.config.clone(), move |t, interval| {
+        probe_target(t, interval, config.clone())
+    });
 
-    // Targets list
-    let targets = config_mgr.targets.clone();
-
-    scheduler.run(move || {
-        let targets = targets.clone();
-        let config_mgr = config_mgr.clone(); // Clone config_mgr so it can be moved into the closure
-        async move {
-            let targets_snapshot = { targets.read().await.clone() };
-            for t in targets_snapshot.into_iter() {
-                let t2 = t.clone();
-                let config_mgr = config_mgr.clone(); // Clone again for each spawned task
-                tokio::spawn(async move {
-                    match t2.kind {
-                        ProbeKind::Icmp => {
-                            // Get timeout from config or use default
-                            let config = config_mgr.config.read().await;
-                            let timeout_ms = config.default_timeout_ms;
-                            drop(config);
-                            
-                            match prober::icmp::probe_icmp(&t2.host, timeout_ms).await {
-                                Ok(latency) => {
-                                    info!("icmp probe {} success: {:?}", t2.host, latency);
-                                    observe_latency(&t2.name, "icmp", latency.as_secs_f64() * 1000.0);
-                                }
-                                Err(e) => {
-                                    error!("icmp probe {} failed: {:?}", t2.host, e);
-                                    inc_timeout(&t2.name, "icmp");
-                                }
-                            }
-                        }
-                        ProbeKind::TcpConnect => {
-                            match prober::tcp_connect::probe_tcp(&t2.host, t2.port.unwrap_or(80)).await {
-                                Ok(latency) => {
-                                    info!("tcp connect {} success: {:?}", t2.host, latency);
-                                    observe_latency(&t2.name, "tcp_connect", latency.as_secs_f64() * 1000.0);
-                                }
-                                Err(e) => {
-                                    error!("tcp connect {} failed: {:?}", t2.host, e);
-                                    inc_timeout(&t2.name, "tcp_connect");
-                                }
-                            }
-                        }
-                        ProbeKind::Http => {
-                            let url = t2.get_http_url();
-                            match prober::http::probe_http(&url).await {
-                                Ok(latency) => {
-                                    info!("http probe {} success: {:?}", url, latency);
-                                    observe_latency(&t2.name, "http", latency.as_secs_f64() * 1000.0);
-                                }
-                                Err(e) => {
-                                    error!("http probe {} failed: {:?}", url, e);
-                                    inc_timeout(&t2.name, "http");
-                                }
-                            }
-                        }
-                        ProbeKind::Echo => {
-                            match prober::echo::probe_echo(&t2.host, t2.port.unwrap_or(9000)).await {
-                                Ok(latency) => {
-                                    info!("echo probe {} success: {:?}", t2.host, latency);
-                                    observe_latency(&t2.name, "echo", latency.as_secs_f64() * 1000.0);
-                                }
-                                Err(e) => {
-                                    error!("echo probe {} failed: {:?}", t2.host, e);
-                                    inc_timeout(&t2.name, "echo");
-                                }
-                            }
-                        }
-                    }
-                });
-            }
+    tokio::select! {
+        result = probe_loop => result,
+        _ = shutdown => {
+            println!("Shutting down latency-probe");
+            exporters::shutdown().await;
+            Ok(())
         }
-    }).await?;
+    }
+}
+
+/// One round of a target: a probe, or one per address with probe_all_addresses.
+/// Probes still waiting for a slot when the next round is due are dropped.
+/// Whether any probe succeeded, or None when all were dropped.
+async fn probe_target(t: TargetConfig, interval: Duration, config: Arc<RwLock<ProbeConfig>>) -> Option<bool> {
+    // Get timeout from the target or use the default
+    let default_timeout_ms = config.read().await.default_timeout_ms;
+    let timeout_ms = t.timeout_ms.unwrap_or(default_timeout_ms);
+    let probe_type = t.probe_type();
 
-    Ok(())
+    // One deadline for the whole round, however many addresses wait in turn
+    let deadline = tokio::time::Instant::now() + interval;
+    // Usually just `t`
+    let mut runs = Vec::new();
+    let (targets, lookup) = prober::expand_addresses(&t).await;
+    for t in targets {
+        // Taken before spawning the probe's task, so a queued probe holds no
+        // more than its round's task
+        let Some(slot) = concurrency::admit(probe_type, deadline).await else {
+            continue;
+        };
+        runs.push(tokio::spawn(async move {
+            let _slot = slot;
+            let _run = metrics::ProbeRun::start(probe_type);
+            let probe_id = events::next_probe_id();
+            events::publish_started(&t.name, t.address, probe_type, probe_id);
+            // Log lines of the run carry its id, as do latency exemplars;
+            // the span is at error level so that every level shows it
+            let (result, stats) = prober::collect_stats(lookup, prober::run_probe(&t, timeout_ms))
+                .instrument(tracing::error_span!("probe", id = probe_id))
+                .await;
+            events::publish_result(&t.name, t.address, probe_type, probe_id, stats, &result);
+            result.is_ok()
+        }));
+    }
+    if runs.is_empty() {
+        return None;
+    }
+    let mut succeeded = false;
+    for run in runs {
+        succeeded |= run.await.unwrap_or(false);
+    }
+    Some(succeeded)
 }
@@ -1,62 +1,727 @@
-use prometheus::{Encoder, TextEncoder, HistogramVec, IntCounterVec, GaugeVec, Opts, Registry};
+use prometheus::{Encoder, TextEncoder, HistogramVec, IntCounterVec, GaugeVec, Gauge, IntCounter, Opts, Registry};
+use prometheus::core::{Collector, MetricVec, MetricVecBuilder};
 use warp::Filter;
-use std::net::SocketAddr;
-use once_cell::sync::Lazy;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use crate::config::{LatencySummaryConfig, ReloadStatus, TargetConfig};
+use crate::events::ProbeEvent;
+use crate::prober::{EchoDetails, HttpDetails, ProbeStats, TcpDetails};
+use crate::runtime_collector::RuntimeCollector;
+use crate::summary::SummaryVec;
+use crate::web_config::WebConfig;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio_rustls::TlsAcceptor;
 
-static REGISTRY: Lazy<Registry> = Lazy::new(|| Registry::new());
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
 
-// Optional histogram for latency history - only registered if enabled
-static LATENCY_HIST: Lazy<Option<HistogramVec>> = Lazy::new(|| None);
+// address is empty (i.e. absent) unless the target has probe_all_addresses;
+// http_version for everything but HTTP probes
+const LATENCY_LABELS: &[&str] = &["target", "probe_type", "address", "http_version"];
+
+// Names of the targets' `labels` added to latency and failure series. Fixed
+// when metrics are initialized, since a metric's label names can't change.
+static TARGET_LABEL_NAMES: OnceCell<Vec<String>> = OnceCell::new();
+// Values of those labels per target name, refreshed on every config change
+static TARGET_LABEL_VALUES: Lazy<RwLock<HashMap<String, Vec<String>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Labels set by the probe itself, which target labels may not reuse
+const RESERVED_LABELS: &[&str] = &["target", "probe_type", "address", "http_version", "reason", "window", "ip", "family", "le", "quantile"];
+
+/// Whether `name` can be used as a target label
+pub fn check_label_name(name: &str) -> Result<(), String> {
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid || name.starts_with("__") {
+        return Err(format!("{:?} is not a valid Prometheus label name", name));
+    }
+    if RESERVED_LABELS.contains(&name) {
+        return Err(format!("{:?} is set by the probe and can't be a target label", name));
+    }
+    Ok(())
+}
+
+/// `base` followed by the target label names
+fn with_target_label_names(base: &[&'static str]) -> Vec<&'static str> {
+    let names = TARGET_LABEL_NAMES.get_or_init(Vec::new);
+    base.iter().copied().chain(names.iter().map(String::as_str)).collect()
+}
+
+/// `values` followed by the target's label values
+fn with_target_labels(values: &[&str], target: &str) -> Vec<String> {
+    let labels = TARGET_LABEL_VALUES.read().unwrap();
+    let own = labels.get(target);
+    let count = TARGET_LABEL_NAMES.get().map_or(0, Vec::len);
+    values.iter()
+        .map(|v| v.to_string())
+        .chain((0..count).map(|i| own.map(|own| own[i].clone()).unwrap_or_default()))
+        .collect()
+}
+
+/// Value of the address label: the address of a probe_all_addresses copy of
+/// the target, empty otherwise
+pub fn address_label(address: Option<IpAddr>) -> String {
+    address.map(|address| address.to_string()).unwrap_or_default()
+}
+
+/// The target's labels that are exported on its series, and the address of
+/// a probe_all_addresses copy, for exporters that tag their own output; empty
+/// values are left out
+pub fn target_labels(target: &str, address: Option<IpAddr>) -> Vec<(String, String)> {
+    let names = TARGET_LABEL_NAMES.get_or_init(Vec::new);
+    std::iter::once(("address".to_string(), address_label(address)))
+        .chain(names.iter().cloned().zip(with_target_labels(&[], target)))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+// Removes the series of a per-target metric that `stale` matches, given
+// their labels by name
+type SeriesPruner = Box<dyn Fn(&dyn Fn(&HashMap<&str, &str>) -> bool) + Send + Sync>;
+// One pruner per metric with a target label, added as each is created
+static TARGET_SERIES: Lazy<Mutex<Vec<SeriesPruner>>> = Lazy::new(|| Mutex::new(Vec::new()));
+// The configured targets, to tell which series and events are still theirs
+static ACTIVE_TARGETS: Lazy<RwLock<Vec<TargetConfig>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Register a metric with a target label and prune it with the others when
+/// targets change
+fn register_target_vec<T: MetricVecBuilder + 'static>(vec: MetricVec<T>) -> MetricVec<T> {
+    REGISTRY.register(Box::new(vec.clone())).unwrap();
+    track_target_vec(vec.clone());
+    vec
+}
+
+/// Prune `vec` with the other per-target metrics, without registering it
+fn track_target_vec<T: MetricVecBuilder + 'static>(vec: MetricVec<T>) {
+    TARGET_SERIES.lock().unwrap().push(Box::new(move |stale| remove_series(&vec, stale)));
+}
 
 static LATENCY_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
     let opts = Opts::new("probe_latency_milliseconds_current", "Current probe latency in milliseconds");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(LATENCY_LABELS)).unwrap();
+    register_target_vec(gauge)
+});
+
+// The timeout slice of probe_failure_total, kept for existing dashboards and alerts
+static TIMEOUT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_timeout_total", "Total number of probe timeouts");
+    let ctr = IntCounterVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(ctr)
+});
+
+static SUCCESS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_success_total", "Total number of successful probes");
+    let ctr = IntCounterVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(ctr)
+});
+
+static FAILURE_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_failure_total", "Total number of failed probes by reason (timeout, dns, refused, tls, ...)");
+    let ctr = IntCounterVec::new(opts, &with_target_label_names(&["target", "probe_type", "address", "reason"])).unwrap();
+    register_target_vec(ctr)
+});
+
+static LAST_SUCCESS_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_last_success_timestamp_seconds", "Unix time of the last successful probe");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(gauge)
+});
+
+static DNS_RESOLUTION_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_dns_resolution_milliseconds", "Time the last probe spent resolving the target's host name");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(gauge)
+});
+
+static BYTES_SENT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_sent_bytes_total", "Bytes of the messages probes built and wrote, without the headers the network stack or TLS adds");
+    let counter = IntCounterVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(counter)
+});
+
+static BYTES_RECEIVED_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_received_bytes_total", "Bytes of the messages probes read and parsed, without the headers the network stack or TLS adds");
+    let counter = IntCounterVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(counter)
+});
+
+static BACKOFF_INTERVAL_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_backoff_interval_seconds", "Time between probes of targets with a backoff; above their interval while backing off");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(&["target", "probe_type"])).unwrap();
+    register_target_vec(gauge)
+});
+
+static CONSECUTIVE_FAILURES_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_consecutive_failures", "Probes failed in a row since the last success");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(&["target", "probe_type", "address"])).unwrap();
+    register_target_vec(gauge)
+});
+
+static SUCCESS_RATIO_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_success_ratio", "Share of successful probes over the rolling window");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(&["target", "probe_type", "address", "window"])).unwrap();
+    register_target_vec(gauge)
+});
+
+// Latency rollups over each configured window, computed by rollup.rs
+static LATENCY_MIN_GAUGE: Lazy<GaugeVec> = Lazy::new(|| rollup_gauge("probe_latency_min_milliseconds", "Lowest probe latency in milliseconds over the window"));
+static LATENCY_MAX_GAUGE: Lazy<GaugeVec> = Lazy::new(|| rollup_gauge("probe_latency_max_milliseconds", "Highest probe latency in milliseconds over the window"));
+static LATENCY_AVG_GAUGE: Lazy<GaugeVec> = Lazy::new(|| rollup_gauge("probe_latency_avg_milliseconds", "Mean probe latency in milliseconds over the window"));
+
+fn rollup_gauge(name: &str, help: &str) -> GaugeVec {
+    let gauge = GaugeVec::new(Opts::new(name, help), &with_target_label_names(&["target", "probe_type", "address", "window"])).unwrap();
+    register_target_vec(gauge)
+}
+
+static TARGET_IP_INFO: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_target_info", "Address the target's host last resolved to, always 1");
+    let gauge = GaugeVec::new(opts, &with_target_label_names(&["target", "ip", "family"])).unwrap();
+    register_target_vec(gauge)
+});
+
+static TARGET_IP_CHANGES_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_target_ip_changes_total", "Times the target's host resolved to different addresses than before");
+    let ctr = IntCounterVec::new(opts, &with_target_label_names(&["target"])).unwrap();
+    register_target_vec(ctr)
+});
+
+// Sorted addresses behind each target's probe_target_info series
+static TARGET_ADDRESSES: Lazy<Mutex<HashMap<String, Vec<IpAddr>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static SCHEDULER_TICK_DRIFT: Lazy<Gauge> = Lazy::new(|| {
+    let gauge = Gauge::new("latency_probe_scheduler_tick_drift_seconds", "How late the last scheduled probe started").unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static SCHEDULER_TICKS_SKIPPED: Lazy<IntCounter> = Lazy::new(|| {
+    let ctr = IntCounter::new("latency_probe_scheduler_ticks_skipped_total", "Probe rounds skipped because a target's loop fell a whole interval behind").unwrap();
+    REGISTRY.register(Box::new(ctr.clone())).unwrap();
+    ctr
+});
+
+static PROBES_IN_FLIGHT: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_probes_in_flight", "Probes currently running");
+    let gauge = GaugeVec::new(opts, &["probe_type"]).unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static PROBES_QUEUED: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_probes_queued", "Probes waiting for a slot under max_concurrent_probes");
+    let gauge = GaugeVec::new(opts, &["probe_type"]).unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static PROBES_SHED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_probes_shed_total", "Probes dropped because no slot under max_concurrent_probes freed up within the target's interval");
+    let ctr = IntCounterVec::new(opts, &["probe_type"]).unwrap();
+    REGISTRY.register(Box::new(ctr.clone())).unwrap();
+    ctr
+});
+
+static EVENTS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_events_dropped_total", "Probe events an event bus consumer missed because it fell behind");
+    let ctr = IntCounterVec::new(opts, &["consumer"]).unwrap();
+    REGISTRY.register(Box::new(ctr.clone())).unwrap();
+    ctr
+});
+
+static ROUNDS_STARTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_rounds_started_total", "Probe rounds started, before per-address expansion");
+    let ctr = IntCounterVec::new(opts, &["probe_type"]).unwrap();
+    REGISTRY.register(Box::new(ctr.clone())).unwrap();
+    ctr
+});
+
+static ROUNDS_BACKED_OFF: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_rounds_backed_off_total", "Rounds of failing targets not probed because of their backoff");
+    let ctr = IntCounterVec::new(opts, &["probe_type"]).unwrap();
+    REGISTRY.register(Box::new(ctr.clone())).unwrap();
+    ctr
+});
+
+static PROBE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = prometheus::HistogramOpts::new(
+        "latency_probe_probe_duration_seconds",
+        "Wall time of probe runs including resolution and scheduling, unlike the measured latency",
+    );
+    let hist = HistogramVec::new(opts, &["probe_type"]).unwrap();
+    REGISTRY.register(Box::new(hist.clone())).unwrap();
+    hist
+});
+
+static BUILD_INFO: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("latency_probe_build_info", "Version, commit and compiler the probe was built with, always 1");
+    let gauge = GaugeVec::new(opts, &["version", "commit", "rustc"]).unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static TARGET_OWNER_INFO: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_target_owner_info", "Target ownership metadata, always 1");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "owner", "team", "runbook_url"]).unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static TARGET_ENABLED_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_target_enabled", "1 if the target is probed, 0 if it is disabled in the config");
     let gauge = GaugeVec::new(opts, &["target", "probe_type"]).unwrap();
     REGISTRY.register(Box::new(gauge.clone())).unwrap();
     gauge
 });
 
-static TIMEOUT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
-    let opts = Opts::new("probe_timeout_total", "Total number of probe timeouts");
-    let ctr = IntCounterVec::new(opts, &["target", "probe_type"]).unwrap();
+static HTTP_UNEXPECTED_STATUS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_http_unexpected_status_total", "Total number of HTTP probes failed by an unexpected status code");
+    let ctr = IntCounterVec::new(opts, &["target", "probe_type", "address", "status_code"]).unwrap();
+    register_target_vec(ctr)
+});
+
+static HTTP_REDIRECTS_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_http_redirects", "Number of redirects followed by the last HTTP probe");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static HTTP_PHASE_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_http_phase_milliseconds", "Time spent in each phase of the last HTTP request in milliseconds");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address", "phase"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static HTTP_RESPONSE_BYTES_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_http_response_bytes", "Body size of the last HTTP response in bytes");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static HTTP_THROUGHPUT_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_http_throughput_bytes_per_second", "Body transfer rate of the last HTTP response");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static HTTP_COMPRESSED_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_http_response_compressed", "Whether the last HTTP response was compressed (1) or not (0)");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static TCP_KERNEL_RTT_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_tcp_kernel_rtt_milliseconds", "Kernel smoothed RTT (TCP_INFO) of the last TCP probe connection in milliseconds");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static TCP_KERNEL_RTTVAR_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_tcp_kernel_rttvar_milliseconds", "Kernel RTT variance (TCP_INFO) of the last TCP probe connection in milliseconds");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static TCP_FASTOPEN_ACCEPTED_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_tcp_fastopen_accepted", "Whether the server accepted the data sent on the SYN by the last TCP Fast Open probe (1) or not (0)");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static TCP_FASTOPEN_SAVED_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_tcp_fastopen_saved_milliseconds", "Time TCP Fast Open saved over a regular connection in the last probe in milliseconds (negative if slower)");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static ICMP_UNEXPECTED_REPLIES_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_icmp_unexpected_replies_total", "Total number of ICMP echo replies that arrived after the probe timed out (late) or more than once (duplicate)");
+    let ctr = IntCounterVec::new(opts, &["target", "probe_type", "address", "kind"]).unwrap();
+    register_target_vec(ctr)
+});
+
+static ECHO_ANOMALIES_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_echo_anomalies_total", "Total number of echo datagrams that got no reply in time (lost), whose reply came after the timeout (late), more than once (duplicate) or after a later one's (reordered)");
+    let ctr = IntCounterVec::new(opts, &["target", "probe_type", "address", "kind"]).unwrap();
+    register_target_vec(ctr)
+});
+
+static ECHO_ONE_WAY_DELAY_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_echo_one_way_delay_milliseconds", "One-way delay of the last echo probe answered by latency-probe server, towards the responder (forward) or back (reverse), in milliseconds; needs synchronised clocks");
+    let gauge = GaugeVec::new(opts, &["target", "probe_type", "address", "direction"]).unwrap();
+    register_target_vec(gauge)
+});
+
+static APPCONFIG_FETCH_LATENCY: Lazy<Gauge> = Lazy::new(|| {
+    let gauge = Gauge::new("appconfig_fetch_latency_milliseconds", "Latency of the last AWS AppConfig fetch in milliseconds").unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static APPCONFIG_FETCH_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    let ctr = IntCounter::new("appconfig_fetch_errors_total", "Total number of failed AWS AppConfig fetches").unwrap();
     REGISTRY.register(Box::new(ctr.clone())).unwrap();
     ctr
 });
 
-// Track whether histogram is enabled
-static mut HISTOGRAM_ENABLED: bool = false;
-static HISTOGRAM_INSTANCE: Lazy<Arc<std::sync::Mutex<Option<HistogramVec>>>> = 
-    Lazy::new(|| Arc::new(std::sync::Mutex::new(None)));
+static CONFIG_INFO: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_config_info", "Always 1; labels identify the active config by source and hash");
+    let gauge = GaugeVec::new(opts, &["source", "hash"]).unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static CONFIG_RELOAD_SUCCESSFUL: Lazy<Gauge> = Lazy::new(|| {
+    let gauge = Gauge::new("probe_config_last_reload_successful", "Whether the last attempt to load the config succeeded (1) or failed (0)").unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static CONFIG_RELOAD_SUCCESS_TIME: Lazy<Gauge> = Lazy::new(|| {
+    let gauge = Gauge::new("probe_config_last_reload_success_timestamp_seconds", "Unix time the config was last loaded successfully").unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+static CONFIG_RELOAD_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    let ctr = IntCounter::new("probe_config_reload_errors_total", "Total number of config loads that failed").unwrap();
+    REGISTRY.register(Box::new(ctr.clone())).unwrap();
+    ctr
+});
+
+static CONFIG_TARGETS_GAUGE: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("probe_config_targets", "Number of targets in the active config by kind");
+    let gauge = GaugeVec::new(opts, &["kind"]).unwrap();
+    REGISTRY.register(Box::new(gauge.clone())).unwrap();
+    gauge
+});
+
+// Upper bounds of the probe_latency_milliseconds buckets
+const LATENCY_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.2, 0.5, 1.0,
+    2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 250.0, 500.0, 1000.0
+];
+
+/// An observation kept with the histogram bucket it fell into, labelled with
+/// the probe run it came from
+#[derive(Debug, Clone)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp_ms: i64,
+}
+
+// A series' label pairs sorted by name, as in the gathered metrics
+type SeriesKey = Vec<(String, String)>;
+
+// Latest exemplar per latency histogram bucket, the last one being +Inf
+static LATENCY_EXEMPLARS: Lazy<Mutex<HashMap<SeriesKey, Vec<Option<Exemplar>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// probe_latency_milliseconds, created at startup and registered only while
+// enable_latency_history is on, so a reload can toggle it
+static LATENCY_HISTOGRAM: OnceCell<HistogramVec> = OnceCell::new();
+// Read on every observation, without a lock
+static HISTOGRAM_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Set when the config has a latency_summary block
+static SUMMARY_INSTANCE: OnceCell<SummaryVec> = OnceCell::new();
+
+// Series of one (target, address, probe_type) resolved once, so observing a
+// result neither builds label values nor looks the series up in the metric vecs
+struct SeriesHandles {
+    // target, probe_type, address and the target's labels
+    labels: Vec<String>,
+    // Created by the first success, so a target that never succeeded has no
+    // last success timestamp of 0
+    success: OnceCell<(IntCounter, Gauge)>,
+    consecutive_failures: Gauge,
+    // Per reason; the timeout ones are counted once more without it
+    failures: RwLock<HashMap<&'static str, IntCounter>>,
+    timeouts: OnceCell<IntCounter>,
+    // Created by the first probe that looked up a host name
+    dns: OnceCell<Gauge>,
+    bytes_sent: IntCounter,
+    bytes_received: IntCounter,
+    // Per http_version, which only HTTP probes vary
+    latency: RwLock<HashMap<String, Arc<LatencyHandles>>>,
+    // Details of the probe kinds, created by the first probe that has them
+    http: OnceCell<HttpHandles>,
+    http_unexpected_status: RwLock<HashMap<u16, IntCounter>>,
+    tcp: OnceCell<(Gauge, Gauge)>,
+    tcp_fast_open: OnceCell<(Gauge, Gauge)>,
+    echo: OnceCell<(Gauge, Gauge)>,
+}
+
+struct HttpHandles {
+    redirects: Gauge,
+    response_bytes: Gauge,
+    compressed: Gauge,
+    throughput: OnceCell<Gauge>,
+    // In the order of HTTP_PHASES
+    phases: Vec<Gauge>,
+}
+
+const HTTP_PHASES: [&str; 5] = ["dns", "tcp_connect", "tls_handshake", "ttfb", "transfer"];
+
+struct LatencyHandles {
+    // target, probe_type, address, http_version and the target's labels
+    labels: Vec<String>,
+    gauge: Gauge,
+    // None while enable_latency_history is off
+    histogram: Option<prometheus::Histogram>,
+}
+
+// (target, address) -> probe_type -> handles. Cleared when label values, the
+// targets or the histogram change, since the handles would then point at
+// stale series.
+type HandleCache = HashMap<(String, String), HashMap<String, Arc<SeriesHandles>>>;
+static SERIES_HANDLES: Lazy<RwLock<HandleCache>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn series_handles(target: &str, address: &str, probe_type: &str) -> Arc<SeriesHandles> {
+    let key = (target.to_string(), address.to_string());
+    if let Some(handles) = SERIES_HANDLES.read().unwrap().get(&key).and_then(|by_type| by_type.get(probe_type)) {
+        return handles.clone();
+    }
+    let labels = with_target_labels(&[target, probe_type, address], target);
+    let handles = Arc::new(SeriesHandles {
+        consecutive_failures: CONSECUTIVE_FAILURES_GAUGE.with_label_values(&labels),
+        bytes_sent: BYTES_SENT_COUNTER.with_label_values(&labels),
+        bytes_received: BYTES_RECEIVED_COUNTER.with_label_values(&labels),
+        labels,
+        success: OnceCell::new(),
+        failures: RwLock::new(HashMap::new()),
+        timeouts: OnceCell::new(),
+        dns: OnceCell::new(),
+        latency: RwLock::new(HashMap::new()),
+        http: OnceCell::new(),
+        http_unexpected_status: RwLock::new(HashMap::new()),
+        tcp: OnceCell::new(),
+        tcp_fast_open: OnceCell::new(),
+        echo: OnceCell::new(),
+    });
+    SERIES_HANDLES
+        .write()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .entry(probe_type.to_string())
+        .or_insert(handles)
+        .clone()
+}
+
+impl SeriesHandles {
+    fn latency(&self, target: &str, address: &str, probe_type: &str, http_version: &str) -> Arc<LatencyHandles> {
+        if let Some(handles) = self.latency.read().unwrap().get(http_version) {
+            return handles.clone();
+        }
+        let labels = with_target_labels(&[target, probe_type, address, http_version], target);
+        let histogram = LATENCY_HISTOGRAM
+            .get()
+            .filter(|_| HISTOGRAM_ENABLED.load(Ordering::Relaxed))
+            .map(|hist| hist.with_label_values(&labels));
+        let handles = Arc::new(LatencyHandles {
+            gauge: LATENCY_GAUGE.with_label_values(&labels),
+            histogram,
+            labels,
+        });
+        self.latency.write().unwrap().entry(http_version.to_string()).or_insert(handles).clone()
+    }
 
-pub fn initialize_metrics(enable_latency_history: bool) {
-    unsafe {
-        HISTOGRAM_ENABLED = enable_latency_history;
+    /// target, probe_type and address, for the metrics without target labels
+    fn base_labels(&self) -> &[String] {
+        &self.labels[..3]
+    }
+
+    fn failure(&self, reason: &'static str) -> IntCounter {
+        cached_counter(&self.failures, reason, || {
+            let mut labels = self.labels.clone();
+            labels.insert(3, reason.to_string());
+            FAILURE_COUNTER.with_label_values(&labels)
+        })
+    }
+
+    fn http_unexpected_status(&self, status_code: u16) -> IntCounter {
+        cached_counter(&self.http_unexpected_status, status_code, || {
+            let mut labels = self.base_labels().to_vec();
+            labels.push(status_code.to_string());
+            HTTP_UNEXPECTED_STATUS_COUNTER.with_label_values(&labels)
+        })
+    }
+
+    fn http(&self) -> &HttpHandles {
+        self.http.get_or_init(|| {
+            let labels = self.base_labels();
+            HttpHandles {
+                redirects: HTTP_REDIRECTS_GAUGE.with_label_values(labels),
+                response_bytes: HTTP_RESPONSE_BYTES_GAUGE.with_label_values(labels),
+                compressed: HTTP_COMPRESSED_GAUGE.with_label_values(labels),
+                throughput: OnceCell::new(),
+                phases: HTTP_PHASES
+                    .iter()
+                    .map(|phase| {
+                        let mut labels = labels.to_vec();
+                        labels.push(phase.to_string());
+                        HTTP_PHASE_GAUGE.with_label_values(&labels)
+                    })
+                    .collect(),
+            }
+        })
+    }
+}
+
+/// The counter cached under `key`, created by `create` the first time
+fn cached_counter<K: Eq + std::hash::Hash + Copy>(
+    cache: &RwLock<HashMap<K, IntCounter>>,
+    key: K,
+    create: impl FnOnce() -> IntCounter,
+) -> IntCounter {
+    if let Some(counter) = cache.read().unwrap().get(&key) {
+        return counter.clone();
+    }
+    cache.write().unwrap().entry(key).or_insert_with(create).clone()
+}
+
+fn clear_series_handles() {
+    SERIES_HANDLES.write().unwrap().clear();
+}
+
+pub fn initialize_metrics(enable_latency_history: bool, latency_summary: Option<&LatencySummaryConfig>, targets: &[TargetConfig]) {
+    let mut label_names = BTreeSet::new();
+    for name in targets.iter().flat_map(|t| t.labels.keys()) {
+        match check_label_name(name) {
+            Ok(()) => {
+                label_names.insert(name.clone());
+            }
+            Err(e) => tracing::warn!("Ignoring target label: {}", e),
+        }
+    }
+    let _ = TARGET_LABEL_NAMES.set(label_names.into_iter().collect());
+    *ACTIVE_TARGETS.write().unwrap() = targets.to_vec();
+
+    let build = crate::build_info::build_info();
+    BUILD_INFO.with_label_values(&[build.version, build.commit, build.rustc]).set(1.0);
+
+    // CPU, memory, open fds and threads of the probe itself (process_*)
+    #[cfg(target_os = "linux")]
+    REGISTRY.register(Box::new(prometheus::process_collector::ProcessCollector::for_self())).unwrap();
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        REGISTRY.register(Box::new(RuntimeCollector::new(handle).expect("creating runtime collector"))).unwrap();
     }
     
-    if enable_latency_history {
-        let opts = Opts::new("probe_latency_milliseconds", "Probe latency in milliseconds");
-        let hist = HistogramVec::new(
-            prometheus::HistogramOpts {
-                common_opts: opts,
-                buckets: vec![
-                    0.05, 0.1, 0.2, 0.5, 1.0,
-                    2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 250.0, 500.0, 1000.0
-                ],
-            },
-            &["target", "probe_type"],
-        ).expect("creating histogram");
-        
+    let opts = Opts::new("probe_latency_milliseconds", "Probe latency in milliseconds");
+    let hist = HistogramVec::new(
+        prometheus::HistogramOpts {
+            common_opts: opts,
+            buckets: LATENCY_BUCKETS.to_vec(),
+        },
+        &with_target_label_names(LATENCY_LABELS),
+    ).expect("creating histogram");
+    track_target_vec(hist.clone());
+    let _ = LATENCY_HISTOGRAM.set(hist);
+    set_latency_history(enable_latency_history);
+
+    if let Some(config) = latency_summary {
+        let summary = SummaryVec::new(
+            "probe_latency_summary_milliseconds",
+            "Probe latency quantiles in milliseconds over a sliding window",
+            &with_target_label_names(LATENCY_LABELS),
+            &config.quantiles,
+            Duration::from_secs(config.max_age_sec.max(1)),
+        ).expect("creating summary");
+        REGISTRY.register(Box::new(summary.clone())).unwrap();
+        let _ = SUMMARY_INSTANCE.set(summary);
+    }
+}
+
+/// Start or stop exporting probe_latency_milliseconds, e.g. when a reload
+/// changes `enable_latency_history`. Re-enabling starts from empty buckets.
+pub fn set_latency_history(enabled: bool) {
+    let Some(hist) = LATENCY_HISTOGRAM.get() else {
+        return;
+    };
+    if HISTOGRAM_ENABLED.swap(enabled, Ordering::Relaxed) == enabled {
+        return;
+    }
+    if enabled {
+        hist.reset();
         REGISTRY.register(Box::new(hist.clone())).unwrap();
-        
-        let mut guard = HISTOGRAM_INSTANCE.lock().unwrap();
-        *guard = Some(hist);
+    } else {
+        let _ = REGISTRY.unregister(Box::new(hist.clone()));
+        hist.reset();
+        LATENCY_EXEMPLARS.lock().unwrap().clear();
     }
+    clear_series_handles();
 }
 
-pub async fn serve_metrics(addr: SocketAddr) {
-    let metrics_route = warp::path!("metrics").map(move || {
-        let encoder = TextEncoder::new();
+/// The exemplar of bucket `bucket` (counting +Inf last) of a gathered
+/// histogram series, if the family keeps exemplars and the bucket has one
+pub fn exemplar(family: &str, labels: &[prometheus::proto::LabelPair], bucket: usize) -> Option<Exemplar> {
+    if family != "probe_latency_milliseconds" {
+        return None;
+    }
+    let mut key: SeriesKey = labels.iter().map(|l| (l.name().to_string(), l.value().to_string())).collect();
+    key.sort();
+    LATENCY_EXEMPLARS.lock().unwrap().get(&key)?.get(bucket)?.clone()
+}
+
+fn record_exemplar(label_values: &[String], latency_ms: f64, exemplar_labels: &[(&str, String)]) {
+    let mut key: SeriesKey = with_target_label_names(LATENCY_LABELS)
+        .into_iter()
+        .map(String::from)
+        .zip(label_values.iter().cloned())
+        .collect();
+    key.sort();
+    let bucket = LATENCY_BUCKETS.iter().position(|&bound| latency_ms <= bound).unwrap_or(LATENCY_BUCKETS.len());
+    let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let mut exemplars = LATENCY_EXEMPLARS.lock().unwrap();
+    exemplars.entry(key).or_insert_with(|| vec![None; LATENCY_BUCKETS.len() + 1])[bucket] = Some(Exemplar {
+        labels: exemplar_labels.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+        value: latency_ms,
+        timestamp_ms,
+    });
+}
+
+/// Current value of every registered metric, for exporters that push them
+pub fn gather() -> Vec<prometheus::proto::MetricFamily> {
+    REGISTRY.gather()
+}
+
+// Rejection for requests without valid credentials
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+async fn unauthorized(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_none() {
+        return Err(rejection);
+    }
+    Ok(warp::http::Response::builder()
+        .status(warp::http::StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Basic realm=\"latency-probe\"")
+        .body("Unauthorized\n")
+        .unwrap())
+}
+
+/// Serve /metrics, /config and the other endpoints, over HTTPS and behind basic auth or a bearer
+/// token when the web config asks for it
+pub async fn serve_metrics(addr: SocketAddr, web_config: WebConfig, tls: Option<TlsAcceptor>) {
+    // OpenMetrics (with exemplars) for scrapers that prefer it, the classic text format otherwise
+    let metrics_route = warp::path!("metrics").and(warp::header::optional::<String>("accept")).map(move |accept: Option<String>| {
         let mf = REGISTRY.gather();
+        if crate::openmetrics::preferred(accept.as_deref()) {
+            return warp::http::Response::builder()
+                .header("Content-Type", crate::openmetrics::CONTENT_TYPE)
+                .body(crate::openmetrics::encode(&mf).into_bytes())
+                .unwrap();
+        }
+        let encoder = TextEncoder::new();
         let mut buf = Vec::new();
         encoder.encode(&mf, &mut buf).unwrap();
         warp::http::Response::builder()
@@ -65,31 +730,506 @@ pub async fn serve_metrics(addr: SocketAddr) {
             .unwrap()
     });
 
-    warp::serve(metrics_route).run(addr).await;
+    // Which config is active and whether the last reload worked
+    let config_route = warp::path!("config").map(|| warp::reply::json(&crate::config::reload_status()));
+    let version_route = warp::path!("version").map(|| warp::reply::json(&crate::build_info::build_info()));
+    let status_route = warp::path!("status").map(|| warp::reply::json(&crate::status::snapshot()));
+
+    let auth = Arc::new(web_config.auth());
+    let authorized = warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                if auth.allows(authorization.as_deref()).await {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one();
+    // Open to kubelet probes, which send no credentials; they reveal nothing
+    let healthz_route = warp::path!("healthz").map(|| "OK".to_string());
+    let readyz_route = warp::path!("readyz").map(|| {
+        let readiness = crate::health::readiness();
+        let status = if readiness.ready {
+            warp::http::StatusCode::OK
+        } else {
+            warp::http::StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(warp::reply::json(&readiness), status)
+    });
+
+    let routes = healthz_route
+        .or(readyz_route)
+        .or(authorized.and(metrics_route.or(config_route).or(version_route).or(status_route)))
+        .recover(unauthorized);
+
+    let Some(tls) = tls else {
+        warp::serve(routes).run(addr).await;
+        return;
+    };
+    // warp has no TLS of its own; its routes are served as a hyper service
+    // on each accepted TLS stream instead
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Cannot listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::debug!("Cannot accept metrics connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let service = TowerToHyperService::new(warp::service(routes.clone()));
+        tokio::spawn(async move {
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!("TLS handshake on the metrics endpoint failed: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).await {
+                tracing::debug!("Metrics connection failed: {}", e);
+            }
+        });
+    }
 }
 
-pub fn observe_latency(target: &str, probe_type: &str, latency_ms: f64) {
+pub fn observe_latency(target: &str, address: &str, probe_type: &str, http_version: &str, latency_ms: f64, exemplar: &[(&str, String)]) {
+    let latency = series_handles(target, address, probe_type).latency(target, address, probe_type, http_version);
     // Always observe current latency in gauge
-    LATENCY_GAUGE
-        .with_label_values(&[target, probe_type])
-        .set(latency_ms);
-    
+    latency.gauge.set(latency_ms);
+
     // Conditionally observe latency history in histogram
-    unsafe {
-        if HISTOGRAM_ENABLED {
-            if let Ok(guard) = HISTOGRAM_INSTANCE.lock() {
-                if let Some(ref hist) = *guard {
-                    hist.with_label_values(&[target, probe_type])
-                        .observe(latency_ms);
-                }
+    if let Some(hist) = &latency.histogram {
+        hist.observe(latency_ms);
+        record_exemplar(&latency.labels, latency_ms, exemplar);
+    }
+
+    if let Some(summary) = SUMMARY_INSTANCE.get() {
+        summary.observe(&latency.labels, latency_ms);
+    }
+}
+
+pub fn inc_success(target: &str, address: &str, probe_type: &str) {
+    let handles = series_handles(target, address, probe_type);
+    let (success, last_success) = handles.success.get_or_init(|| {
+        (SUCCESS_COUNTER.with_label_values(&handles.labels), LAST_SUCCESS_GAUGE.with_label_values(&handles.labels))
+    });
+    success.inc();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    last_success.set(now);
+    handles.consecutive_failures.set(0.0);
+}
+
+pub fn inc_failure(target: &str, address: &str, probe_type: &str, reason: &'static str) {
+    let handles = series_handles(target, address, probe_type);
+    handles.failure(reason).inc();
+    if reason == "timeout" {
+        handles.timeouts.get_or_init(|| TIMEOUT_COUNTER.with_label_values(&handles.labels)).inc();
+    }
+    // Without the reason, so a run of mixed failures still counts up
+    handles.consecutive_failures.inc();
+}
+
+/// Record the host name lookup time and traffic of a probe run
+pub fn observe_stats(target: &str, address: &str, probe_type: &str, stats: &ProbeStats) {
+    let handles = series_handles(target, address, probe_type);
+    if let Some(dns) = stats.dns {
+        handles
+            .dns
+            .get_or_init(|| DNS_RESOLUTION_GAUGE.with_label_values(&handles.labels))
+            .set(dns.as_secs_f64() * 1000.0);
+    }
+    handles.bytes_sent.inc_by(stats.bytes_sent);
+    handles.bytes_received.inc_by(stats.bytes_received);
+    // A probe_all_addresses copy probes an address resolved beforehand
+    if address.is_empty()
+        && let Some(resolved) = stats.resolved
+    {
+        set_resolved_addresses(target, &[resolved]);
+    }
+}
+
+/// Record the addresses the target's host resolved to, moving its
+/// probe_target_info series and counting a change when they differ from
+/// the previous ones
+pub fn set_resolved_addresses(target: &str, addresses: &[IpAddr]) {
+    let mut addresses = addresses.to_vec();
+    addresses.sort();
+    let previous = TARGET_ADDRESSES.lock().unwrap().insert(target.to_string(), addresses.clone());
+    if previous.as_ref() == Some(&addresses) {
+        return;
+    }
+    let ip_labels = |ip: &IpAddr| {
+        let family = if ip.is_ipv4() { "ipv4" } else { "ipv6" };
+        with_target_labels(&[target, &ip.to_string(), family], target)
+    };
+    if let Some(previous) = previous {
+        tracing::info!("{} moved from {:?} to {:?}", target, previous, addresses);
+        for ip in previous.iter().filter(|ip| !addresses.contains(ip)) {
+            let _ = TARGET_IP_INFO.remove_label_values(&ip_labels(ip));
+        }
+        TARGET_IP_CHANGES_COUNTER.with_label_values(&with_target_labels(&[target], target)).inc();
+    }
+    for ip in &addresses {
+        TARGET_IP_INFO.with_label_values(&ip_labels(ip)).set(1.0);
+    }
+}
+
+pub fn observe_scheduler_tick(drift: Duration, skipped: u32) {
+    SCHEDULER_TICK_DRIFT.set(drift.as_secs_f64());
+    SCHEDULER_TICKS_SKIPPED.inc_by(u64::from(skipped));
+}
+
+/// Counts a probe as in flight until dropped, then records how long it ran
+pub struct ProbeRun {
+    probe_type: &'static str,
+    start: std::time::Instant,
+}
+
+impl ProbeRun {
+    pub fn start(probe_type: &'static str) -> Self {
+        PROBES_IN_FLIGHT.with_label_values(&[probe_type]).inc();
+        Self { probe_type, start: std::time::Instant::now() }
+    }
+}
+
+impl Drop for ProbeRun {
+    fn drop(&mut self) {
+        PROBES_IN_FLIGHT.with_label_values(&[self.probe_type]).dec();
+        PROBE_DURATION.with_label_values(&[self.probe_type]).observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Counts a probe as queued until dropped
+pub struct ProbeQueued {
+    probe_type: &'static str,
+}
+
+impl ProbeQueued {
+    pub fn start(probe_type: &'static str) -> Self {
+        PROBES_QUEUED.with_label_values(&[probe_type]).inc();
+        Self { probe_type }
+    }
+}
+
+impl Drop for ProbeQueued {
+    fn drop(&mut self) {
+        PROBES_QUEUED.with_label_values(&[self.probe_type]).dec();
+    }
+}
+
+pub fn probe_shed(probe_type: &str) {
+    PROBES_SHED.with_label_values(&[probe_type]).inc();
+}
+
+/// Record events a bus consumer missed, and warn about them
+pub fn events_dropped(consumer: &str, skipped: u64) {
+    tracing::warn!("{} consumer lagged, {} probe events dropped", consumer, skipped);
+    EVENTS_DROPPED.with_label_values(&[consumer]).inc_by(skipped);
+}
+
+/// Time between probes of a target with a backoff, or None to drop its
+/// series when the target has none
+pub fn set_backoff_interval(target: &str, probe_type: &str, interval: Option<Duration>) {
+    let labels = with_target_labels(&[target, probe_type], target);
+    match interval {
+        Some(_) if !is_active(target, None, probe_type) => {}
+        Some(interval) => BACKOFF_INTERVAL_GAUGE.with_label_values(&labels).set(interval.as_secs_f64()),
+        None => {
+            let _ = BACKOFF_INTERVAL_GAUGE.remove_label_values(&labels);
+        }
+    }
+}
+
+pub fn round_started(probe_type: &str) {
+    ROUNDS_STARTED.with_label_values(&[probe_type]).inc();
+}
+
+pub fn round_backed_off(probe_type: &str) {
+    ROUNDS_BACKED_OFF.with_label_values(&[probe_type]).inc();
+}
+
+/// Replace the exported success ratios with the given (target, address,
+/// probe_type, window, ratio) values
+pub fn set_success_ratios(ratios: &[(String, Option<IpAddr>, &str, String, f64)]) {
+    SUCCESS_RATIO_GAUGE.reset();
+    for (target, address, probe_type, window, ratio) in ratios {
+        SUCCESS_RATIO_GAUGE
+            .with_label_values(&with_target_labels(&[target, probe_type, &address_label(*address), window], target))
+            .set(*ratio);
+    }
+}
+
+/// Min, max and mean latency of a target's successful probes over a window
+pub struct LatencyRollup {
+    pub target: String,
+    pub address: Option<IpAddr>,
+    pub probe_type: &'static str,
+    pub window: String,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub avg_ms: f64,
+}
+
+/// Replace the exported latency rollups with the given ones
+pub fn set_latency_rollups(rollups: &[LatencyRollup]) {
+    for gauge in [&LATENCY_MIN_GAUGE, &LATENCY_MAX_GAUGE, &LATENCY_AVG_GAUGE] {
+        gauge.reset();
+    }
+    for rollup in rollups {
+        let address = address_label(rollup.address);
+        let labels = with_target_labels(&[&rollup.target, rollup.probe_type, &address, &rollup.window], &rollup.target);
+        LATENCY_MIN_GAUGE.with_label_values(&labels).set(rollup.min_ms);
+        LATENCY_MAX_GAUGE.with_label_values(&labels).set(rollup.max_ms);
+        LATENCY_AVG_GAUGE.with_label_values(&labels).set(rollup.avg_ms);
+    }
+}
+
+/// Replace the exported target info and enabled series with the given targets
+pub fn set_target_info(targets: &[TargetConfig]) {
+    TARGET_OWNER_INFO.reset();
+    TARGET_ENABLED_GAUGE.reset();
+    let names = TARGET_LABEL_NAMES.get_or_init(Vec::new);
+    let mut label_values = HashMap::new();
+    for t in targets {
+        if let Some(name) = t.labels.keys().find(|name| !names.contains(name)) {
+            tracing::warn!("Target {} has label {:?}, which is only exported after a restart", t.name, name);
+        }
+        let values: Vec<String> = names.iter().map(|name| t.labels.get(name).cloned().unwrap_or_default()).collect();
+        label_values.insert(t.name.clone(), values);
+        let enabled = t.enabled.unwrap_or(true);
+        TARGET_ENABLED_GAUGE
+            .with_label_values(&[t.name.as_str(), t.probe_type()])
+            .set(if enabled { 1.0 } else { 0.0 });
+        TARGET_OWNER_INFO
+            .with_label_values(&[
+                t.name.as_str(),
+                t.probe_type(),
+                t.owner.as_deref().unwrap_or(""),
+                t.team.as_deref().unwrap_or(""),
+                t.runbook_url.as_deref().unwrap_or(""),
+            ])
+            .set(1.0);
+    }
+    *TARGET_LABEL_VALUES.write().unwrap() = label_values;
+    *ACTIVE_TARGETS.write().unwrap() = targets.to_vec();
+    clear_series_handles();
+    remove_stale_series();
+}
+
+/// Whether events and series of `target` at `address` (None unless it has
+/// probe_all_addresses) and `probe_type` still belong to a configured target
+fn is_active(target: &str, address: Option<IpAddr>, probe_type: &str) -> bool {
+    crate::prober::is_current(&ACTIVE_TARGETS.read().unwrap(), target, address, probe_type)
+}
+
+/// Whether a series with these labels belongs to a target that is no longer
+/// configured, was configured with another probe type or other labels, or
+/// to an address the target no longer resolves to
+fn is_stale(labels: &HashMap<&str, &str>) -> bool {
+    let Some(target) = labels.get("target") else {
+        return false;
+    };
+    let address = match labels.get("address") {
+        None | Some(&"") => None,
+        Some(address) => match address.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => return true,
+        },
+    };
+    // Per-target series without a probe_type belong to the target whatever its kind
+    let active = match labels.get("probe_type") {
+        Some(probe_type) => is_active(target, address, probe_type),
+        None => ACTIVE_TARGETS.read().unwrap().iter().any(|t| t.name == *target),
+    };
+    if !active {
+        return true;
+    }
+    let names = TARGET_LABEL_NAMES.get_or_init(Vec::new);
+    let values = with_target_labels(&[], target);
+    names.iter().zip(&values).any(|(name, value)| labels.get(name.as_str()).is_some_and(|v| v != value))
+}
+
+/// Delete the stale series of every per-target metric, so the last values
+/// of removed targets and addresses don't linger (and keep alerts firing)
+/// after a reload or a change in DNS
+fn remove_stale_series() {
+    for prune in TARGET_SERIES.lock().unwrap().iter() {
+        prune(&is_stale);
+    }
+    if let Some(summary) = SUMMARY_INSTANCE.get() {
+        let names = with_target_label_names(LATENCY_LABELS);
+        summary.retain(|values| !is_stale(&names.iter().copied().zip(values.iter().map(String::as_str)).collect()));
+    }
+    LATENCY_EXEMPLARS.lock().unwrap().retain(|key, _| {
+        !is_stale(&key.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect())
+    });
+    let active = ACTIVE_TARGETS.read().unwrap();
+    TARGET_ADDRESSES.lock().unwrap().retain(|target, _| active.iter().any(|t| t.name == *target));
+}
+
+/// Delete the series of `vec` that `stale` matches
+fn remove_series<T: MetricVecBuilder>(vec: &MetricVec<T>, stale: &dyn Fn(&HashMap<&str, &str>) -> bool) {
+    for family in Collector::collect(vec) {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric.get_label().iter().map(|l| (l.name(), l.value())).collect();
+            if stale(&labels) {
+                let _ = vec.remove(&labels);
             }
         }
     }
 }
 
-pub fn inc_timeout(target: &str, probe_type: &str) {
-    TIMEOUT_COUNTER
-        .with_label_values(&[target, probe_type])
-        .inc();
+pub fn inc_http_unexpected_status(target: &str, address: &str, probe_type: &str, status_code: u16) {
+    series_handles(target, address, probe_type).http_unexpected_status(status_code).inc();
 }
 
+pub fn observe_http_details(target: &str, address: &str, probe_type: &str, details: &HttpDetails) {
+    let handles = series_handles(target, address, probe_type);
+    let http = handles.http();
+    http.redirects.set(details.redirects as f64);
+    http.response_bytes.set(details.body_bytes as f64);
+    http.compressed.set(if details.compressed { 1.0 } else { 0.0 });
+    if let Some(rate) = details.throughput() {
+        http.throughput
+            .get_or_init(|| HTTP_THROUGHPUT_GAUGE.with_label_values(handles.base_labels()))
+            .set(rate);
+    }
+
+    // Phases that did not happen (reused connection, plain HTTP) cost nothing
+    let p = &details.phases;
+    for (gauge, duration) in http.phases.iter().zip([p.dns, p.tcp_connect, p.tls_handshake, p.ttfb, p.transfer]) {
+        gauge.set(duration.map_or(0.0, |d| d.as_secs_f64() * 1000.0));
+    }
+}
+
+pub fn observe_tcp_details(target: &str, address: &str, probe_type: &str, latency: Duration, details: &TcpDetails) {
+    let handles = series_handles(target, address, probe_type);
+    if let Some(fast_open) = &details.fast_open {
+        let (accepted, saved) = handles.tcp_fast_open.get_or_init(|| {
+            let labels = handles.base_labels();
+            (TCP_FASTOPEN_ACCEPTED_GAUGE.with_label_values(labels), TCP_FASTOPEN_SAVED_GAUGE.with_label_values(labels))
+        });
+        accepted.set(if fast_open.accepted { 1.0 } else { 0.0 });
+        saved.set((fast_open.regular.as_secs_f64() - latency.as_secs_f64()) * 1000.0);
+    }
+    let (rtt, rttvar) = handles.tcp.get_or_init(|| {
+        let labels = handles.base_labels();
+        (TCP_KERNEL_RTT_GAUGE.with_label_values(labels), TCP_KERNEL_RTTVAR_GAUGE.with_label_values(labels))
+    });
+    rtt.set(details.srtt.as_secs_f64() * 1000.0);
+    rttvar.set(details.rttvar.as_secs_f64() * 1000.0);
+}
+
+/// Count an ICMP echo reply nobody was waiting for; `kind` is "late" or "duplicate"
+pub fn inc_icmp_unexpected_reply(target: &str, address: Option<IpAddr>, kind: &str) {
+    if !is_active(target, address, "icmp") {
+        return;
+    }
+    ICMP_UNEXPECTED_REPLIES_COUNTER.with_label_values(&[target, "icmp", &address_label(address), kind]).inc();
+}
+
+pub fn observe_echo_details(target: &str, address: &str, probe_type: &str, details: &EchoDetails) {
+    let handles = series_handles(target, address, probe_type);
+    let (forward, reverse) = handles.echo.get_or_init(|| {
+        let direction = |direction: &str| {
+            let mut labels = handles.base_labels().to_vec();
+            labels.push(direction.to_string());
+            ECHO_ONE_WAY_DELAY_GAUGE.with_label_values(&labels)
+        };
+        (direction("forward"), direction("reverse"))
+    });
+    forward.set(details.forward_ms);
+    reverse.set(details.reverse_ms);
+}
+
+pub fn inc_echo_anomaly(target: &str, address: Option<IpAddr>, probe_type: &str, kind: &str) {
+    if !is_active(target, address, probe_type) {
+        return;
+    }
+    ECHO_ANOMALIES_COUNTER.with_label_values(&[target, probe_type, &address_label(address), kind]).inc();
+}
+
+pub fn observe_appconfig_fetch(latency_ms: f64, success: bool) {
+    APPCONFIG_FETCH_LATENCY.set(latency_ms);
+    if success {
+        // Touch the counter so it is exported as 0 before the first error
+        Lazy::force(&APPCONFIG_FETCH_ERRORS);
+    } else {
+        APPCONFIG_FETCH_ERRORS.inc();
+    }
+}
+
+/// Export the outcome of a config load; `status` already reflects it
+pub fn observe_config_reload(status: &ReloadStatus, success: bool) {
+    if success {
+        CONFIG_INFO.reset();
+        CONFIG_INFO.with_label_values(&[&status.source, &status.hash]).set(1.0);
+        CONFIG_TARGETS_GAUGE.reset();
+        for (kind, count) in &status.targets_by_kind {
+            CONFIG_TARGETS_GAUGE.with_label_values(&[kind]).set(*count as f64);
+        }
+        CONFIG_RELOAD_SUCCESS_TIME.set(status.last_success as f64);
+        // Touch the counter so it is exported as 0 before the first error
+        Lazy::force(&CONFIG_RELOAD_ERRORS);
+    } else {
+        CONFIG_RELOAD_ERRORS.inc();
+    }
+    CONFIG_RELOAD_SUCCESSFUL.set(if success { 1.0 } else { 0.0 });
+}
+
+/// Event bus consumer that turns probe results into Prometheus metrics
+pub async fn consume_events(mut rx: broadcast::Receiver<ProbeEvent>) {
+    loop {
+        match rx.recv().await {
+            // Results of probes that were in flight when their target was
+            // removed would bring back the series the reload deleted
+            Ok(ProbeEvent::Completed { target, address, probe_type, .. } | ProbeEvent::Failed { target, address, probe_type, .. })
+                if !is_active(&target, address, probe_type) => {}
+            Ok(ProbeEvent::Completed { target, address, probe_type, probe_id, latency, details, stats, .. }) => {
+                let address = address_label(address);
+                inc_success(&target, &address, probe_type);
+                observe_stats(&target, &address, probe_type, &stats);
+                let http_version = details.http.as_ref().map_or("", |h| h.version);
+                let mut exemplar = vec![("probe_id", probe_id.to_string())];
+                if let Some(reached) = details.address {
+                    exemplar.push(("ip", reached.to_string()));
+                }
+                observe_latency(&target, &address, probe_type, http_version, latency.as_secs_f64() * 1000.0, &exemplar);
+                if let Some(http) = details.http {
+                    observe_http_details(&target, &address, probe_type, &http);
+                }
+                if let Some(tcp) = details.tcp {
+                    observe_tcp_details(&target, &address, probe_type, latency, &tcp);
+                }
+                if let Some(echo) = details.echo {
+                    observe_echo_details(&target, &address, probe_type, &echo);
+                }
+            }
+            Ok(ProbeEvent::Failed { target, address, probe_type, reason, status_code, stats, .. }) => {
+                let address = address_label(address);
+                inc_failure(&target, &address, probe_type, reason);
+                observe_stats(&target, &address, probe_type, &stats);
+                if let Some(code) = status_code {
+                    inc_http_unexpected_status(&target, &address, probe_type, code);
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                events_dropped("metrics", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// OpenMetrics text exposition, which the prometheus crate doesn't implement.
+// Served on /metrics to scrapers that ask for it; unlike the classic text
+// format it carries the latency histogram's exemplars.
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::fmt::Write;
+use crate::metrics::Exemplar;
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Whether an `Accept` header prefers OpenMetrics to the classic text format.
+/// Prometheus asks for both with OpenMetrics weighted higher.
+pub fn preferred(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let (mut openmetrics, mut text) = (0.0f64, 0.0f64);
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or("").to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.strip_prefix("q="))
+            .find_map(|q| q.parse::<f64>().ok())
+            .unwrap_or(1.0);
+        match media_type.as_str() {
+            "application/openmetrics-text" => openmetrics = openmetrics.max(q),
+            "text/plain" | "text/*" | "*/*" => text = text.max(q),
+            _ => {}
+        }
+    }
+    openmetrics > 0.0 && openmetrics >= text
+}
+
+/// Encode the families in the OpenMetrics text format, `# EOF` included
+pub fn encode(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = family.name();
+        let (type_name, family_name) = match family.get_field_type() {
+            // Counter samples end in _total; the family name doesn't
+            MetricType::COUNTER => ("counter", name.strip_suffix("_total").unwrap_or(name)),
+            MetricType::GAUGE => ("gauge", name),
+            MetricType::HISTOGRAM => ("histogram", name),
+            MetricType::SUMMARY => ("summary", name),
+            MetricType::UNTYPED => ("unknown", name),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family_name, type_name);
+        let _ = writeln!(out, "# HELP {} {}", family_name, escape(family.help()));
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    sample(&mut out, &format!("{}_total", family_name), labels, None, metric.get_counter().value(), None);
+                }
+                MetricType::GAUGE => sample(&mut out, name, labels, None, metric.get_gauge().value(), None),
+                // Nothing registers untyped metrics
+                MetricType::UNTYPED => {}
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_name = format!("{}_bucket", name);
+                    let buckets = histogram.get_bucket();
+                    for (i, bucket) in buckets.iter().enumerate() {
+                        let exemplar = crate::metrics::exemplar(name, labels, i);
+                        let le = format_value(bucket.upper_bound());
+                        sample(&mut out, &bucket_name, labels, Some(("le", &le)), bucket.cumulative_count() as f64, exemplar.as_ref());
+                    }
+                    let exemplar = crate::metrics::exemplar(name, labels, buckets.len());
+                    sample(&mut out, &bucket_name, labels, Some(("le", "+Inf")), histogram.sample_count() as f64, exemplar.as_ref());
+                    sample(&mut out, &format!("{}_sum", name), labels, None, histogram.sample_sum(), None);
+                    sample(&mut out, &format!("{}_count", name), labels, None, histogram.sample_count() as f64, None);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = format_value(quantile.quantile());
+                        sample(&mut out, name, labels, Some(("quantile", &q)), quantile.value(), None);
+                    }
+                    sample(&mut out, &format!("{}_sum", name), labels, None, summary.sample_sum(), None);
+                    sample(&mut out, &format!("{}_count", name), labels, None, summary.sample_count() as f64, None);
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn sample(out: &mut String, name: &str, labels: &[LabelPair], extra: Option<(&str, &str)>, value: f64, exemplar: Option<&Exemplar>) {
+    out.push_str(name);
+    let pairs: Vec<(&str, &str)> = labels.iter().map(|l| (l.name(), l.value())).chain(extra).collect();
+    write_labels(out, &pairs);
+    out.push(' ');
+    out.push_str(&format_value(value));
+    if let Some(exemplar) = exemplar {
+        out.push_str(" # ");
+        let pairs: Vec<(&str, &str)> = exemplar.labels.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+        // An exemplar always has braces, even without labels
+        if pairs.is_empty() {
+            out.push_str("{}");
+        } else {
+            write_labels(out, &pairs);
+        }
+        let _ = write!(out, " {} {:.3}", format_value(exemplar.value), exemplar.timestamp_ms as f64 / 1000.0);
+    }
+    out.push('\n');
+}
+
+fn write_labels(out: &mut String, pairs: &[(&str, &str)]) {
+    if pairs.is_empty() {
+        return;
+    }
+    out.push('{');
+    for (i, (name, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}=\"{}\"", name, escape(value));
+    }
+    out.push('}');
+}
+
+/// Backslash, double quote and newline escaped, as in label values and HELP
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+/// Values as OpenMetrics spells them: "+Inf", "NaN", and whole numbers with
+/// a ".0" so that `le` and `quantile` label values are canonical
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
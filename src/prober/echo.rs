@@ -1,17 +1,531 @@
 use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
 use tokio::time::{timeout, Duration, Instant};
+use crate::config::TargetConfig;
+use crate::metrics;
+use super::{count_received, count_sent, resolve_one, EchoDetails, ProbeDetails, ProbeFailure, ProbeOutcome, SocketOptions, TargetAddress};
 
-pub async fn probe_echo(host: &str, port: u16) -> Result<Duration> {
-    let addr = format!("{}:{}", host, port);
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(&addr).await?;
-    let start = Instant::now();
-    let msg = b"ping";
-    socket.send(msg).await?;
-    let mut buf = [0u8; 32];
-    let recv_fut = socket.recv(&mut buf);
-    timeout(Duration::from_millis(1000), recv_fut).await??;
-    let elapsed = start.elapsed();
-    Ok(elapsed)
+// Every echo datagram starts with this header, all big-endian:
+//   0..4   magic
+//   4..12  sequence number
+//   12..20 send time
+//   20..28 responder receive time  } zero unless the responder is
+//   28..36 responder send time     } `latency-probe server`
+// Times are nanoseconds since the Unix epoch. Version 1 ("LPE1") had only
+// the first 20 bytes; a responder leaves its datagrams alone, so an old probe
+// still gets them back unchanged, and an old responder just reflects ours.
+const MAGIC: &[u8; 4] = b"LPE2";
+pub const HEADER_LEN: usize = 36;
+
+// Largest UDP payload, and largest TCP frame
+pub const MAX_PAYLOAD_SIZE: usize = 65507;
+
+/// How echo datagrams travel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EchoTransport {
+    #[default]
+    Udp,
+    /// One TCP connection per target carrying frames prefixed with their
+    /// length (u32, big-endian), for paths that drop UDP
+    Tcp,
+}
+
+/// What fills an echo datagram after its header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadPattern {
+    /// 0x00 bytes
+    Zeros,
+    /// 0, 1, ..., 255, 0, 1, ...
+    #[default]
+    Counting,
+    /// Pseudo-random bytes, different for every datagram, so compression
+    /// or deduplication along the path gains nothing
+    Random,
+}
+
+// How long a sent datagram is remembered, so replies arriving after the
+// probe timed out (or a second time) can still be recognised
+const REPLY_HISTORY: Duration = Duration::from_secs(60);
+
+// One socket per target (and address, with probe_all_addresses), kept across
+// probes so that replies to earlier probes are still received and can be told
+// apart from new ones
+static SESSIONS: Lazy<Mutex<HashMap<TargetAddress, Arc<EchoSession>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn probe_echo(
+    config: &TargetConfig,
+    host: &str,
+    port: u16,
+    timeout_ms: u64,
+    sockopts: SocketOptions,
+) -> Result<ProbeOutcome> {
+    let target = config.name.as_str();
+    let pattern = config.echo.payload_pattern.unwrap_or_default();
+    let transport = config.echo.transport.unwrap_or_default();
+    let size = config.echo.payload_size.unwrap_or(HEADER_LEN);
+    if !(HEADER_LEN..=MAX_PAYLOAD_SIZE).contains(&size) {
+        anyhow::bail!("echo payload_size {} must be between {} and {}", size, HEADER_LEN, MAX_PAYLOAD_SIZE);
+    }
+    let addr = SocketAddr::new(resolve_one(host, sockopts.family).await?, port);
+    let session = session(target, config.address, addr, transport, timeout_ms, sockopts).await?;
+
+    let (sequence, reply) = session.expect();
+    // Taken before sending: the receive task may see the reply before send() returns here
+    let sent = Instant::now();
+    let sent_ns = unix_nanos();
+    if let Err(e) = session.send(&datagram(sequence, sent_ns, size, pattern)).await {
+        session.forget(sequence);
+        return Err(ProbeFailure::new("send_error", format!("sending echo datagram to {} failed: {}", addr, e)).into());
+    }
+
+    match timeout(Duration::from_millis(timeout_ms), reply).await {
+        Ok(Ok(reply)) => {
+            count_received(reply.bytes);
+            let observed = reply.received.saturating_duration_since(sent);
+            // The kernel saw the reply no later than we did; anything else
+            // means the wall clock was stepped in between
+            let latency = reply.kernel_received_ns
+                .and_then(|received_ns| received_ns.checked_sub(sent_ns))
+                .map(Duration::from_nanos)
+                .filter(|kernel| *kernel <= observed)
+                .unwrap_or(observed);
+            Ok(ProbeOutcome { latency, details: ProbeDetails { address: Some(addr.ip()), echo: reply.one_way, ..Default::default() } })
+        }
+        Ok(Err(_)) => Err(ProbeFailure::new("closed", format!("echo connection to {} closed", addr)).into()),
+        Err(_) => {
+            session.timed_out(sequence);
+            Err(ProbeFailure::new("timeout", format!("no echo reply from {} within {}ms", addr, timeout_ms)).into())
+        }
+    }
+}
+
+struct Reply {
+    received: Instant,
+    // As read from the socket, including the TCP transport's length prefix
+    bytes: usize,
+    // When the kernel received the datagram (SO_TIMESTAMPNS), since the Unix epoch
+    kernel_received_ns: Option<u64>,
+    one_way: Option<EchoDetails>,
+}
+
+enum ReplyState {
+    Waiting(oneshot::Sender<Reply>),
+    TimedOut,
+    Replied,
+}
+
+#[derive(Default)]
+struct Sequencing {
+    next_sequence: u64,
+    // Highest sequence number a reply was received for
+    highest_received: Option<u64>,
+    sent: HashMap<u64, ReplyState>,
+    // Send order, for expiring entries older than REPLY_HISTORY
+    order: VecDeque<(Instant, u64)>,
+}
+
+enum Channel {
+    Udp(UdpSocket),
+    // Writes are serialised; the read half belongs to the receive task
+    Tcp(tokio::sync::Mutex<OwnedWriteHalf>),
+}
+
+struct EchoSession {
+    target: String,
+    // The target's address label; None unless it has probe_all_addresses
+    address: Option<IpAddr>,
+    addr: SocketAddr,
+    transport: EchoTransport,
+    sockopts: SocketOptions,
+    channel: Channel,
+    // The TCP connection is gone; the next probe opens a new session
+    closed: AtomicBool,
+    sequencing: Mutex<Sequencing>,
+    receiver: Mutex<Option<AbortHandle>>,
+}
+
+impl EchoSession {
+    fn probe_type(&self) -> &'static str {
+        match self.transport {
+            EchoTransport::Udp => "echo",
+            EchoTransport::Tcp => "echo_tcp",
+        }
+    }
+
+    async fn send(&self, datagram: &[u8]) -> std::io::Result<()> {
+        match &self.channel {
+            Channel::Udp(socket) => socket.send(datagram).await.map(count_sent),
+            Channel::Tcp(writer) => {
+                let mut frame = Vec::with_capacity(4 + datagram.len());
+                frame.extend_from_slice(&(datagram.len() as u32).to_be_bytes());
+                frame.extend_from_slice(datagram);
+                writer.lock().await.write_all(&frame).await?;
+                count_sent(frame.len());
+                Ok(())
+            }
+        }
+    }
+
+    /// Allocate the next sequence number; the receiver yields its reply.
+    /// Datagrams that timed out and got no reply within REPLY_HISTORY are
+    /// counted as lost as they are forgotten.
+    fn expect(&self) -> (u64, oneshot::Receiver<Reply>) {
+        let now = Instant::now();
+        let mut lost = 0;
+        let (sequence, rx) = {
+            let mut seq = self.sequencing.lock().unwrap();
+            while let Some(&(sent_at, old)) = seq.order.front() {
+                if now.saturating_duration_since(sent_at) < REPLY_HISTORY {
+                    break;
+                }
+                seq.order.pop_front();
+                if matches!(seq.sent.remove(&old), Some(ReplyState::TimedOut)) {
+                    lost += 1;
+                }
+            }
+            let sequence = seq.next_sequence;
+            seq.next_sequence += 1;
+            let (tx, rx) = oneshot::channel();
+            seq.sent.insert(sequence, ReplyState::Waiting(tx));
+            seq.order.push_back((now, sequence));
+            (sequence, rx)
+        };
+        self.count_lost(lost);
+        (sequence, rx)
+    }
+
+    fn count_lost(&self, lost: usize) {
+        for _ in 0..lost {
+            metrics::inc_echo_anomaly(&self.target, self.address, self.probe_type(), "lost");
+        }
+    }
+
+    /// Stop receiving replies; datagrams that timed out without one are lost
+    fn close(&self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.abort();
+        }
+        let lost = self.sequencing.lock().unwrap().sent.values().filter(|state| matches!(state, ReplyState::TimedOut)).count();
+        self.count_lost(lost);
+    }
+
+    fn forget(&self, sequence: u64) {
+        self.sequencing.lock().unwrap().sent.remove(&sequence);
+    }
+
+    fn timed_out(&self, sequence: u64) {
+        if let Some(state) = self.sequencing.lock().unwrap().sent.get_mut(&sequence)
+            && matches!(state, ReplyState::Waiting(_))
+        {
+            *state = ReplyState::TimedOut;
+        }
+    }
+
+    /// Hand a reply to its probe, counting it as late, duplicate and/or
+    /// reordered where it is
+    fn replied(&self, sequence: u64, reply: Reply) {
+        let mut seq = self.sequencing.lock().unwrap();
+        let highest = seq.highest_received;
+        let Some(state) = seq.sent.get_mut(&sequence) else {
+            // Not ours, or older than REPLY_HISTORY
+            return;
+        };
+        let previous = std::mem::replace(state, ReplyState::Replied);
+        if matches!(previous, ReplyState::Replied) {
+            tracing::debug!("duplicate echo reply from {} (sequence {}) for {}", self.addr, sequence, self.target);
+            metrics::inc_echo_anomaly(&self.target, self.address, self.probe_type(), "duplicate");
+            return;
+        }
+        if highest.is_some_and(|highest| sequence < highest) {
+            tracing::debug!("reordered echo reply from {} (sequence {}) for {}", self.addr, sequence, self.target);
+            metrics::inc_echo_anomaly(&self.target, self.address, self.probe_type(), "reordered");
+        }
+        seq.highest_received = Some(highest.map_or(sequence, |highest| highest.max(sequence)));
+        let late = match previous {
+            // A probe dropped before its deadline is no different from one that timed out
+            ReplyState::Waiting(tx) => tx.send(reply).is_err(),
+            _ => true,
+        };
+        if late {
+            tracing::debug!("late echo reply from {} (sequence {}) for {}", self.addr, sequence, self.target);
+            metrics::inc_echo_anomaly(&self.target, self.address, self.probe_type(), "late");
+        }
+    }
+
+    /// A reply of `buf`, which took `bytes` on the wire
+    fn received(&self, buf: &[u8], bytes: usize, kernel_received_ns: Option<u64>) {
+        let received = Instant::now();
+        let received_ns = kernel_received_ns.unwrap_or_else(unix_nanos);
+        if let Some(header) = parse_reply(buf) {
+            let one_way = header.one_way(received_ns);
+            self.replied(header.sequence, Reply { received, bytes, kernel_received_ns, one_way });
+        }
+    }
+
+    async fn receive_datagrams(self: Arc<Self>) {
+        let Channel::Udp(socket) = &self.channel else {
+            return;
+        };
+        let mut buf = vec![0u8; 65536];
+        loop {
+            match recv_timestamped(socket, &mut buf).await {
+                Ok((len, kernel_received_ns)) => self.received(&buf[..len], len, kernel_received_ns),
+                // e.g. ICMP port unreachable for an earlier datagram
+                Err(e) => tracing::debug!("echo receive from {} failed: {}", self.addr, e),
+            }
+        }
+    }
+
+    async fn receive_frames(self: Arc<Self>, mut reader: OwnedReadHalf) {
+        let mut buf = vec![0u8; MAX_PAYLOAD_SIZE];
+        let err = loop {
+            let len = match reader.read_u32().await {
+                Ok(len) => len as usize,
+                Err(e) => break e,
+            };
+            if len > buf.len() {
+                break std::io::Error::new(std::io::ErrorKind::InvalidData, format!("echo frame of {} bytes", len));
+            }
+            if let Err(e) = reader.read_exact(&mut buf[..len]).await {
+                break e;
+            }
+            self.received(&buf[..len], 4 + len, None);
+        };
+        tracing::debug!("echo connection to {} for {} closed: {}", self.addr, self.target, err);
+        self.closed.store(true, Ordering::Relaxed);
+        // Fail the probes still waiting rather than let them time out
+        for state in self.sequencing.lock().unwrap().sent.values_mut() {
+            if matches!(state, ReplyState::Waiting(_)) {
+                *state = ReplyState::TimedOut;
+            }
+        }
+    }
+}
+
+/// The target's session, replaced when its address, transport or socket
+/// options changed, or its TCP connection was lost
+async fn session(
+    target: &str,
+    address: Option<IpAddr>,
+    addr: SocketAddr,
+    transport: EchoTransport,
+    timeout_ms: u64,
+    sockopts: SocketOptions,
+) -> Result<Arc<EchoSession>> {
+    let key = (target.to_string(), address);
+    if let Some(session) = SESSIONS.lock().unwrap().get(&key)
+        && session.addr == addr
+        && session.transport == transport
+        && session.sockopts == sockopts
+        && !session.closed.load(Ordering::Relaxed)
+    {
+        return Ok(session.clone());
+    }
+    let host = addr.ip().to_string();
+    let (channel, reader) = match transport {
+        EchoTransport::Udp => {
+            let (socket, _) = sockopts.connect_udp(&host, addr.port()).await?;
+            if let Err(e) = enable_rx_timestamps(&socket) {
+                tracing::debug!("no kernel receive timestamps for echo target {}: {}", target, e);
+            }
+            (Channel::Udp(socket), None)
+        }
+        EchoTransport::Tcp => {
+            let stream = match timeout(Duration::from_millis(timeout_ms), sockopts.connect_tcp(&host, addr.port())).await {
+                Ok(stream) => stream?,
+                Err(_) => return Err(ProbeFailure::new("timeout", format!("echo connection to {} timed out after {}ms", addr, timeout_ms)).into()),
+            };
+            stream.set_nodelay(true)?;
+            let (reader, writer) = stream.into_split();
+            (Channel::Tcp(tokio::sync::Mutex::new(writer)), Some(reader))
+        }
+    };
+    let session = Arc::new(EchoSession {
+        target: target.to_string(),
+        address,
+        addr,
+        transport,
+        sockopts,
+        channel,
+        closed: AtomicBool::new(false),
+        sequencing: Mutex::default(),
+        receiver: Mutex::default(),
+    });
+    let receiver = match reader {
+        Some(reader) => tokio::spawn(session.clone().receive_frames(reader)),
+        None => tokio::spawn(session.clone().receive_datagrams()),
+    }
+    .abort_handle();
+    *session.receiver.lock().unwrap() = Some(receiver);
+    // The replaced session's replies are no longer received
+    let old = SESSIONS.lock().unwrap().insert(key, session.clone());
+    if let Some(old) = old {
+        old.close();
+    }
+    Ok(session)
+}
+
+/// Close the sessions of targets that were removed or reconfigured, and of
+/// addresses they no longer resolve to
+pub fn retain_targets(targets: &[TargetConfig]) {
+    let mut closed = Vec::new();
+    SESSIONS.lock().unwrap().retain(|(name, address), session| {
+        let keep = super::is_current(targets, name, *address, session.probe_type());
+        if !keep {
+            closed.push(session.clone());
+        }
+        keep
+    });
+    for session in closed {
+        session.close();
+    }
+}
+
+/// Have the kernel timestamp every datagram it receives on `socket`
+#[cfg(target_os = "linux")]
+fn enable_rx_timestamps(socket: &UdpSocket) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    // SAFETY: the option value is a c_int of the advertised size
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_rx_timestamps(_socket: &UdpSocket) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "SO_TIMESTAMPNS is Linux only"))
+}
+
+/// Receive a datagram along with its kernel receive timestamp, if any
+#[cfg(target_os = "linux")]
+async fn recv_timestamped(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<(usize, Option<u64>)> {
+    use std::os::fd::AsRawFd;
+
+    socket.async_io(tokio::io::Interest::READABLE, || {
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        // u64 elements keep the control buffer aligned for cmsghdr
+        let mut control = [0u64; 8];
+        // SAFETY: msghdr is plain data; all-zero is a valid empty value
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        // SAFETY: msg points at iov and control, which outlive the call
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) };
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut received_ns = None;
+        // SAFETY: the kernel filled in msg_control/msg_controllen, which the
+        // CMSG macros walk without leaving the buffer
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS {
+                    let ts = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                    received_ns = Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64);
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((len as usize, received_ns))
+    }).await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn recv_timestamped(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<(usize, Option<u64>)> {
+    Ok((socket.recv(buf).await?, None))
+}
+
+pub fn unix_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn datagram(sequence: u64, sent_ns: u64, size: usize, pattern: PayloadPattern) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(size);
+    datagram.extend_from_slice(MAGIC);
+    datagram.extend_from_slice(&sequence.to_be_bytes());
+    datagram.extend_from_slice(&sent_ns.to_be_bytes());
+    datagram.resize(HEADER_LEN, 0);
+    let fill = size - HEADER_LEN;
+    match pattern {
+        PayloadPattern::Zeros => datagram.resize(size, 0),
+        PayloadPattern::Counting => datagram.extend((0..=255u8).cycle().take(fill)),
+        PayloadPattern::Random => {
+            // xorshift64; only needs to look random, not be unpredictable
+            let mut state = (sent_ns ^ sequence) | 1;
+            datagram.extend(std::iter::repeat_with(|| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).take(fill));
+        }
+    }
+    datagram
+}
+
+struct ReplyHeader {
+    sequence: u64,
+    sent_ns: u64,
+    responder_received_ns: u64,
+    responder_sent_ns: u64,
+}
+
+impl ReplyHeader {
+    fn one_way(&self, received_ns: u64) -> Option<EchoDetails> {
+        if self.responder_received_ns == 0 || self.responder_sent_ns == 0 {
+            return None;
+        }
+        let millis = |from: u64, to: u64| (to as i128 - from as i128) as f64 / 1e6;
+        Some(EchoDetails {
+            forward_ms: millis(self.sent_ns, self.responder_received_ns),
+            reverse_ms: millis(self.responder_sent_ns, received_ns),
+        })
+    }
+}
+
+/// Header of a reflected datagram; None for anything else
+fn parse_reply(buf: &[u8]) -> Option<ReplyHeader> {
+    if buf.len() < HEADER_LEN || &buf[..4] != MAGIC {
+        return None;
+    }
+    let field = |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().unwrap());
+    Some(ReplyHeader { sequence: field(4), sent_ns: field(12), responder_received_ns: field(20), responder_sent_ns: field(28) })
+}
+
+/// Fill in the responder's timestamps of an echo datagram about to be sent
+/// back; anything that isn't one is left alone
+pub fn stamp_reply(buf: &mut [u8], received_ns: u64) {
+    if buf.len() < HEADER_LEN || &buf[..4] != MAGIC {
+        return;
+    }
+    buf[20..28].copy_from_slice(&received_ns.to_be_bytes());
+    buf[28..36].copy_from_slice(&unix_nanos().to_be_bytes());
 }
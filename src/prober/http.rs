@@ -1,16 +1,446 @@
 use anyhow::Result;
-use reqwest::Client;
-use tokio::time::{timeout, Duration, Instant};
+use base64::prelude::*;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{Client, Method, Proxy, StatusCode, Url, Version};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, COOKIE, LOCATION};
+use reqwest::redirect::Policy;
+use tokio::time::{timeout_at, Duration, Instant};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use crate::config::{HttpAuth, TargetConfig};
+use super::http_dscp::DscpLayer;
+use super::http_timing::PhaseRecorder;
+use super::http_tls;
+use super::{count_received, count_sent, HttpDetails, ProbeDetails, ProbeFailure, ProbeOutcome, TargetAddress};
+
+/// How an HTTP target's connections are managed between probes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionMode {
+    /// Keep connections alive and reuse them: measures the server
+    Reuse,
+    /// New connection (DNS, TCP, TLS) for every probe: measures full setup cost
+    Cold,
+}
+
+/// Point of the response at which an HTTP probe stops the clock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeasureUntil {
+    /// Status line and headers received
+    Headers,
+    /// First byte of the body received
+    Ttfb,
+    /// Whole body received
+    #[default]
+    FullBody,
+}
+
+/// HTTP protocol version an HTTP target is pinned to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HttpVersion {
+    #[serde(rename = "1.1")]
+    Http1,
+    #[serde(rename = "2")]
+    Http2,
+}
+
+// Encodings we can decode for the body checks
+const ACCEPTED_ENCODINGS: &str = "gzip, deflate, br";
+
+// Same limit reqwest applies by default
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+struct CachedClient {
+    target: TargetConfig,
+    // Resolved proxy password; a rotated one rebuilds the client
+    proxy_password: Option<String>,
+    client: Client,
+}
+
+// One client per target name (and address, with probe_all_addresses), so
+// pooled connections and TLS sessions carry over between probes. Rebuilt when
+// the target's settings change.
+static CLIENTS: Lazy<Mutex<HashMap<TargetAddress, CachedClient>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Drop the clients, and with them the pooled connections, of targets that
+/// were removed or switched to cold mode, and of addresses they no longer
+/// resolve to
+pub fn retain_targets(targets: &[TargetConfig]) {
+    CLIENTS.lock().unwrap().retain(|(name, address), cached| super::is_current(targets, name, *address, cached.target.probe_type()));
+}
+
+/// Inclusive range of acceptable HTTP status codes. Configured as a number
+/// (`200`), a range (`"200-299"`) or a class (`"2xx"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "StatusCodeSpec", into = "String")]
+pub struct StatusCodeRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusCodeSpec {
+    Code(u16),
+    Text(String),
+}
+
+impl TryFrom<StatusCodeSpec> for StatusCodeRange {
+    type Error = String;
+
+    fn try_from(spec: StatusCodeSpec) -> Result<Self, Self::Error> {
+        let invalid = |s: &str| format!("invalid HTTP status code range: {}", s);
+        let (start, end) = match spec {
+            StatusCodeSpec::Code(code) => (code, code),
+            StatusCodeSpec::Text(text) => {
+                let t = text.trim();
+                if let Some(class) = t.strip_suffix("xx").or_else(|| t.strip_suffix("XX")) {
+                    let digit: u16 = class.parse().map_err(|_| invalid(t))?;
+                    (digit * 100, digit * 100 + 99)
+                } else if let Some((a, b)) = t.split_once('-') {
+                    let a = a.trim().parse().map_err(|_| invalid(t))?;
+                    let b = b.trim().parse().map_err(|_| invalid(t))?;
+                    (a, b)
+                } else {
+                    let code = t.parse().map_err(|_| invalid(t))?;
+                    (code, code)
+                }
+            }
+        };
+        if !(100..=999).contains(&start) || end < start || end > 999 {
+            return Err(format!("invalid HTTP status code range: {}-{}", start, end));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl From<StatusCodeRange> for String {
+    fn from(r: StatusCodeRange) -> Self {
+        if r.start == r.end {
+            r.start.to_string()
+        } else {
+            format!("{}-{}", r.start, r.end)
+        }
+    }
+}
+
+impl StatusCodeRange {
+    pub fn contains(&self, code: u16) -> bool {
+        (self.start..=self.end).contains(&code)
+    }
+}
+
+/// `body_regex`, compiled when the config is read so that an invalid
+/// pattern fails the load rather than every probe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BodyRegex(Regex);
+
+impl TryFrom<String> for BodyRegex {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(Self).map_err(|e| format!("invalid body_regex {:?}: {}", pattern, e))
+    }
+}
+
+impl From<BodyRegex> for String {
+    fn from(re: BodyRegex) -> Self {
+        re.0.as_str().to_string()
+    }
+}
+
+impl PartialEq for BodyRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// Probe `url`; the requests, redirects included, and reading the body must
+/// all finish within `timeout_ms`
+pub async fn probe_http(url: &str, target: &TargetConfig, timeout_ms: u64) -> Result<ProbeOutcome> {
+    let proxy_password = match &target.http.proxy_password {
+        Some(password) => Some(password.resolve().await?),
+        None => None,
+    };
+    let client = client_for(target, proxy_password)?;
+    // Per probe: overlapping probes of a target share its client
+    let recorder = PhaseRecorder::default();
+
+    let follow_redirects = target.http.follow_redirects.unwrap_or(true);
+    let max_redirects = target.http.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS);
+    let include_redirect_time = target.http.include_redirect_time.unwrap_or(true);
+    let measure = target.http.measure.unwrap_or_default();
+
+    let mut method = match &target.http.method {
+        Some(m) => Method::from_bytes(m.to_uppercase().as_bytes())
+            .map_err(|_| anyhow::anyhow!("Invalid HTTP method: {}", m))?,
+        None => Method::GET,
+    };
+    let mut request_body = target.http.body.clone();
+    let mut headers = build_headers(target).await?;
+    let mut url = Url::parse(url)?;
+    let mut redirects = 0;
 
-pub async fn probe_http(url: &str) -> Result<Duration> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()?;
     let start = Instant::now();
-    let resp_fut = client.get(url).send();
-    let resp = timeout(Duration::from_secs(30), resp_fut).await??;
-    // you might want to measure until headers / first byte etc.
-    let _ = resp.text().await?;
-    let elapsed = start.elapsed();
-    Ok(elapsed)
+    let deadline = start + Duration::from_millis(timeout_ms);
+    let timed_out = || ProbeFailure::new("timeout", format!("HTTP probe of {} timed out after {} ms", target.get_http_url(), timeout_ms));
+    let (mut resp, hop_start, headers_received) = loop {
+        recorder.reset();
+        let hop_start = Instant::now();
+        let mut request = client.request(method.clone(), url.clone()).headers(headers.clone());
+        if let Some(body) = &request_body {
+            request = request.body(body.clone());
+        }
+        let resp = timeout_at(deadline, recorder.record(request.send())).await.map_err(|_| timed_out())??;
+        let headers_received = Instant::now();
+        let request_line = format!("{} {}{} HTTP/1.1", method, url.path(), url.query().map_or(String::new(), |q| format!("?{}", q)));
+        count_sent(head_size(&request_line, &headers) + request_body.as_ref().map_or(0, |b| b.len()));
+        count_received(head_size(&format!("{:?} {}", resp.version(), resp.status()), resp.headers()));
+
+        let status = resp.status();
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .map(str::to_string);
+        let Some(location) = location.filter(|_| follow_redirects && status.is_redirection()) else {
+            break (resp, hop_start, headers_received);
+        };
+        if redirects >= max_redirects {
+            return Err(ProbeFailure::new(
+                "too_many_redirects",
+                format!("more than {} redirects from {}", max_redirects, target.get_http_url()),
+            )
+            .into());
+        }
+
+        let next = url.join(&location)?;
+        // Don't leak credentials to another host
+        if next.host_str() != url.host_str() {
+            headers.remove(AUTHORIZATION);
+            headers.remove(COOKIE);
+            if let Some(HttpAuth::ApiKey { header, .. }) = &target.http.auth {
+                headers.remove(header.as_str());
+            }
+        }
+        if status == StatusCode::SEE_OTHER
+            || (matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND) && method == Method::POST)
+        {
+            method = Method::GET;
+            request_body = None;
+        }
+        url = next;
+        redirects += 1;
+    };
+
+    let status = resp.status().as_u16();
+    let version = version_label(resp.version());
+    let address = resp.remote_addr().map(|a| a.ip());
+    let content_encoding = resp
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|e| e.to_str().ok())
+        .map(|e| e.trim().to_ascii_lowercase())
+        .filter(|e| e != "identity");
+    // The body is always read in full, for the body checks and so the
+    // connection can be reused, even when the clock stops earlier
+    let mut body = Vec::new();
+    let mut first_byte = None;
+    while let Some(chunk) = timeout_at(deadline, resp.chunk()).await.map_err(|_| timed_out())?? {
+        first_byte.get_or_insert_with(Instant::now);
+        body.extend_from_slice(&chunk);
+    }
+    count_received(body.len());
+    let body_received = Instant::now();
+    let body_bytes = body.len();
+    let phases = recorder.phases(hop_start, headers_received, body_received);
+    let stop = match measure {
+        MeasureUntil::Headers => headers_received,
+        MeasureUntil::Ttfb => first_byte.unwrap_or(body_received),
+        MeasureUntil::FullBody => body_received,
+    };
+    let elapsed = stop - if include_redirect_time { start } else { hop_start };
+
+    // Any status is acceptable unless the target restricts it
+    if !target.http.expected_status.is_empty() && !target.http.expected_status.iter().any(|r| r.contains(status)) {
+        return Err(ProbeFailure::new("unexpected_status", format!("unexpected HTTP status {} from {}", status, url))
+            .with_status(status)
+            .into());
+    }
+    if target.http.body_contains.is_some() || target.http.body_regex.is_some() {
+        let body = decode_body(content_encoding.as_deref(), &body)?;
+        check_body(target, &String::from_utf8_lossy(&body))?;
+    }
+    Ok(ProbeOutcome {
+        latency: elapsed,
+        details: ProbeDetails {
+            address,
+            http: Some(HttpDetails { redirects, phases, body_bytes, version, compressed: content_encoding.is_some() }),
+            ..Default::default()
+        },
+    })
+}
+
+/// Size of a request or status line and headers as HTTP/1.1 puts them on
+/// the wire. An estimate: headers the client adds itself, HTTP/2 framing and
+/// TLS records aren't seen here
+fn head_size(start_line: &str, headers: &HeaderMap) -> usize {
+    let fields: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum();
+    start_line.len() + 2 + fields + 2
+}
+
+/// The target's shared client, or a new one in cold mode
+fn client_for(target: &TargetConfig, proxy_password: Option<String>) -> Result<Client> {
+    if target.http.connection_mode == Some(ConnectionMode::Cold) {
+        return build_client(target, proxy_password.as_deref());
+    }
+    let mut clients = CLIENTS.lock().unwrap();
+    let key = (target.name.clone(), target.address);
+    if let Some(cached) = clients.get(&key)
+        && cached.target == *target
+        && cached.proxy_password == proxy_password
+    {
+        return Ok(cached.client.clone());
+    }
+    let client = build_client(target, proxy_password.as_deref())?;
+    clients.insert(key, CachedClient { target: target.clone(), proxy_password, client: client.clone() });
+    Ok(client)
+}
+
+fn build_client(target: &TargetConfig, proxy_password: Option<&str>) -> Result<Client> {
+    // Redirects are followed by hand so they can be counted and timed per hop
+    let mut tls = http_tls::client_config(target.http.tls.as_ref())?;
+    let mut builder = Client::builder();
+    // Pinning a version also restricts what ALPN offers, so a TLS server can't
+    // negotiate the other one
+    match target.http.http_version {
+        Some(HttpVersion::Http1) => {
+            builder = builder.http1_only();
+            tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+        Some(HttpVersion::Http2) => {
+            builder = builder.http2_prior_knowledge();
+            tls.alpn_protocols = vec![b"h2".to_vec()];
+        }
+        None => {}
+    }
+    let mut builder = PhaseRecorder::install(builder, tls, target.address_family.unwrap_or_default())
+        .redirect(Policy::none());
+    if let Some(dscp) = target.dscp {
+        builder = builder.connector_layer(DscpLayer { dscp });
+    }
+    if let Some(proxy) = build_proxy(target, proxy_password)? {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ip) = target.http.resolve_to {
+        let url = Url::parse(&target.get_http_url())?;
+        let host = url.host_str().ok_or_else(|| anyhow::anyhow!("resolve_to needs a URL with a host name"))?;
+        // The port is taken from the URL, not from the override
+        builder = builder.resolve(host, SocketAddr::new(ip, 0));
+    }
+    Ok(builder.build()?)
+}
+
+fn build_proxy(target: &TargetConfig, password: Option<&str>) -> Result<Option<Proxy>> {
+    let Some(url) = &target.http.proxy else {
+        return Ok(None);
+    };
+    let mut proxy = Proxy::all(url).map_err(|e| anyhow::anyhow!("Invalid proxy URL {:?}: {}", url, e))?;
+    if let Some(username) = &target.http.proxy_username {
+        proxy = proxy.basic_auth(username, password.unwrap_or(""));
+    }
+    Ok(Some(proxy))
+}
+
+fn version_label(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_11 => "1.1",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "unknown",
+    }
+}
+
+/// Undo the response's Content-Encoding; bodies are read raw so the size
+/// metrics reflect what went over the wire
+fn decode_body<'a>(encoding: Option<&str>, body: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    let mut decoded = Vec::new();
+    match encoding {
+        None => return Ok(Cow::Borrowed(body)),
+        Some("gzip" | "x-gzip") => flate2::read::MultiGzDecoder::new(body).read_to_end(&mut decoded)?,
+        Some("deflate") => flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded)?,
+        Some("br") => brotli_decompressor::Decompressor::new(body, 4096).read_to_end(&mut decoded)?,
+        Some(other) => anyhow::bail!("Cannot check body with unsupported Content-Encoding {:?}", other),
+    };
+    Ok(Cow::Owned(decoded))
+}
+
+/// Catch "200 with an error page": the body must contain / match what the target expects
+fn check_body(target: &TargetConfig, body: &str) -> Result<()> {
+    if let Some(needle) = &target.http.body_contains
+        && !body.contains(needle.as_str())
+    {
+        return Err(ProbeFailure::new("body_mismatch", format!("response body does not contain {:?}", needle)).into());
+    }
+    if let Some(BodyRegex(re)) = &target.http.body_regex
+        && !re.is_match(body)
+    {
+        return Err(ProbeFailure::new("body_mismatch", format!("response body does not match /{}/", re)).into());
+    }
+    Ok(())
+}
+
+async fn build_headers(target: &TargetConfig) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &target.http.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| anyhow::anyhow!("Invalid HTTP header name: {}", name))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| anyhow::anyhow!("Invalid value for HTTP header {}", name))?;
+        headers.insert(name, value);
+    }
+    if let Some(auth) = &target.http.auth {
+        let (name, value) = auth_header(auth).await?;
+        headers.insert(name, value);
+    }
+    // Without the option no Accept-Encoding is sent, which servers generally
+    // answer uncompressed; an explicit header in `headers` wins
+    if let Some(compression) = target.http.compression
+        && !headers.contains_key(ACCEPT_ENCODING)
+    {
+        let value = if compression { ACCEPTED_ENCODINGS } else { "identity" };
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+    }
+    Ok(headers)
+}
+
+async fn auth_header(auth: &HttpAuth) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = match auth {
+        HttpAuth::Basic { username, password } => {
+            let password = match password {
+                Some(password) => password.resolve().await?,
+                None => String::new(),
+            };
+            let credentials = BASE64_STANDARD.encode(format!("{}:{}", username.resolve().await?, password));
+            (AUTHORIZATION, format!("Basic {}", credentials))
+        }
+        HttpAuth::Bearer { token } => (AUTHORIZATION, format!("Bearer {}", token.resolve().await?)),
+        HttpAuth::ApiKey { header, value } => {
+            let name = HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| anyhow::anyhow!("Invalid API key header name: {}", header))?;
+            (name, value.resolve().await?)
+        }
+    };
+    let mut value = HeaderValue::from_str(&value).map_err(|_| anyhow::anyhow!("Invalid value for HTTP header {}", name))?;
+    value.set_sensitive(true);
+    Ok((name, value))
 }
@@ -0,0 +1,100 @@
+// DSCP marking for HTTP probes. reqwest opens its sockets itself and has no
+// option for the TOS byte, so a connector layer looks up each new
+// connection's socket by its addresses and marks it with socket2. The TCP and
+// TLS handshakes of the connection go out before that and are not marked.
+
+use anyhow::{Context as _, Result};
+use hyper_util::client::legacy::connect::{Connection, HttpInfo};
+use socket2::SockRef;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+use super::SocketOptions;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone)]
+pub struct DscpLayer {
+    pub dscp: u8,
+}
+
+impl<S> Layer<S> for DscpLayer {
+    type Service = Dscp<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Dscp { inner, dscp: self.dscp }
+    }
+}
+
+#[derive(Clone)]
+pub struct Dscp<S> {
+    inner: S,
+    dscp: u8,
+}
+
+impl<S, R> Service<R> for Dscp<S>
+where
+    S: Service<R>,
+    S::Response: Connection,
+    S::Error: From<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let dscp = self.dscp;
+        let connecting = self.inner.call(req);
+        Box::pin(async move {
+            let conn = connecting.await?;
+            // An unmarked connection would measure the default class instead
+            if let Err(e) = mark(&conn, dscp) {
+                let e: BoxError = e.context("cannot set the DSCP of the HTTP connection").into();
+                return Err(e.into());
+            }
+            Ok(conn)
+        })
+    }
+}
+
+fn mark(conn: &impl Connection, dscp: u8) -> Result<()> {
+    let mut extensions = http::Extensions::new();
+    conn.connected().get_extras(&mut extensions);
+    let info = extensions.get::<HttpInfo>().context("the connection has no address information")?;
+    with_socket(info.local_addr(), info.remote_addr(), |socket| {
+        SocketOptions { dscp: Some(dscp), ..Default::default() }.apply(socket)
+    })
+}
+
+/// Run `f` on the process's socket connected from `local` to `remote`
+#[cfg(unix)]
+fn with_socket(local: SocketAddr, remote: SocketAddr, f: impl FnOnce(SockRef<'_>) -> Result<()>) -> Result<()> {
+    let dir = if cfg!(target_os = "linux") { "/proc/self/fd" } else { "/dev/fd" };
+    for entry in std::fs::read_dir(dir)? {
+        let Some(fd) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        // SAFETY: only used within this iteration; a descriptor closed in
+        // the meantime fails the calls below with EBADF
+        let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        let socket = SockRef::from(&fd);
+        let addr = |addr: std::io::Result<socket2::SockAddr>| addr.ok().and_then(|a| a.as_socket());
+        if addr(socket.local_addr()) == Some(local) && addr(socket.peer_addr()) == Some(remote) {
+            return f(socket);
+        }
+    }
+    anyhow::bail!("no socket from {} to {}", local, remote)
+}
+
+#[cfg(not(unix))]
+fn with_socket(_local: SocketAddr, _remote: SocketAddr, _f: impl FnOnce(SockRef<'_>) -> Result<()>) -> Result<()> {
+    anyhow::bail!("DSCP on HTTP probes is only supported on Unix")
+}
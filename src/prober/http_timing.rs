@@ -0,0 +1,207 @@
+// Per-phase timing for the HTTP probe. reqwest exposes no hooks into the
+// connection lifecycle, so each phase boundary is observed from the outside:
+//   - DNS:  a custom resolver timing the lookup
+//   - TCP:  a connector layer around reqwest's connector (DNS + TCP + TLS)
+//   - TLS:  a rustls session store; rustls consults it while building the
+//           ClientHello, i.e. right after the TCP handshake completed
+// TTFB and body transfer are measured around the request in `probe_http`.
+
+use reqwest::ClientBuilder;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue, Tls13ClientSessionValue};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, NamedGroup};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+use crate::util::{AddressFamily, ResolveError};
+use super::{resolve, HttpPhases};
+
+const TLS_SESSION_CACHE_SIZE: usize = 256;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Marks {
+    connect_start: Option<Instant>,
+    dns_start: Option<Instant>,
+    dns_end: Option<Instant>,
+    tls_start: Option<Instant>,
+    connect_end: Option<Instant>,
+}
+
+tokio::task_local! {
+    // Recorder of the request being sent; the hooks of a client shared by
+    // overlapping probes mark whichever probe's request they work for
+    static CURRENT: PhaseRecorder;
+}
+
+/// Collects phase boundaries for the connection opened by one probe's
+/// request. The resolver, connector layer and TLS config of a client write
+/// to the recorder of the `send` they run under.
+#[derive(Debug, Default, Clone)]
+pub struct PhaseRecorder(Arc<Mutex<Marks>>);
+
+impl PhaseRecorder {
+    /// Forget the previous request; call before each request is sent
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = Marks::default();
+    }
+
+    /// Run `send` with this recorder collecting the phases of its connection
+    pub async fn record<F: Future>(&self, send: F) -> F::Output {
+        CURRENT.scope(self.clone(), send).await
+    }
+
+    /// Mark the recorder of the request being sent, if any. A connection
+    /// hyper finishes in the background after the request got a pooled one
+    /// has none and goes unrecorded.
+    fn mark(f: impl FnOnce(&mut Marks)) {
+        let _ = CURRENT.try_with(|recorder| f(&mut recorder.0.lock().unwrap()));
+    }
+
+    /// Wire the timing hooks into a client builder. `tls` is used as-is
+    /// apart from its session store, which the hooks take over. Resolved
+    /// addresses are arranged by `family`.
+    pub fn install(builder: ClientBuilder, mut tls: ClientConfig, family: AddressFamily) -> ClientBuilder {
+        tls.resumption = Resumption::store(Arc::new(HandshakeMarker {
+            inner: ClientSessionMemoryCache::new(TLS_SESSION_CACHE_SIZE),
+        }));
+        builder
+            .dns_resolver(Arc::new(TimingResolver { family }))
+            .connector_layer(ConnectTimingLayer)
+            .use_preconfigured_tls(tls)
+    }
+
+    /// Split a request into phases. Connection phases are `None` when the
+    /// request reused a pooled connection (or, for DNS/TLS, when there was
+    /// nothing to resolve or no TLS).
+    pub fn phases(&self, request_start: Instant, headers_received: Instant, body_received: Instant) -> HttpPhases {
+        let m = *self.0.lock().unwrap();
+        let between = |from: Option<Instant>, to: Option<Instant>| Some(to?.saturating_duration_since(from?));
+        let connected = m.connect_end.is_some();
+        HttpPhases {
+            dns: between(m.dns_start, m.dns_end).filter(|_| connected),
+            tcp_connect: between(m.dns_end.or(m.connect_start), m.tls_start.or(m.connect_end)),
+            tls_handshake: between(m.tls_start, m.connect_end),
+            ttfb: Some(headers_received.saturating_duration_since(m.connect_end.unwrap_or(request_start))),
+            transfer: Some(body_received.saturating_duration_since(headers_received)),
+        }
+    }
+}
+
+struct TimingResolver {
+    family: AddressFamily,
+}
+
+impl Resolve for TimingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let family = self.family;
+        Box::pin(async move {
+            PhaseRecorder::mark(|m| m.dns_start = Some(Instant::now()));
+            let addrs = resolve(name.as_str(), family).await;
+            PhaseRecorder::mark(|m| m.dns_end = Some(Instant::now()));
+            // A ResolveError is boxed as itself so its reason survives inside reqwest's error
+            let addrs = addrs.map_err(|e| -> Box<dyn std::error::Error + Send + Sync> {
+                match e.downcast::<ResolveError>() {
+                    Ok(failure) => Box::new(failure),
+                    Err(e) => e.into(),
+                }
+            })?;
+            Ok(Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0))) as Addrs)
+        })
+    }
+}
+
+#[derive(Clone)]
+struct ConnectTimingLayer;
+
+impl<S> Layer<S> for ConnectTimingLayer {
+    type Service = ConnectTiming<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectTiming { inner }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectTiming<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for ConnectTiming<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        PhaseRecorder::mark(|m| m.connect_start = Some(Instant::now()));
+        let connecting = self.inner.call(req);
+        Box::pin(async move {
+            let conn = connecting.await;
+            if conn.is_ok() {
+                PhaseRecorder::mark(|m| m.connect_end = Some(Instant::now()));
+            }
+            conn
+        })
+    }
+}
+
+/// Session store that notes when rustls starts building the ClientHello.
+/// Every handshake looks up a cached session or key-exchange hint first.
+#[derive(Debug)]
+struct HandshakeMarker {
+    inner: ClientSessionMemoryCache,
+}
+
+impl HandshakeMarker {
+    fn handshake_started(&self) {
+        PhaseRecorder::mark(|m| {
+            m.tls_start.get_or_insert_with(Instant::now);
+        });
+    }
+}
+
+impl ClientSessionStore for HandshakeMarker {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.inner.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.handshake_started();
+        self.inner.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.inner.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.handshake_started();
+        self.inner.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.inner.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(&self, server_name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.inner.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        self.handshake_started();
+        self.inner.take_tls13_ticket(server_name)
+    }
+}
@@ -0,0 +1,105 @@
+// rustls client configuration for HTTPS probes, built from the target's `tls` block
+
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme, SupportedProtocolVersion};
+use std::sync::Arc;
+use crate::config::TlsConfig;
+
+static TLS12_AND_UP: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13, &rustls::version::TLS12];
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+pub fn client_config(tls: Option<&TlsConfig>) -> Result<ClientConfig> {
+    let default = TlsConfig::default();
+    let tls = tls.unwrap_or(&default);
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let versions = match tls.min_version.as_deref() {
+        None | Some("1.2") => TLS12_AND_UP,
+        Some("1.3") => TLS13_ONLY,
+        Some(other) => anyhow::bail!("Unsupported TLS min_version {:?}, expected \"1.2\" or \"1.3\"", other),
+    };
+    let builder = ClientConfig::builder_with_provider(provider.clone()).with_protocol_versions(versions)?;
+
+    let builder = if tls.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+    } else {
+        builder.with_root_certificates(root_store(tls.ca_file.as_deref())?)
+    };
+
+    let mut config = match (&tls.cert_file, &tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let certs = CertificateDer::pem_file_iter(cert_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("Failed to read client certificate {}", cert_file))?;
+            let key = PrivateKeyDer::from_pem_file(key_file)
+                .with_context(|| format!("Failed to read client key {}", key_file))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("TLS cert_file and key_file must be set together"),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn root_store(ca_file: Option<&str>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let Some(ca_file) = ca_file else {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        return Ok(roots);
+    };
+    for cert in CertificateDer::pem_file_iter(ca_file).with_context(|| format!("Failed to read CA bundle {}", ca_file))? {
+        let cert = cert.with_context(|| format!("Invalid certificate in CA bundle {}", ca_file))?;
+        roots.add(cert)?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("CA bundle {} contains no certificates", ca_file);
+    }
+    Ok(roots)
+}
+
+/// Accepts any certificate, but still checks the handshake signatures so the
+/// handshake costs what a verified one would
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
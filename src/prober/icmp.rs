@@ -1,18 +1,380 @@
-use surge_ping::ping;
+use surge_ping::{AsyncSocket, Config, ICMP};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Once};
 use std::time::Duration;
 use anyhow::Result;
-use crate::util::resolve_host_to_ip;
+use once_cell::sync::Lazy;
+use socket2::{SockRef, Type};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Instant};
+use crate::metrics;
+use crate::util::internet_checksum;
+use crate::config::TargetConfig;
+use super::{count_received, count_sent, resolve_one, ProbeFailure, SocketOptions, TargetAddress};
 
-pub async fn probe_icmp(host: &str, _timeout_ms: u64) -> Result<Duration> {
+// Largest echo payload that fits an IPv4 datagram (65535 - 20 IP - 8 ICMP)
+pub const MAX_PAYLOAD_SIZE: usize = 65507;
+const DEFAULT_PAYLOAD_SIZE: usize = 2;
+
+// How long a sent echo request is remembered, so replies arriving after the
+// probe timed out (or a second time) can still be recognised
+const REPLY_HISTORY: Duration = Duration::from_secs(60);
+
+// A receive error right after another one is retried after this long,
+// doubling up to the maximum, so a persistent one (ENETDOWN, ...) doesn't
+// turn the receive task into a busy loop
+const RECEIVE_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RECEIVE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Kind of socket ICMP probes use, from ICMP_SOCKET
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocketMode {
+    /// Unprivileged ping socket, falling back to a raw socket
+    Auto,
+    /// Ping socket only (Linux `net.ipv4.ping_group_range`, macOS)
+    Unprivileged,
+    /// Raw socket only (CAP_NET_RAW / root)
+    Raw,
+}
+
+static SOCKET_MODE: Lazy<SocketMode> = Lazy::new(|| {
+    match std::env::var("ICMP_SOCKET").unwrap_or_default().to_lowercase().as_str() {
+        "" | "auto" => SocketMode::Auto,
+        "unprivileged" | "dgram" => SocketMode::Unprivileged,
+        "raw" => SocketMode::Raw,
+        other => {
+            tracing::warn!("Unknown ICMP_SOCKET {:?}, using auto", other);
+            SocketMode::Auto
+        }
+    }
+});
+
+static REPORT_SOCKET_TYPE: Once = Once::new();
+
+/// Socket-level settings that set one shared socket apart from another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct SocketKey {
+    ipv6: bool,
+    ttl: Option<u32>,
+    dscp: Option<u8>,
+}
+
+// One socket (plus its receive task) per address family, and per TTL/DSCP
+// since those are socket options. Replies are handed to the waiting probe by
+// source address, identifier and sequence.
+static SOCKETS: Lazy<Mutex<HashMap<SocketKey, Arc<IcmpSocket>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Echo identifier of a target and the sequence number of its next request
+struct TargetSequence {
+    identifier: u16,
+    next_sequence: u16,
+}
+
+// Every target (and address, with probe_all_addresses) gets its own
+// identifier, kept across config reloads that leave it in place, and numbers
+// its requests from 0. Identifiers start from the process id so two probe
+// processes on one host rarely claim each other's replies.
+static TARGET_SEQUENCES: Lazy<Mutex<HashMap<TargetAddress, TargetSequence>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Identifiers handed out so far; not reused while the counter doesn't wrap,
+// even once their target is gone
+static IDENTIFIERS_ALLOCATED: AtomicU16 = AtomicU16::new(0);
+
+/// Forget the sequences of targets that were removed, and of addresses they
+/// no longer resolve to
+pub fn retain_targets(targets: &[TargetConfig]) {
+    TARGET_SEQUENCES.lock().unwrap().retain(|(name, address), _| super::is_current(targets, name, *address, "icmp"));
+}
+
+/// Identifier and sequence number for the next echo request of `target`
+fn next_sequence(target: &str, address: Option<IpAddr>) -> (u16, u16) {
+    let mut sequences = TARGET_SEQUENCES.lock().unwrap();
+    let entry = sequences.entry((target.to_string(), address)).or_insert_with(|| TargetSequence {
+        identifier: (std::process::id() as u16).wrapping_add(IDENTIFIERS_ALLOCATED.fetch_add(1, Ordering::Relaxed)),
+        next_sequence: 0,
+    });
+    let sequence = entry.next_sequence;
+    entry.next_sequence = sequence.wrapping_add(1);
+    (entry.identifier, sequence)
+}
+
+pub async fn probe_icmp(
+    target: &str,
+    address: Option<IpAddr>,
+    host: &str,
+    timeout_ms: u64,
+    payload_size: Option<usize>,
+    sockopts: SocketOptions,
+) -> Result<(Duration, IpAddr)> {
     // Parse the host to IP address
-    let ip_addr = resolve_host_to_ip(host).await?;
-    
-    // Create a simple payload - using process ID as identifier in the payload
-    let process_id = std::process::id() as u16;
-    let payload = process_id.to_be_bytes();
-    
-    // Send ping and measure time
-    let (_packet, duration) = ping(ip_addr, &payload).await?;
-    
-    Ok(duration)
-}
\ No newline at end of file
+    let ip_addr = resolve_one(host, sockopts.family).await?;
+
+    let (identifier, sequence) = next_sequence(target, address);
+    let payload = build_payload(identifier, payload_size.unwrap_or(DEFAULT_PAYLOAD_SIZE))?;
+
+    let socket = shared_socket(ip_addr.is_ipv6(), sockopts)?;
+    // A ping socket's reply can only be told apart by the identifier echoed in the payload
+    let identifier_seen = !socket.kernel_managed || payload.len() >= 2;
+    let key = ReplyKey { addr: ip_addr, identifier: identifier_seen.then_some(identifier), sequence };
+    let reply = socket.expect(key, target, address)?;
+
+    let mut packet = echo_request(ip_addr.is_ipv6(), identifier, sequence, &payload);
+    if let Err(e) = socket.socket.send_to(&mut packet, &SocketAddr::new(ip_addr, 0)).await {
+        socket.forget(&key);
+        return Err(e.into());
+    }
+    let sent = Instant::now();
+    count_sent(packet.len());
+
+    match timeout(Duration::from_millis(timeout_ms), reply).await {
+        Ok(Ok((received, size))) => {
+            count_received(size);
+            Ok((received.saturating_duration_since(sent), ip_addr))
+        }
+        Ok(Err(_)) => anyhow::bail!("ICMP receive task for {} stopped", ip_addr),
+        Err(_) => {
+            socket.timed_out(&key);
+            Err(ProbeFailure::new("timeout", format!("no ICMP echo reply from {} within {}ms", ip_addr, timeout_ms)).into())
+        }
+    }
+}
+
+/// Identifies the reply to one echo request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ReplyKey {
+    addr: IpAddr,
+    // Taken from the payload on Linux ping sockets, where the kernel overwrites
+    // the header's identifier; None if the payload is too short to carry it
+    identifier: Option<u16>,
+    sequence: u16,
+}
+
+// When a reply arrived and the size of its ICMP message
+type Received = (Instant, usize);
+
+enum ReplyState {
+    Waiting(oneshot::Sender<Received>),
+    TimedOut,
+    Replied,
+}
+
+struct SentRequest {
+    target: String,
+    // The target's address label; None unless it has probe_all_addresses
+    address: Option<IpAddr>,
+    sent_at: Instant,
+    state: ReplyState,
+}
+
+#[derive(Default)]
+struct SentRequests {
+    by_key: HashMap<ReplyKey, SentRequest>,
+    // Send order, for expiring entries older than REPLY_HISTORY
+    order: VecDeque<(Instant, ReplyKey)>,
+}
+
+struct IcmpSocket {
+    socket: AsyncSocket,
+    // Linux ping sockets: the kernel owns the identifier and strips the IP header
+    kernel_managed: bool,
+    sent: Mutex<SentRequests>,
+}
+
+impl IcmpSocket {
+    /// Register an echo request about to be sent; the receiver yields the
+    /// time its reply arrived and its size
+    fn expect(&self, key: ReplyKey, target: &str, address: Option<IpAddr>) -> Result<oneshot::Receiver<Received>> {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        while let Some(&(sent_at, old)) = sent.order.front() {
+            if now.saturating_duration_since(sent_at) < REPLY_HISTORY {
+                break;
+            }
+            sent.order.pop_front();
+            if sent.by_key.get(&old).is_some_and(|s| s.sent_at == sent_at) {
+                sent.by_key.remove(&old);
+            }
+        }
+        if let Some(SentRequest { state: ReplyState::Waiting(tx), .. }) = sent.by_key.get(&key)
+            && !tx.is_closed()
+        {
+            anyhow::bail!("an ICMP echo request to {} with sequence {} is already outstanding", key.addr, key.sequence);
+        }
+        let (tx, rx) = oneshot::channel();
+        sent.by_key.insert(key, SentRequest { target: target.to_string(), address, sent_at: now, state: ReplyState::Waiting(tx) });
+        sent.order.push_back((now, key));
+        Ok(rx)
+    }
+
+    fn forget(&self, key: &ReplyKey) {
+        self.sent.lock().unwrap().by_key.remove(key);
+    }
+
+    fn timed_out(&self, key: &ReplyKey) {
+        if let Some(request) = self.sent.lock().unwrap().by_key.get_mut(key)
+            && matches!(request.state, ReplyState::Waiting(_))
+        {
+            request.state = ReplyState::TimedOut;
+        }
+    }
+
+    /// Hand a reply to its probe, or count it as late or duplicate
+    fn replied(&self, key: &ReplyKey, received: Received) {
+        let mut sent = self.sent.lock().unwrap();
+        let Some(request) = sent.by_key.get_mut(key) else {
+            // Not ours, or older than REPLY_HISTORY
+            return;
+        };
+        let kind = match std::mem::replace(&mut request.state, ReplyState::Replied) {
+            // A probe dropped before its deadline is no different from one that timed out
+            ReplyState::Waiting(tx) => match tx.send(received) {
+                Ok(()) => return,
+                Err(_) => "late",
+            },
+            ReplyState::TimedOut => "late",
+            ReplyState::Replied => "duplicate",
+        };
+        tracing::debug!("{} ICMP echo reply from {} (sequence {}) for {}", kind, key.addr, key.sequence, request.target);
+        metrics::inc_icmp_unexpected_reply(&request.target, request.address, kind);
+    }
+
+    async fn receive_replies(self: Arc<Self>) {
+        let mut buf = vec![0u8; 65536];
+        // Zero until an error; a lone one is retried at once
+        let mut retry_delay = Duration::ZERO;
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!("ICMP receive failed: {}", e);
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).clamp(RECEIVE_RETRY_DELAY, MAX_RECEIVE_RETRY_DELAY);
+                    continue;
+                }
+            };
+            retry_delay = Duration::ZERO;
+            let received = Instant::now();
+            let Some((identifier, sequence, size)) = parse_echo_reply(&buf[..len], from.is_ipv6(), self.kernel_managed) else {
+                continue;
+            };
+            self.replied(&ReplyKey { addr: from.ip(), identifier, sequence }, (received, size));
+        }
+    }
+}
+
+/// The socket for this address family and socket options, created on first use
+fn shared_socket(ipv6: bool, sockopts: SocketOptions) -> Result<Arc<IcmpSocket>> {
+    let key = SocketKey { ipv6, ttl: sockopts.ttl, dscp: sockopts.dscp };
+    let mut sockets = SOCKETS.lock().unwrap();
+    if let Some(socket) = sockets.get(&key) {
+        return Ok(socket.clone());
+    }
+    let socket = new_socket(ipv6)?;
+    apply_sockopts(&socket, &sockopts)?;
+    let kernel_managed = cfg!(any(target_os = "linux", target_os = "android"))
+        && i32::from(socket.get_type()) == i32::from(Type::DGRAM);
+    let socket = Arc::new(IcmpSocket { socket, kernel_managed, sent: Mutex::default() });
+    tokio::spawn(socket.clone().receive_replies());
+    sockets.insert(key, socket.clone());
+    Ok(socket)
+}
+
+/// ICMP socket of the type ICMP_SOCKET asks for. surge_ping tries its hint
+/// first and silently falls back to the other type, so the result is checked.
+/// surge_ping links an older socket2, hence the comparisons on the raw type.
+fn new_socket(ipv6: bool) -> Result<AsyncSocket> {
+    let mode = *SOCKET_MODE;
+    let wanted = i32::from(if mode == SocketMode::Raw { Type::RAW } else { Type::DGRAM });
+    let mut config = Config::builder().sock_type_hint(wanted.into());
+    if ipv6 {
+        config = config.kind(ICMP::V6);
+    }
+    let socket = AsyncSocket::new(&config.build()).map_err(|e| {
+        anyhow::anyhow!(
+            "cannot open an ICMP socket ({}); unprivileged ping sockets need the process's group in \
+             net.ipv4.ping_group_range, raw sockets need CAP_NET_RAW",
+            e
+        )
+    })?;
+    let unprivileged = i32::from(socket.get_type()) == i32::from(Type::DGRAM);
+    if mode != SocketMode::Auto && i32::from(socket.get_type()) != wanted {
+        anyhow::bail!(
+            "ICMP_SOCKET={} requested but only a {} ICMP socket could be opened",
+            if mode == SocketMode::Raw { "raw" } else { "unprivileged" },
+            if unprivileged { "unprivileged" } else { "raw" }
+        );
+    }
+    REPORT_SOCKET_TYPE.call_once(|| {
+        tracing::info!("ICMP probes are using {} sockets", if unprivileged { "unprivileged ping" } else { "raw" });
+    });
+    Ok(socket)
+}
+
+// surge_ping only knows the IPv4 TTL, so options are set on its socket directly
+#[cfg(unix)]
+fn apply_sockopts(socket: &AsyncSocket, sockopts: &SocketOptions) -> Result<()> {
+    // SAFETY: the socket is kept open by the AsyncSocket, which outlives this borrow
+    let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(socket.get_native_sock()) };
+    sockopts.apply(SockRef::from(&fd))
+}
+
+#[cfg(windows)]
+fn apply_sockopts(socket: &AsyncSocket, sockopts: &SocketOptions) -> Result<()> {
+    // SAFETY: the socket is kept open by the AsyncSocket, which outlives this borrow
+    let socket = unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(socket.get_native_sock()) };
+    sockopts.apply(SockRef::from(&socket))
+}
+
+/// Echo request header and payload. The kernel fills in the ICMPv6 checksum,
+/// and on ping sockets the identifier and ICMPv4 checksum as well.
+fn echo_request(ipv6: bool, identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(8 + payload.len());
+    packet.push(if ipv6 { ICMPV6_ECHO_REQUEST } else { ICMPV4_ECHO_REQUEST });
+    packet.push(0); // code
+    packet.extend_from_slice(&[0, 0]); // checksum, filled below
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(payload);
+    if !ipv6 {
+        let checksum = internet_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Identifier, sequence and ICMP message size of an echo reply; everything
+/// else is ignored.
+/// IPv4 raw sockets (and ping sockets outside Linux) deliver the IP header too.
+/// On Linux ping sockets the identifier is read from the echoed payload.
+fn parse_echo_reply(buf: &[u8], ipv6: bool, kernel_managed: bool) -> Option<(Option<u16>, u16, usize)> {
+    let icmp = if ipv6 || kernel_managed {
+        buf
+    } else {
+        let header_len = (buf.first()? & 0x0f) as usize * 4;
+        buf.get(header_len..)?
+    };
+    let echo_reply = if ipv6 { ICMPV6_ECHO_REPLY } else { ICMPV4_ECHO_REPLY };
+    if icmp.len() < 8 || icmp[0] != echo_reply {
+        return None;
+    }
+    let identifier = if kernel_managed {
+        icmp.get(8..10).map(|id| u16::from_be_bytes([id[0], id[1]]))
+    } else {
+        Some(u16::from_be_bytes([icmp[4], icmp[5]]))
+    };
+    Some((identifier, u16::from_be_bytes([icmp[6], icmp[7]]), icmp.len()))
+}
+
+/// The target's identifier, padded with a counting pattern up to `size`
+fn build_payload(identifier: u16, size: usize) -> Result<Vec<u8>> {
+    if size > MAX_PAYLOAD_SIZE {
+        anyhow::bail!("ICMP payload_size {} exceeds the maximum of {}", size, MAX_PAYLOAD_SIZE);
+    }
+    Ok(identifier.to_be_bytes().into_iter().chain((0..=255u8).cycle()).take(size).collect())
+}
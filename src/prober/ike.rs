@@ -0,0 +1,148 @@
+use anyhow::Result;
+use std::net::IpAddr;
+use tokio::time::{timeout, Duration, Instant};
+use crate::util::random_bytes;
+use super::{count_received, count_sent, ProbeFailure, SocketOptions};
+
+// IKEv2 constants (RFC 7296)
+const IKE_VERSION_2: u8 = 0x20;
+const EXCHANGE_IKE_SA_INIT: u8 = 34;
+const FLAG_INITIATOR: u8 = 0x08;
+const FLAG_RESPONSE: u8 = 0x20;
+const PAYLOAD_NONE: u8 = 0;
+const PAYLOAD_SA: u8 = 33;
+const PAYLOAD_KE: u8 = 34;
+const PAYLOAD_NONCE: u8 = 40;
+const DH_GROUP_14: u16 = 14;
+const DH_GROUP_14_KEY_LEN: usize = 256;
+const NAT_T_PORT: u16 = 4500;
+
+/// Send an IKE_SA_INIT request and measure time until the responder replies.
+/// No SA is established; any reply carrying our initiator SPI (including
+/// notify-only replies such as INVALID_KE_PAYLOAD or COOKIE) counts.
+pub async fn probe_ike(host: &str, port: u16, timeout_ms: u64, sockopts: SocketOptions) -> Result<(Duration, IpAddr)> {
+    let (socket, addr) = sockopts.connect_udp(host, port).await?;
+
+    let spi_i: [u8; 8] = random_bytes::<8>();
+    let mut msg = Vec::with_capacity(512);
+    // Port 4500 expects the 4-byte non-ESP marker in front of IKE messages
+    if port == NAT_T_PORT {
+        msg.extend_from_slice(&[0u8; 4]);
+    }
+    let header_offset = msg.len();
+    msg.extend_from_slice(&build_sa_init(&spi_i));
+
+    let start = Instant::now();
+    socket.send(&msg).await?;
+    count_sent(msg.len());
+
+    let mut buf = [0u8; 2048];
+    let deadline = Duration::from_millis(timeout_ms);
+    loop {
+        let remaining = deadline
+            .checked_sub(start.elapsed())
+            .ok_or_else(|| ProbeFailure::new("timeout", format!("IKE_SA_INIT to {} timed out", addr)))?;
+        let n = timeout(remaining, socket.recv(&mut buf)).await??;
+        let elapsed = start.elapsed();
+        count_received(n);
+        if is_sa_init_response(&buf[..n], header_offset, &spi_i) {
+            return Ok((elapsed, addr.ip()));
+        }
+        tracing::debug!("ignoring unrelated {}-byte datagram from {}", n, addr);
+    }
+}
+
+fn is_sa_init_response(buf: &[u8], header_offset: usize, spi_i: &[u8; 8]) -> bool {
+    let Some(hdr) = buf.get(header_offset..header_offset + 28) else {
+        return false;
+    };
+    hdr[0..8] == spi_i[..]
+        && hdr[17] & 0xf0 == IKE_VERSION_2
+        && hdr[18] == EXCHANGE_IKE_SA_INIT
+        && hdr[19] & FLAG_RESPONSE != 0
+}
+
+fn build_sa_init(spi_i: &[u8; 8]) -> Vec<u8> {
+    let sa = sa_payload();
+    let ke = ke_payload();
+    let nonce = generic_payload(PAYLOAD_NONE, &random_bytes::<32>());
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&generic_header(PAYLOAD_KE, sa.len()));
+    body.extend_from_slice(&sa);
+    body.extend_from_slice(&generic_header(PAYLOAD_NONCE, ke.len()));
+    body.extend_from_slice(&ke);
+    body.extend_from_slice(&nonce);
+
+    let total_len = (28 + body.len()) as u32;
+    let mut msg = Vec::with_capacity(total_len as usize);
+    msg.extend_from_slice(spi_i);
+    msg.extend_from_slice(&[0u8; 8]); // responder SPI
+    msg.push(PAYLOAD_SA);
+    msg.push(IKE_VERSION_2);
+    msg.push(EXCHANGE_IKE_SA_INIT);
+    msg.push(FLAG_INITIATOR);
+    msg.extend_from_slice(&0u32.to_be_bytes()); // message id
+    msg.extend_from_slice(&total_len.to_be_bytes());
+    msg.extend_from_slice(&body);
+    msg
+}
+
+fn generic_header(next_payload: u8, body_len: usize) -> [u8; 4] {
+    let len = ((body_len + 4) as u16).to_be_bytes();
+    [next_payload, 0, len[0], len[1]]
+}
+
+fn generic_payload(next_payload: u8, body: &[u8]) -> Vec<u8> {
+    let mut p = generic_header(next_payload, body.len()).to_vec();
+    p.extend_from_slice(body);
+    p
+}
+
+/// Single IKE proposal: AES-CBC-256 / HMAC-SHA2-256 / HMAC-SHA2-256-128 / MODP-2048
+fn sa_payload() -> Vec<u8> {
+    // (type, id, key length attribute)
+    let transforms: [(u8, u16, Option<u16>); 4] = [
+        (1, 12, Some(256)), // ENCR_AES_CBC
+        (2, 5, None),       // PRF_HMAC_SHA2_256
+        (3, 12, None),      // AUTH_HMAC_SHA2_256_128
+        (4, DH_GROUP_14, None),
+    ];
+
+    let mut body = Vec::new();
+    for (i, (t_type, t_id, key_len)) in transforms.iter().enumerate() {
+        let last = i == transforms.len() - 1;
+        let attr_len = if key_len.is_some() { 4 } else { 0 };
+        let len = (8 + attr_len) as u16;
+        body.push(if last { 0 } else { 3 });
+        body.push(0);
+        body.extend_from_slice(&len.to_be_bytes());
+        body.push(*t_type);
+        body.push(0);
+        body.extend_from_slice(&t_id.to_be_bytes());
+        if let Some(bits) = key_len {
+            body.extend_from_slice(&(0x8000u16 | 14).to_be_bytes()); // TV format, Key Length
+            body.extend_from_slice(&bits.to_be_bytes());
+        }
+    }
+
+    let mut proposal = Vec::new();
+    proposal.push(0); // last proposal
+    proposal.push(0);
+    proposal.extend_from_slice(&((8 + body.len()) as u16).to_be_bytes());
+    proposal.push(1); // proposal number
+    proposal.push(1); // protocol id: IKE
+    proposal.push(0); // SPI size
+    proposal.push(transforms.len() as u8);
+    proposal.extend_from_slice(&body);
+    proposal
+}
+
+fn ke_payload() -> Vec<u8> {
+    // The key exchange data only has to look plausible; we never derive keys
+    let mut body = Vec::with_capacity(4 + DH_GROUP_14_KEY_LEN);
+    body.extend_from_slice(&DH_GROUP_14.to_be_bytes());
+    body.extend_from_slice(&[0u8; 2]);
+    body.extend_from_slice(&random_bytes::<DH_GROUP_14_KEY_LEN>());
+    body
+}
@@ -1,9 +1,69 @@
 use serde::{Deserialize, Serialize};
+use anyhow::Result;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{info, error};
+use crate::config::TargetConfig;
+use socket2::SockRef;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use crate::util::{ip_literal, resolve_host, unspecified_for, AddressFamily, ResolveError};
 
 pub mod icmp;
 pub mod tcp_connect;
 pub mod http;
+mod http_dscp;
+mod http_timing;
+mod http_tls;
 pub mod echo;
+pub mod ike;
+pub mod rpc;
+#[cfg(unix)]
+pub mod tcp_syn;
+
+/// A target's name and, for a copy made by probe_all_addresses, its address:
+/// what probers keep per-target state (sessions, connections, clients) by
+pub type TargetAddress = (String, Option<IpAddr>);
+
+// Addresses each probe_all_addresses target resolved to last, so state kept
+// for addresses DNS has rotated away can be dropped
+static RESOLVED: Lazy<Mutex<HashMap<String, Vec<IpAddr>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Bumped whenever a target resolves to a different set of addresses
+static RESOLUTIONS: Lazy<watch::Sender<()>> = Lazy::new(|| watch::channel(()).0);
+
+/// Notified when a probe_all_addresses target resolves to other addresses
+pub fn subscribe_resolutions() -> watch::Receiver<()> {
+    RESOLUTIONS.subscribe()
+}
+
+/// Whether state kept for `name` (at `address`, for a probe_all_addresses
+/// copy) and `probe_type` still belongs to one of `targets`: the target is
+/// configured with that probe type and, if set, resolved to that address last
+pub fn is_current(targets: &[TargetConfig], name: &str, address: Option<IpAddr>, probe_type: &str) -> bool {
+    targets.iter().any(|t| {
+        t.name == name
+            && t.probe_type() == probe_type
+            && address.is_none_or(|ip| {
+                t.probe_all_addresses == Some(true)
+                    && RESOLVED.lock().unwrap().get(name).is_some_and(|addrs| addrs.contains(&ip))
+            })
+    })
+}
+
+fn record_resolution(target: &str, addrs: &[IpAddr]) {
+    let mut sorted = addrs.to_vec();
+    sorted.sort();
+    crate::metrics::set_resolved_addresses(target, &sorted);
+    let previous = RESOLVED.lock().unwrap().insert(target.to_string(), sorted.clone());
+    if previous.is_some_and(|previous| previous != sorted) {
+        RESOLUTIONS.send_replace(());
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
@@ -12,4 +72,554 @@ pub enum ProbeKind {
     TcpConnect,
     Http,
     Echo,
+    Ike,
+    #[serde(alias = "tcp_syn")]
+    TcpSyn,
+    Portmap,
+    Nfs,
+}
+
+impl ProbeKind {
+    /// Value used for the `probe_type` metric label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeKind::Icmp => "icmp",
+            ProbeKind::TcpConnect => "tcp_connect",
+            ProbeKind::Http => "http",
+            ProbeKind::Echo => "echo",
+            ProbeKind::Ike => "ike",
+            ProbeKind::TcpSyn => "tcp_syn",
+            ProbeKind::Portmap => "portmap",
+            ProbeKind::Nfs => "nfs",
+        }
+    }
+
+    /// Port probed when a target doesn't set one (unused by ICMP)
+    pub fn default_port(&self) -> u16 {
+        match self {
+            ProbeKind::Icmp => 0,
+            ProbeKind::TcpConnect | ProbeKind::Http | ProbeKind::TcpSyn => 80,
+            ProbeKind::Echo => 9000,
+            ProbeKind::Ike => 500,
+            ProbeKind::Portmap => 111,
+            ProbeKind::Nfs => 2049,
+        }
+    }
+}
+
+/// What a probe run did besides taking its latency, whether or not it
+/// succeeded; collected by `collect_stats` from wherever the probe does it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProbeStats {
+    // Time spent resolving host names; None when nothing was looked up (IP
+    // literals, reused connections)
+    pub dns: Option<Duration>,
+    // Most preferred address of the last host resolved, also for IP literals
+    pub resolved: Option<IpAddr>,
+    // Payload bytes written to and read from the probe's sockets
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+tokio::task_local! {
+    static STATS: Cell<ProbeStats>;
+}
+
+/// Run `future` (a probe), also returning the stats it counted on top of
+/// `initial`
+pub async fn collect_stats<F: Future>(initial: ProbeStats, future: F) -> (F::Output, ProbeStats) {
+    STATS.scope(Cell::new(initial), async {
+        let output = future.await;
+        (output, STATS.with(Cell::get))
+    }).await
+}
+
+/// Add to the stats of the probe running on this task, if any
+fn count(update: impl FnOnce(&mut ProbeStats)) {
+    let _ = STATS.try_with(|stats| {
+        let mut current = stats.get();
+        update(&mut current);
+        stats.set(current);
+    });
+}
+
+fn count_dns(elapsed: Duration) {
+    count(|stats| stats.dns = Some(stats.dns.unwrap_or_default() + elapsed));
+}
+
+// Counts the lookup when dropped, so a lookup the probe timeout cancels
+// counts up to then: a resolver timing out is what the DNS time is for
+struct DnsTimer(std::time::Instant);
+
+impl Drop for DnsTimer {
+    fn drop(&mut self) {
+        count_dns(self.0.elapsed());
+    }
+}
+
+/// `util::resolve_host` with the lookup time counted into the probe's stats
+pub async fn resolve(host: &str, family: AddressFamily) -> Result<Vec<IpAddr>> {
+    let timer = ip_literal(host).is_none().then(|| DnsTimer(std::time::Instant::now()));
+    let addrs = resolve_host(host, family).await;
+    drop(timer);
+    if let Ok(addrs) = &addrs {
+        count(|stats| stats.resolved = Some(addrs[0]));
+    }
+    addrs
+}
+
+/// The most preferred address of `host`, see `resolve`
+pub async fn resolve_one(host: &str, family: AddressFamily) -> Result<IpAddr> {
+    Ok(resolve(host, family).await?[0])
+}
+
+pub fn count_sent(bytes: usize) {
+    count(|stats| stats.bytes_sent += bytes as u64);
+}
+
+pub fn count_received(bytes: usize) {
+    count(|stats| stats.bytes_received += bytes as u64);
+}
+
+/// Result of a successful probe: the latency plus any kind-specific measurements
+#[derive(Debug, Clone, Default)]
+pub struct ProbeOutcome {
+    pub latency: Duration,
+    pub details: ProbeDetails,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProbeDetails {
+    // Address the probe reached, where the prober knows it
+    pub address: Option<IpAddr>,
+    pub http: Option<HttpDetails>,
+    pub tcp: Option<TcpDetails>,
+    pub echo: Option<EchoDetails>,
+}
+
+/// One-way delays of an echo probe answered by `latency-probe server`. They
+/// rely on both clocks being synchronised; skew shifts one direction's delay
+/// into the other and can make either negative.
+#[derive(Debug, Clone, Default)]
+pub struct EchoDetails {
+    // Our send to the responder's receive, in milliseconds
+    pub forward_ms: f64,
+    // The responder's send to our receive, in milliseconds
+    pub reverse_ms: f64,
+}
+
+/// Kernel view of a TCP connection right after the handshake (TCP_INFO)
+#[derive(Debug, Clone, Default)]
+pub struct TcpDetails {
+    // Smoothed RTT and its mean deviation; just after connect this is the
+    // SYN / SYN-ACK round trip
+    pub srtt: Duration,
+    pub rttvar: Duration,
+    pub fast_open: Option<FastOpenDetails>,
+}
+
+/// Outcome of a TCP Fast Open probe
+#[derive(Debug, Clone, Default)]
+pub struct FastOpenDetails {
+    // The server acknowledged the data sent on the SYN
+    pub accepted: bool,
+    // Time the same exchange took over a regular connection
+    pub regular: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpDetails {
+    pub redirects: usize,
+    pub phases: HttpPhases,
+    // Response body size as received (before any content decoding)
+    pub body_bytes: usize,
+    // Protocol of the final response: "1.0", "1.1", "2" or "3"
+    pub version: &'static str,
+    // Whether the response carried a Content-Encoding
+    pub compressed: bool,
+}
+
+impl HttpDetails {
+    /// Body bytes per second over the transfer phase, if it took measurable time
+    pub fn throughput(&self) -> Option<f64> {
+        let transfer = self.phases.transfer?.as_secs_f64();
+        (transfer > 0.0).then(|| self.body_bytes as f64 / transfer)
+    }
+}
+
+/// Where the time of the final request went. Connection phases are `None`
+/// when a pooled connection was reused or the phase did not apply.
+#[derive(Debug, Clone, Default)]
+pub struct HttpPhases {
+    pub dns: Option<Duration>,
+    pub tcp_connect: Option<Duration>,
+    pub tls_handshake: Option<Duration>,
+    pub ttfb: Option<Duration>,
+    pub transfer: Option<Duration>,
+}
+
+impl From<Duration> for ProbeOutcome {
+    fn from(latency: Duration) -> Self {
+        Self { latency, details: ProbeDetails::default() }
+    }
+}
+
+/// The latency and the address that answered
+impl From<(Duration, IpAddr)> for ProbeOutcome {
+    fn from((latency, address): (Duration, IpAddr)) -> Self {
+        Self { latency, details: ProbeDetails { address: Some(address), ..Default::default() } }
+    }
+}
+
+/// Probe failure with a machine-readable reason, carried inside `anyhow::Error`
+/// so probers can keep using `?` for everything else
+#[derive(Debug)]
+pub struct ProbeFailure {
+    pub reason: &'static str,
+    pub status_code: Option<u16>,
+    pub message: String,
+}
+
+impl ProbeFailure {
+    pub fn new(reason: &'static str, message: impl Into<String>) -> Self {
+        Self { reason, status_code: None, message: message.into() }
+    }
+
+    pub fn with_status(mut self, status_code: u16) -> Self {
+        self.status_code = Some(status_code);
+        self
+    }
+}
+
+impl std::fmt::Display for ProbeFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProbeFailure {}
+
+/// Structured failure details, if the prober attached any
+pub fn failure_of(err: &anyhow::Error) -> Option<&ProbeFailure> {
+    err.chain().find_map(|e| e.downcast_ref::<ProbeFailure>())
+}
+
+/// Failure reason of a probe error: the prober's own, or else derived from
+/// the error types in the chain, so a timeout, refused connection or TLS
+/// error gets the same reason whichever library reported it
+pub fn reason_of(err: &anyhow::Error) -> &'static str {
+    if let Some(failure) = failure_of(err) {
+        return failure.reason;
+    }
+    for cause in err.chain() {
+        if cause.is::<ResolveError>() {
+            return "dns";
+        }
+        if cause.is::<tokio::time::error::Elapsed>() {
+            return "timeout";
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>()
+            && e.is_timeout()
+        {
+            return "timeout";
+        }
+        if cause.is::<rustls::Error>() {
+            return "tls";
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            if wraps_tls_error(e) {
+                return "tls";
+            }
+            match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => return "refused",
+                std::io::ErrorKind::NetworkUnreachable => return "network_unreachable",
+                std::io::ErrorKind::HostUnreachable => return "host_unreachable",
+                std::io::ErrorKind::TimedOut => return "timeout",
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe => return "reset",
+                _ => {}
+            }
+        }
+    }
+    "error"
+}
+
+/// TLS errors reach hyper wrapped in (possibly nested) io::Errors, whose
+/// source() skips the wrapped error
+fn wraps_tls_error(err: &std::io::Error) -> bool {
+    match err.get_ref() {
+        Some(inner) if inner.is::<rustls::Error>() => true,
+        Some(inner) => inner.downcast_ref::<std::io::Error>().is_some_and(wraps_tls_error),
+        None => false,
+    }
+}
+
+/// IP-level options applied to probe sockets
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketOptions {
+    pub family: AddressFamily,
+    pub ttl: Option<u32>,
+    pub dscp: Option<u8>,
+    // Only used for TCP connections
+    pub source_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub fast_open: bool,
+}
+
+impl SocketOptions {
+    pub fn for_target(target: &TargetConfig) -> Self {
+        Self {
+            family: target.address_family.unwrap_or_default(),
+            ttl: target.ttl,
+            dscp: target.dscp,
+            source_address: target.source_address,
+            interface: target.interface.clone(),
+            fast_open: false,
+        }
+    }
+
+    /// Apply to a socket; IPv4 or IPv6 options are picked from its local address
+    pub fn apply(&self, socket: SockRef<'_>) -> Result<()> {
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        if let Some(ttl) = self.ttl {
+            if ipv6 {
+                socket.set_unicast_hops_v6(ttl)?;
+            } else {
+                socket.set_ttl_v4(ttl)?;
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            // DSCP is the upper six bits of the TOS / traffic class byte
+            let tos = (dscp as u32) << 2;
+            if ipv6 {
+                set_tclass_v6(&socket, tos)?;
+            } else {
+                socket.set_tos_v4(tos)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve `host` and connect, see `connect_tcp_to`
+    pub async fn connect_tcp(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let addrs = resolve(host, self.family).await?;
+        self.connect_tcp_to(host, addrs, port).await
+    }
+
+    /// Connect to `host`, resolved to `addrs`, with the options applied before
+    /// the SYN is sent, trying each address in order of preference. With a
+    /// source address only destinations of its family are tried.
+    pub async fn connect_tcp_to(&self, host: &str, mut addrs: Vec<IpAddr>, port: u16) -> Result<TcpStream> {
+        if let Some(source) = self.source_address {
+            addrs.retain(|ip| ip.is_ipv4() == source.is_ipv4());
+            if addrs.is_empty() {
+                anyhow::bail!("{} has no address of the same family as source_address {}", host, source);
+            }
+        }
+        let mut last_err = None;
+        for ip in addrs {
+            let socket = if ip.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            self.prepare_tcp(&socket)?;
+            self.apply(SockRef::from(&socket))?;
+            match socket.connect(SocketAddr::new(ip, port)).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.expect("resolve_host returns at least one address").into())
+    }
+
+    fn prepare_tcp(&self, socket: &TcpSocket) -> Result<()> {
+        if self.fast_open {
+            set_fast_open_connect(SockRef::from(socket))?;
+        }
+        if let Some(interface) = &self.interface {
+            bind_device(SockRef::from(socket), interface)?;
+        }
+        if let Some(source) = self.source_address {
+            socket
+                .bind(SocketAddr::new(source, 0))
+                .map_err(|e| anyhow::anyhow!("cannot bind to source_address {}: {}", source, e))?;
+        }
+        Ok(())
+    }
+
+    /// Resolve `host` and return a UDP socket connected to it
+    pub async fn connect_udp(&self, host: &str, port: u16) -> Result<(UdpSocket, SocketAddr)> {
+        let addr = SocketAddr::new(resolve_one(host, self.family).await?, port);
+        let socket = UdpSocket::bind(unspecified_for(addr.ip())).await?;
+        self.apply(SockRef::from(&socket))?;
+        socket.connect(addr).await?;
+        Ok((socket, addr))
+    }
+}
+
+// With TCP_FASTOPEN_CONNECT, connect() returns at once and the SYN goes out
+// with the first write, carrying its data if the kernel has a cookie
+#[cfg(target_os = "linux")]
+fn set_fast_open_connect(socket: SockRef<'_>) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    // SAFETY: the option value is a c_int of the advertised size
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        anyhow::bail!("cannot enable TCP Fast Open: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open_connect(_socket: SockRef<'_>) -> Result<()> {
+    anyhow::bail!("TCP Fast Open probes are only supported on Linux")
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: SockRef<'_>, interface: &str) -> Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| anyhow::anyhow!("cannot bind to interface {}: {}", interface, e))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: SockRef<'_>, _interface: &str) -> Result<()> {
+    anyhow::bail!("Binding to an interface is only supported on Linux")
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn set_tclass_v6(socket: &SockRef<'_>, tclass: u32) -> Result<()> {
+    Ok(socket.set_tclass_v6(tclass)?)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+fn set_tclass_v6(_socket: &SockRef<'_>, _tclass: u32) -> Result<()> {
+    anyhow::bail!("DSCP on IPv6 is not supported on this platform")
+}
+
+/// Drop the state probers keep per target (open connections, sessions) for
+/// targets that are no longer configured as they were
+pub fn retain_targets(targets: &[TargetConfig]) {
+    RESOLVED.lock().unwrap().retain(|name, _| targets.iter().any(|t| t.name == *name && t.probe_all_addresses == Some(true)));
+    tcp_connect::retain_targets(targets);
+    echo::retain_targets(targets);
+    icmp::retain_targets(targets);
+    http::retain_targets(targets);
+}
+
+/// With `probe_all_addresses`, one copy of the target per address its host
+/// resolves to, each with its `address` set; otherwise just the target. If the host
+/// doesn't resolve the target is returned as-is and its probe reports why.
+/// Also returns the stats of the lookup, which the copies' probes start from
+/// since they do none of their own.
+pub async fn expand_addresses(target: &TargetConfig) -> (Vec<TargetConfig>, ProbeStats) {
+    let unexpanded = || (vec![target.clone()], ProbeStats::default());
+    if target.probe_all_addresses != Some(true) || (target.kind == ProbeKind::Http && target.http.resolve_to.is_some()) {
+        return unexpanded();
+    }
+    let family = target.address_family.unwrap_or_default();
+    let (resolved, lookup) = match target.kind {
+        // HTTP keeps the URL (Host header, SNI) and pins the address instead
+        ProbeKind::Http => match reqwest::Url::parse(&target.get_http_url()) {
+            Ok(url) => match url.host_str() {
+                Some(host) => collect_stats(ProbeStats::default(), resolve(host, family)).await,
+                None => return unexpanded(),
+            },
+            Err(_) => return unexpanded(),
+        },
+        _ => collect_stats(ProbeStats::default(), resolve(&target.host, family)).await,
+    };
+    let Ok(mut addrs) = resolved else {
+        return unexpanded();
+    };
+    let mut seen = std::collections::HashSet::new();
+    addrs.retain(|ip| seen.insert(*ip));
+    record_resolution(&target.name, &addrs);
+    let targets = addrs
+        .into_iter()
+        .map(|ip| {
+            let mut t = target.clone();
+            t.address = Some(ip);
+            if t.kind == ProbeKind::Http {
+                t.http.resolve_to = Some(ip);
+            } else {
+                t.host = ip.to_string();
+            }
+            t
+        })
+        .collect();
+    (targets, lookup)
+}
+
+/// Run a single probe against the target and log the outcome
+pub async fn run_probe(target: &TargetConfig, timeout_ms: u64) -> Result<ProbeOutcome> {
+    let host = &target.host;
+    let port = target.port.unwrap_or(target.kind.default_port());
+    let sockopts = SocketOptions::for_target(target);
+    let (label, subject, result) = match target.kind {
+        ProbeKind::Icmp => {
+            let result = icmp::probe_icmp(&target.name, target.address, host, timeout_ms, target.icmp.payload_size, sockopts).await;
+            ("icmp probe", host.clone(), result.map(ProbeOutcome::from))
+        }
+        ProbeKind::TcpConnect => {
+            let result = match target.tcp.mode {
+                Some(tcp_connect::TcpMode::Persistent) => {
+                    let payload = target.tcp.payload.as_deref().unwrap_or(tcp_connect::DEFAULT_PAYLOAD);
+                    tcp_connect::probe_tcp_persistent(&target.name, target.address, host, port, timeout_ms, payload, sockopts).await
+                }
+                _ if target.tcp.fast_open == Some(true) => {
+                    let payload = target.tcp.payload.as_deref().unwrap_or(tcp_connect::DEFAULT_PAYLOAD);
+                    tcp_connect::probe_tcp_fast_open(host, port, timeout_ms, payload, sockopts).await
+                }
+                _ => {
+                    let close = target.tcp.close.unwrap_or_default();
+                    let include_teardown = target.tcp.include_teardown.unwrap_or(false);
+                    tcp_connect::probe_tcp(host, port, timeout_ms, close, include_teardown, sockopts).await
+                }
+            };
+            ("tcp connect", host.clone(), result)
+        }
+        ProbeKind::Http => {
+            let url = target.get_http_url();
+            let result = http::probe_http(&url, target, timeout_ms).await;
+            ("http probe", url, result)
+        }
+        ProbeKind::Echo => {
+            let result = echo::probe_echo(target, host, port, timeout_ms, sockopts).await;
+            ("echo probe", host.clone(), result)
+        }
+        ProbeKind::Ike => {
+            let result = ike::probe_ike(host, port, timeout_ms, sockopts).await;
+            ("ike probe", host.clone(), result.map(ProbeOutcome::from))
+        }
+        ProbeKind::Portmap => {
+            let result = rpc::probe_portmap(host, port, timeout_ms, sockopts).await;
+            ("portmap probe", host.clone(), result.map(ProbeOutcome::from))
+        }
+        ProbeKind::Nfs => {
+            let result = rpc::probe_nfs(host, port, timeout_ms, sockopts).await;
+            ("nfs probe", host.clone(), result.map(ProbeOutcome::from))
+        }
+        #[cfg(unix)]
+        ProbeKind::TcpSyn => {
+            let result = tcp_syn::probe_tcp_syn(host, port, timeout_ms, sockopts).await;
+            ("tcp syn probe", host.clone(), result.map(ProbeOutcome::from))
+        }
+        #[cfg(not(unix))]
+        ProbeKind::TcpSyn => {
+            ("tcp syn probe", host.clone(), Err(anyhow::anyhow!("tcp_syn probes are only supported on Unix")))
+        }
+    };
+
+    match &result {
+        Ok(outcome) => info!("{} {} success: {:?}", label, subject, outcome.latency),
+        Err(e) => error!("{} {} failed: {:?}", label, subject, e),
+    }
+    result
 }
@@ -0,0 +1,140 @@
+use anyhow::Result;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration, Instant};
+use crate::util::random_u64;
+use super::{count_received, count_sent, ProbeFailure, SocketOptions};
+
+// ONC RPC (RFC 5531) constants
+const RPC_VERSION: u32 = 2;
+const MSG_CALL: u32 = 0;
+const MSG_REPLY: u32 = 1;
+const MSG_ACCEPTED: u32 = 0;
+const ACCEPT_SUCCESS: u32 = 0;
+const ACCEPT_PROG_MISMATCH: u32 = 2;
+const PROC_NULL: u32 = 0;
+const LAST_FRAGMENT: u32 = 0x8000_0000;
+
+const PORTMAP_PROGRAM: u32 = 100000;
+const PORTMAP_VERSION: u32 = 2;
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+
+/// NULL procedure call to rpcbind/portmapper over UDP
+pub async fn probe_portmap(host: &str, port: u16, timeout_ms: u64, sockopts: SocketOptions) -> Result<(Duration, IpAddr)> {
+    rpc_null_udp(host, port, PORTMAP_PROGRAM, PORTMAP_VERSION, timeout_ms, sockopts).await
+}
+
+/// NFSv3 NULL procedure call over TCP. Nothing is mounted; NFSv4-only servers
+/// answer with PROG_MISMATCH, which still proves the server is responsive.
+pub async fn probe_nfs(host: &str, port: u16, timeout_ms: u64, sockopts: SocketOptions) -> Result<(Duration, IpAddr)> {
+    rpc_null_tcp(host, port, NFS_PROGRAM, NFS_VERSION, timeout_ms, sockopts).await
+}
+
+async fn rpc_null_udp(
+    host: &str,
+    port: u16,
+    program: u32,
+    version: u32,
+    timeout_ms: u64,
+    sockopts: SocketOptions,
+) -> Result<(Duration, IpAddr)> {
+    let (socket, addr) = sockopts.connect_udp(host, port).await?;
+
+    let xid = random_u64() as u32;
+    let call = null_call(xid, program, version);
+    let start = Instant::now();
+    socket.send(&call).await?;
+    count_sent(call.len());
+
+    let mut buf = [0u8; 512];
+    let deadline = Duration::from_millis(timeout_ms);
+    loop {
+        let remaining = deadline
+            .checked_sub(start.elapsed())
+            .ok_or_else(|| ProbeFailure::new("timeout", format!("RPC NULL call to {} timed out", addr)))?;
+        let n = timeout(remaining, socket.recv(&mut buf)).await??;
+        let elapsed = start.elapsed();
+        count_received(n);
+        // Replies to earlier, timed-out calls carry a different xid
+        if let Some(result) = check_reply(&buf[..n], xid) {
+            return result.map(|_| (elapsed, addr.ip()));
+        }
+    }
+}
+
+async fn rpc_null_tcp(
+    host: &str,
+    port: u16,
+    program: u32,
+    version: u32,
+    timeout_ms: u64,
+    sockopts: SocketOptions,
+) -> Result<(Duration, IpAddr)> {
+    let addr = format!("{}:{}", host, port);
+    let deadline = Duration::from_millis(timeout_ms);
+    let mut stream = timeout(deadline, sockopts.connect_tcp(host, port)).await??;
+    let peer = stream.peer_addr()?.ip();
+
+    let xid = random_u64() as u32;
+    let call = null_call(xid, program, version);
+    // Record marking: single last fragment
+    let mut frame = (LAST_FRAGMENT | call.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&call);
+
+    let start = Instant::now();
+    let exchange = async {
+        stream.write_all(&frame).await?;
+        count_sent(frame.len());
+        let mut marker = [0u8; 4];
+        stream.read_exact(&mut marker).await?;
+        let len = (u32::from_be_bytes(marker) & !LAST_FRAGMENT) as usize;
+        let mut reply = vec![0u8; len.min(4096)];
+        stream.read_exact(&mut reply).await?;
+        count_received(marker.len() + reply.len());
+        Ok::<_, anyhow::Error>(reply)
+    };
+    let reply = timeout(deadline, exchange)
+        .await
+        .map_err(|_| ProbeFailure::new("timeout", format!("RPC NULL call to {} timed out", addr)))??;
+    let elapsed = start.elapsed();
+
+    check_reply(&reply, xid)
+        .ok_or_else(|| anyhow::anyhow!("unexpected RPC reply from {}", addr))?
+        .map(|_| (elapsed, peer))
+}
+
+fn null_call(xid: u32, program: u32, version: u32) -> Vec<u8> {
+    let words = [
+        xid,
+        MSG_CALL,
+        RPC_VERSION,
+        program,
+        version,
+        PROC_NULL,
+        0, 0, // credentials: AUTH_NONE
+        0, 0, // verifier: AUTH_NONE
+    ];
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+/// `None` if the datagram is not a reply to `xid`
+fn check_reply(buf: &[u8], xid: u32) -> Option<Result<()>> {
+    let word = |i: usize| {
+        buf.get(i * 4..i * 4 + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if word(0)? != xid || word(1)? != MSG_REPLY {
+        return None;
+    }
+    if word(2)? != MSG_ACCEPTED {
+        return Some(Err(anyhow::anyhow!("RPC call denied by server")));
+    }
+    // Skip the verifier (flavor, length, opaque body padded to 4 bytes)
+    let verf_len = word(4)? as usize;
+    let accept_stat = word(5 + verf_len.div_ceil(4))?;
+    match accept_stat {
+        ACCEPT_SUCCESS | ACCEPT_PROG_MISMATCH => Some(Ok(())),
+        other => Some(Err(anyhow::anyhow!("RPC call not accepted (accept_stat {})", other))),
+    }
+}
@@ -1,13 +1,321 @@
 use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use socket2::SockRef;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::time::{timeout, Duration, Instant};
+use tokio::time::{timeout_at, Duration, Instant};
+use crate::config::TargetConfig;
+use super::{count_received, count_sent, FastOpenDetails, ProbeDetails, ProbeFailure, ProbeOutcome, SocketOptions, TargetAddress, TcpDetails};
 
-pub async fn probe_tcp(host: &str, port: u16) -> Result<Duration> {
-    let addr = format!("{}:{}", host, port);
+pub const DEFAULT_PAYLOAD: &str = "\r\n";
+
+// tcpi_options flag: the SYN carried data and the server acknowledged it
+#[cfg(target_os = "linux")]
+const TCPI_OPT_SYN_DATA: u8 = 32;
+
+// How often a persistent connection is checked for the acknowledgement
+const ACK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How a TCP target is measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpMode {
+    /// Open (and close) a connection every probe: measures connection setup
+    #[default]
+    Connect,
+    /// Keep one connection open and time small writes on it: measures the
+    /// steady-state RTT without a SYN per probe
+    Persistent,
+}
+
+/// How a connect probe closes its connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpClose {
+    /// Regular FIN handshake; the probe host keeps the socket in TIME_WAIT
+    #[default]
+    Fin,
+    /// Abort with a RST (SO_LINGER 0): no TIME_WAIT on either end
+    Rst,
+}
+
+// Open connections of persistent-mode targets, by target name and address
+// (with probe_all_addresses). A connection is taken out while in use and only
+// put back if its probe succeeded.
+static CONNECTIONS: Lazy<Mutex<HashMap<TargetAddress, Connection>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Connection {
+    // Host and port the connection was opened to
+    host: String,
+    port: u16,
+    stream: TcpStream,
+}
+
+/// Close the connections of targets that were removed, switched to another
+/// mode or moved to another host or port, and of addresses they no longer
+/// resolve to
+pub fn retain_targets(targets: &[TargetConfig]) {
+    CONNECTIONS.lock().unwrap().retain(|(name, address), conn| {
+        super::is_current(targets, name, *address, "tcp_persistent")
+            && targets.iter().any(|t| {
+                t.name == *name
+                    && t.port.unwrap_or(t.kind.default_port()) == conn.port
+                    && (address.is_some() || t.host == conn.host)
+            })
+    });
+}
+
+pub async fn probe_tcp(
+    host: &str,
+    port: u16,
+    timeout_ms: u64,
+    close: TcpClose,
+    include_teardown: bool,
+    sockopts: SocketOptions,
+) -> Result<ProbeOutcome> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let (start, conn) = connect(host, port, deadline, &sockopts).await?;
+    let mut elapsed = start.elapsed();
+    let tcp = kernel_rtt(&conn);
+    let address = conn.peer_addr().ok().map(|a| a.ip());
+    match close {
+        TcpClose::Rst => {
+            SockRef::from(&conn).set_linger(Some(Duration::ZERO))?;
+            drop(conn);
+        }
+        TcpClose::Fin if include_teardown => {
+            close_gracefully(conn, deadline).await?;
+        }
+        TcpClose::Fin => drop(conn),
+    }
+    // With a RST, or a FIN nobody waits for, closing takes no round trip
+    if include_teardown {
+        elapsed = start.elapsed();
+    }
+    Ok(ProbeOutcome { latency: elapsed, details: ProbeDetails { address, tcp, ..Default::default() } })
+}
+
+/// Send our FIN and wait for the peer's
+async fn close_gracefully(mut conn: TcpStream, deadline: Instant) -> Result<()> {
+    conn.shutdown().await?;
+    let mut discard = [0u8; 4096];
+    loop {
+        match tokio::time::timeout_at(deadline, conn.read(&mut discard)).await {
+            Ok(Ok(0)) => return Ok(()),
+            Ok(Ok(n)) => count_received(n),
+            Ok(Err(e)) => return Err(connection_lost(e)),
+            Err(_) => return Err(ProbeFailure::new("timeout", "peer did not close the connection before the timeout").into()),
+        }
+    }
+}
+
+/// Time connect + `payload` acknowledged with TCP Fast Open, after doing the
+/// same over a regular connection for comparison. The kernel only has a
+/// cookie for the server from the second Fast Open attempt on, so the first
+/// probe of a target never sees its data accepted.
+pub async fn probe_tcp_fast_open(
+    host: &str,
+    port: u16,
+    timeout_ms: u64,
+    payload: &str,
+    sockopts: SocketOptions,
+) -> Result<ProbeOutcome> {
+    let (regular, _) = timed_exchange(host, port, timeout_ms, payload.as_bytes(), &sockopts).await?;
+    let fast_open_opts = SocketOptions { fast_open: true, ..sockopts };
+    let (latency, conn) = timed_exchange(host, port, timeout_ms, payload.as_bytes(), &fast_open_opts).await?;
+    let mut tcp = kernel_rtt(&conn).unwrap_or_default();
+    tcp.fast_open = Some(FastOpenDetails { accepted: syn_data_acked(&conn), regular });
+    let address = conn.peer_addr().ok().map(|a| a.ip());
+    Ok(ProbeOutcome { latency, details: ProbeDetails { address, tcp: Some(tcp), ..Default::default() } })
+}
+
+/// Connect and send `payload`, timed until it is acknowledged
+async fn timed_exchange(
+    host: &str,
+    port: u16,
+    timeout_ms: u64,
+    payload: &[u8],
+    sockopts: &SocketOptions,
+) -> Result<(Duration, TcpStream)> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let (start, mut conn) = connect(host, port, deadline, sockopts).await?;
+    round_trip(&mut conn, payload, deadline).await?;
+    Ok((start.elapsed(), conn))
+}
+
+/// Write `payload` on the target's open connection and time it until it is
+/// acknowledged; the kernel's smoothed RTT is only reported as a detail. A
+/// connection the peer has closed is replaced by a new one within the same
+/// probe, before the same deadline.
+pub async fn probe_tcp_persistent(
+    target: &str,
+    address: Option<IpAddr>,
+    host: &str,
+    port: u16,
+    timeout_ms: u64,
+    payload: &str,
+    sockopts: SocketOptions,
+) -> Result<ProbeOutcome> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let key = (target.to_string(), address);
+    // A connection to where the target pointed before a reload is not reused
+    let cached = CONNECTIONS.lock().unwrap().remove(&key).filter(|c| c.host == host && c.port == port);
+    let mut conn = match cached {
+        Some(mut conn) => match round_trip(&mut conn.stream, payload.as_bytes(), deadline).await {
+            Ok((latency, tcp)) => return Ok(keep(key, conn, latency, tcp)),
+            Err(e) => {
+                tracing::debug!("persistent TCP connection to {}:{} dropped: {:#}", host, port, e);
+                connect(host, port, deadline, &sockopts).await?.1
+            }
+        },
+        None => connect(host, port, deadline, &sockopts).await?.1,
+    };
+    let (latency, tcp) = round_trip(&mut conn, payload.as_bytes(), deadline).await?;
+    Ok(keep(key, Connection { host: host.to_string(), port, stream: conn }, latency, tcp))
+}
+
+fn keep(key: TargetAddress, conn: Connection, latency: Duration, tcp: TcpDetails) -> ProbeOutcome {
+    let address = conn.stream.peer_addr().ok().map(|a| a.ip());
+    CONNECTIONS.lock().unwrap().insert(key, conn);
+    ProbeOutcome { latency, details: ProbeDetails { address, tcp: Some(tcp), ..Default::default() } }
+}
+
+/// Resolve `host` and connect before `deadline`. Also returns when the
+/// connection attempt started, so that probes time the network and not the
+/// resolver, whose time is counted separately.
+async fn connect(host: &str, port: u16, deadline: Instant, sockopts: &SocketOptions) -> Result<(Instant, TcpStream)> {
+    let connecting = async {
+        let addrs = super::resolve(host, sockopts.family).await?;
+        let start = Instant::now();
+        Ok((start, sockopts.connect_tcp_to(host, addrs, port).await?))
+    };
+    match timeout_at(deadline, connecting).await {
+        Ok(Ok(conn)) => Ok(conn),
+        Ok(Err(e)) => Err(classify(e, host, port)),
+        Err(_) => Err(ProbeFailure::new("timeout", format!("TCP connect to {}:{} did not complete before the timeout", host, port)).into()),
+    }
+}
+
+/// Failure reason of a connect error. A refused connection means the host
+/// answered, unreachable ones that a router (or the local stack) gave up on it.
+fn classify(err: anyhow::Error, host: &str, port: u16) -> anyhow::Error {
+    let reason = match err.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+        Some(ErrorKind::ConnectionRefused) => "refused",
+        Some(ErrorKind::NetworkUnreachable) => "network_unreachable",
+        Some(ErrorKind::HostUnreachable) => "host_unreachable",
+        Some(ErrorKind::TimedOut) => "timeout",
+        _ => return err,
+    };
+    ProbeFailure::new(reason, format!("TCP connect to {}:{} failed: {}", host, port, err)).into()
+}
+
+/// Send `payload` and wait until the peer has acknowledged everything sent,
+/// returning how long that took. Whatever the peer sent meanwhile is
+/// discarded so its window never fills.
+async fn round_trip(conn: &mut TcpStream, payload: &[u8], deadline: Instant) -> Result<(Duration, TcpDetails)> {
+    let mut discard = [0u8; 4096];
+    loop {
+        match conn.try_read(&mut discard) {
+            Ok(0) => return Err(ProbeFailure::new("closed", "connection closed by peer").into()),
+            Ok(n) => count_received(n),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(connection_lost(e)),
+        }
+    }
     let start = Instant::now();
-    let conn_fut = TcpStream::connect(addr);
-    let conn = timeout(Duration::from_millis(3000), conn_fut).await??;
-    drop(conn);
-    let elapsed = start.elapsed();
-    Ok(elapsed)
+    conn.write_all(payload).await.map_err(connection_lost)?;
+    count_sent(payload.len());
+    loop {
+        let (unacked, tcp) = ack_state(conn)?;
+        if unacked == 0 {
+            return Ok((start.elapsed(), tcp));
+        }
+        if Instant::now() >= deadline {
+            return Err(ProbeFailure::new("timeout", "TCP payload not acknowledged before the timeout").into());
+        }
+        tokio::time::sleep(ACK_POLL_INTERVAL).await;
+    }
+}
+
+fn connection_lost(err: std::io::Error) -> anyhow::Error {
+    match err.kind() {
+        ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => ProbeFailure::new("reset", format!("connection reset by peer: {}", err)).into(),
+        _ => err.into(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_info(stream: &TcpStream) -> std::io::Result<libc::tcp_info> {
+    use std::os::fd::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: info and len describe a writable tcp_info of the advertised size
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+#[cfg(target_os = "linux")]
+fn details_of(info: &libc::tcp_info) -> TcpDetails {
+    TcpDetails {
+        srtt: Duration::from_micros(info.tcpi_rtt as u64),
+        rttvar: Duration::from_micros(info.tcpi_rttvar as u64),
+        fast_open: None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn syn_data_acked(stream: &TcpStream) -> bool {
+    tcp_info(stream).is_ok_and(|info| info.tcpi_options & TCPI_OPT_SYN_DATA != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn syn_data_acked(_stream: &TcpStream) -> bool {
+    false
+}
+
+/// RTT the kernel measured for the handshake, unaffected by how late this
+/// task got scheduled after the connection completed
+#[cfg(target_os = "linux")]
+fn kernel_rtt(stream: &TcpStream) -> Option<TcpDetails> {
+    match tcp_info(stream) {
+        Ok(info) => Some(details_of(&info)),
+        Err(e) => {
+            tracing::debug!("TCP_INFO unavailable: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_rtt(_stream: &TcpStream) -> Option<TcpDetails> {
+    None
+}
+
+/// Segments still awaiting an acknowledgement, and the current RTT estimate
+#[cfg(target_os = "linux")]
+fn ack_state(stream: &TcpStream) -> Result<(u32, TcpDetails)> {
+    let info = tcp_info(stream)?;
+    Ok((info.tcpi_unacked, details_of(&info)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ack_state(_stream: &TcpStream) -> Result<(u32, TcpDetails)> {
+    anyhow::bail!("persistent TCP probes need TCP_INFO, which is only available on Linux")
 }
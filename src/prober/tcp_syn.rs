@@ -0,0 +1,181 @@
+use anyhow::Result;
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{timeout, Duration, Instant};
+use crate::util::{internet_checksum, random_u64, unspecified_for};
+use super::{count_received, count_sent, resolve_one, ProbeFailure, SocketOptions};
+
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_ACK: u8 = 0x10;
+const TCP_HEADER_LEN: usize = 20;
+const MSS_OPTION: [u8; 4] = [2, 4, 0x05, 0xb4]; // MSS 1460
+
+/// Send a bare SYN over a raw socket and measure the time to the SYN-ACK,
+/// then reset the half-open connection. Requires CAP_NET_RAW.
+/// The target never sees a completed handshake, so its accept queue is untouched.
+pub async fn probe_tcp_syn(host: &str, port: u16, timeout_ms: u64, sockopts: SocketOptions) -> Result<(Duration, IpAddr)> {
+    let dst_ip = resolve_one(host, sockopts.family).await?;
+    let src_ip = local_ip_for(SocketAddr::new(dst_ip, port))?;
+    let src_port = 32768 + (random_u64() % 28_000) as u16;
+    let seq = random_u64() as u32;
+
+    let domain = if dst_ip.is_ipv4() { Domain::IPV4 } else { Domain::IPV6 };
+    let socket = Socket::new(domain, Type::RAW, Some(Protocol::TCP))
+        .map_err(|e| anyhow::anyhow!("tcp_syn probe needs a raw socket (CAP_NET_RAW): {}", e))?;
+    socket.set_nonblocking(true)?;
+    sockopts.apply(SockRef::from(&socket))?;
+    let socket = AsyncFd::new(socket)?;
+    // Raw sockets carry no port; for IPv6 a non-zero port would be read as the protocol
+    let dst = SockAddr::from(SocketAddr::new(dst_ip, 0));
+
+    let syn = build_segment(src_ip, src_port, dst_ip, port, seq, 0, FLAG_SYN);
+    let start = Instant::now();
+    send_to(&socket, &syn, &dst).await?;
+
+    let deadline = Duration::from_millis(timeout_ms);
+    let reply = timeout(deadline, wait_reply(&socket, dst_ip, port, src_port, seq))
+        .await
+        .map_err(|_| ProbeFailure::new("timeout", format!("no SYN-ACK from {}:{} within {} ms", dst_ip, port, timeout_ms)))??;
+    let elapsed = start.elapsed();
+
+    match reply {
+        Reply::SynAck { their_seq } => {
+            let rst = build_segment(src_ip, src_port, dst_ip, port, seq.wrapping_add(1), their_seq.wrapping_add(1), FLAG_RST | FLAG_ACK);
+            if let Err(e) = send_to(&socket, &rst, &dst).await {
+                tracing::debug!("failed to send RST to {}:{}: {}", dst_ip, port, e);
+            }
+            Ok((elapsed, dst_ip))
+        }
+        Reply::Rst => Err(anyhow::anyhow!("{}:{} answered SYN with RST (port closed)", dst_ip, port)),
+    }
+}
+
+enum Reply {
+    SynAck { their_seq: u32 },
+    Rst,
+}
+
+/// Source address the kernel would pick for this destination, needed for the
+/// TCP checksum pseudo-header
+fn local_ip_for(dst: SocketAddr) -> Result<IpAddr> {
+    let udp = std::net::UdpSocket::bind(unspecified_for(dst.ip()))?;
+    udp.connect(dst)?;
+    Ok(udp.local_addr()?.ip())
+}
+
+async fn send_to(socket: &AsyncFd<Socket>, buf: &[u8], dst: &SockAddr) -> Result<()> {
+    loop {
+        let mut guard = socket.writable().await?;
+        match guard.try_io(|inner| inner.get_ref().send_to(buf, dst)) {
+            Ok(result) => return result.map(|_| count_sent(buf.len())).map_err(Into::into),
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Read raw TCP segments until one answers our SYN
+async fn wait_reply(
+    socket: &AsyncFd<Socket>,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    src_port: u16,
+    seq: u32,
+) -> Result<Reply> {
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1500];
+    loop {
+        let mut guard = socket.readable().await?;
+        let (n, from) = match guard.try_io(|inner| inner.get_ref().recv_from(&mut buf)) {
+            Ok(result) => result?,
+            Err(_would_block) => continue,
+        };
+        if from.as_socket().map(|a| a.ip()) != Some(dst_ip) {
+            continue;
+        }
+        // SAFETY: recv_from initialised the first n bytes
+        let packet = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n) };
+        // IPv4 raw sockets deliver the IP header, IPv6 ones don't
+        let tcp = if dst_ip.is_ipv4() {
+            match packet.first() {
+                Some(b) => &packet[((b & 0x0f) as usize * 4).min(n)..],
+                None => continue,
+            }
+        } else {
+            packet
+        };
+        if tcp.len() < TCP_HEADER_LEN {
+            continue;
+        }
+
+        let their_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+        let our_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+        let their_seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+        let ack = u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]);
+        let flags = tcp[13];
+        if their_port != dst_port || our_port != src_port || ack != seq.wrapping_add(1) {
+            continue;
+        }
+        count_received(tcp.len());
+        if flags & FLAG_RST != 0 {
+            return Ok(Reply::Rst);
+        }
+        if flags & (FLAG_SYN | FLAG_ACK) == FLAG_SYN | FLAG_ACK {
+            return Ok(Reply::SynAck { their_seq });
+        }
+    }
+}
+
+fn build_segment(
+    src_ip: IpAddr,
+    src_port: u16,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+) -> Vec<u8> {
+    let options: &[u8] = if flags & FLAG_SYN != 0 { &MSS_OPTION } else { &[] };
+    let header_len = TCP_HEADER_LEN + options.len();
+
+    let mut seg = Vec::with_capacity(header_len);
+    seg.extend_from_slice(&src_port.to_be_bytes());
+    seg.extend_from_slice(&dst_port.to_be_bytes());
+    seg.extend_from_slice(&seq.to_be_bytes());
+    seg.extend_from_slice(&ack.to_be_bytes());
+    seg.push(((header_len / 4) as u8) << 4);
+    seg.push(flags);
+    seg.extend_from_slice(&64240u16.to_be_bytes()); // window
+    seg.extend_from_slice(&[0, 0]); // checksum, filled below
+    seg.extend_from_slice(&[0, 0]); // urgent pointer
+    seg.extend_from_slice(options);
+
+    let checksum = tcp_checksum(src_ip, dst_ip, &seg);
+    seg[16..18].copy_from_slice(&checksum.to_be_bytes());
+    seg
+}
+
+fn tcp_checksum(src: IpAddr, dst: IpAddr, segment: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(40 + segment.len());
+    match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&[0, libc::IPPROTO_TCP as u8]);
+            pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        (s, d) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            pseudo.extend_from_slice(&to_v6(s).octets());
+            pseudo.extend_from_slice(&to_v6(d).octets());
+            pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, libc::IPPROTO_TCP as u8]);
+        }
+    }
+    pseudo.extend_from_slice(segment);
+    internet_checksum(&pseudo)
+}
@@ -0,0 +1,66 @@
+// Min, max and average latency per target over sliding windows, recomputed
+// from the latency history. Three series per window instead of a histogram's
+// buckets, for teams that need more than the last sample but can't afford
+// the histogram's cardinality.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use crate::config::{ProbeConfig, TargetConfig};
+use crate::history;
+use crate::metrics::{self, LatencyRollup};
+use crate::prober;
+use crate::timestamp::monotonic_ns;
+use crate::util::window_label;
+
+// How often the gauges are recomputed
+const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub async fn run(config: Arc<RwLock<ProbeConfig>>) {
+    let mut update = tokio::time::interval(UPDATE_INTERVAL);
+    loop {
+        update.tick().await;
+        // Re-read each round so enabling or changing windows via reload takes effect
+        let (windows, targets) = {
+            let config = config.read().await;
+            let windows = config.latency_rollup.as_ref().map(|r| r.windows_sec.clone()).unwrap_or_default();
+            (windows, config.targets.clone())
+        };
+        let rollups = if windows.is_empty() { Vec::new() } else { compute(&windows, &targets) };
+        metrics::set_latency_rollups(&rollups);
+    }
+}
+
+fn compute(windows_sec: &[u64], targets: &[TargetConfig]) -> Vec<LatencyRollup> {
+    let now = monotonic_ns();
+    let mut rollups = Vec::new();
+    for ((target, address, probe_type), samples) in history::snapshot() {
+        // Removed or reconfigured, or an address DNS rotated away
+        if !prober::is_current(targets, &target, address, probe_type) {
+            continue;
+        }
+        for &window_sec in windows_sec {
+            let since = now.saturating_sub(Duration::from_secs(window_sec).as_nanos());
+            let (mut min, mut max, mut sum, mut count) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0u64);
+            for latency in samples.iter().filter(|s| s.timestamp_ns >= since).filter_map(|s| s.latency_ms) {
+                min = min.min(latency);
+                max = max.max(latency);
+                sum += latency;
+                count += 1;
+            }
+            // No successful probe in the window: no series rather than a made-up value
+            if count > 0 {
+                rollups.push(LatencyRollup {
+                    target: target.clone(),
+                    address,
+                    probe_type,
+                    window: window_label(window_sec),
+                    min_ms: min,
+                    max_ms: max,
+                    avg_ms: sum / count as f64,
+                });
+            }
+        }
+    }
+    rollups
+}
@@ -0,0 +1,50 @@
+// Tokio runtime gauges, read from the runtime when the registry is gathered.
+// Alongside the process collector they show whether a probe host with many
+// targets is short of workers or piling up tasks.
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntGauge, Opts};
+use tokio::runtime::Handle;
+
+pub struct RuntimeCollector {
+    handle: Handle,
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+}
+
+impl RuntimeCollector {
+    /// Collector for the runtime `handle` belongs to
+    pub fn new(handle: Handle) -> prometheus::Result<Self> {
+        Ok(Self {
+            handle,
+            workers: IntGauge::with_opts(Opts::new("tokio_workers", "Worker threads of the runtime"))?,
+            alive_tasks: IntGauge::with_opts(Opts::new("tokio_alive_tasks", "Tasks spawned and not finished yet"))?,
+            global_queue_depth: IntGauge::with_opts(Opts::new(
+                "tokio_global_queue_depth",
+                "Tasks waiting in the runtime's global queue for a worker",
+            ))?,
+        })
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [&self.workers, &self.alive_tasks, &self.global_queue_depth]
+            .into_iter()
+            .flat_map(|gauge| gauge.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let metrics = self.handle.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth.set(metrics.global_queue_depth() as i64);
+        [&self.workers, &self.alive_tasks, &self.global_queue_depth]
+            .into_iter()
+            .flat_map(|gauge| gauge.collect())
+            .collect()
+    }
+}
@@ -1,30 +1,215 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::{sleep_until, Instant};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant, MissedTickBehavior};
 use anyhow::Result;
+use crate::config::{ProbeConfig, ProbeSpreadConfig, SpreadMode, TargetConfig};
+use crate::{health, metrics};
 
+// A target is identified by name and probe type, as in the metrics
+type TargetKey = (String, &'static str);
+
+#[derive(Clone, Copy)]
 pub struct Scheduler {
+    // Interval of targets without their own interval_ms, and of the checks
+    // for added targets
     interval: Duration,
+    // Rounds are counted from here, so that targets sharing an interval stay
+    // in step however late their loops started
+    epoch: Instant,
+}
+
+struct TargetLoop {
+    handle: JoinHandle<()>,
+    // Set once a probe of the target has returned, whatever its result
+    probed: Arc<AtomicBool>,
 }
 
 impl Scheduler {
     pub fn new(interval_ms: u64) -> Result<Self> {
+        anyhow::ensure!(interval_ms > 0, "the probe interval must be greater than 0");
         Ok(Self {
             interval: Duration::from_millis(interval_ms),
+            epoch: Instant::now(),
         })
     }
 
-    /// job: async closure for each tick
-    pub async fn run<J, F>(&self, mut job: J) -> Result<()>
+    /// Probe every enabled target on its own timer: `job` runs once per round
+    /// of the target, with the target's interval, and tells whether any probe
+    /// of the round succeeded (None when none ran). The target list is
+    /// checked every interval; targets added by a reload get a loop of their
+    /// own, and a loop ends when its target is removed or disabled.
+    pub async fn run<J, F>(
+        &self,
+        targets: Arc<RwLock<Vec<TargetConfig>>>,
+        config: Arc<RwLock<ProbeConfig>>,
+        job: J,
+    ) -> Result<()>
     where
-        J: FnMut() -> F + Send + 'static,
-        F: std::future::Future<Output = ()> + Send + 'static,
+        J: Fn(TargetConfig, Duration) -> F + Send + Sync + 'static,
+        F: Future<Output = Option<bool>> + Send + 'static,
+    {
+        let job = Arc::new(job);
+        let mut loops: HashMap<TargetKey, TargetLoop> = HashMap::new();
+        let mut check = tokio::time::interval_at(self.epoch, self.interval);
+        check.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            let checked_at = check.tick().await;
+            health::scheduler_ticked(self.interval);
+            let enabled: Vec<TargetKey> = targets
+                .read()
+                .await
+                .iter()
+                .filter(|t| t.enabled.unwrap_or(true))
+                .map(key)
+                .collect();
+            loops.retain(|_, l| !l.handle.is_finished());
+            for key in enabled {
+                if loops.contains_key(&key) {
+                    continue;
+                }
+                let probed = Arc::new(AtomicBool::new(false));
+                let handle = tokio::spawn(self.target_loop(
+                    key.clone(),
+                    checked_at,
+                    targets.clone(),
+                    config.clone(),
+                    job.clone(),
+                    probed.clone(),
+                ));
+                loops.insert(key, TargetLoop { handle, probed });
+            }
+            // For /readyz
+            if loops.values().all(|l| l.probed.load(Ordering::Relaxed)) {
+                health::cycle_completed();
+            }
+        }
+    }
+
+    async fn target_loop<J, F>(
+        self,
+        key: TargetKey,
+        // When the target was found in the list, as scheduled
+        found_at: Instant,
+        targets: Arc<RwLock<Vec<TargetConfig>>>,
+        config: Arc<RwLock<ProbeConfig>>,
+        job: Arc<J>,
+        probed: Arc<AtomicBool>,
+    ) where
+        J: Fn(TargetConfig, Duration) -> F + Send + Sync + 'static,
+        F: Future<Output = Option<bool>> + Send + 'static,
     {
-        let mut next = Instant::now();
+        // Start of the target's current round; probes start at an offset into it
+        let mut round = None;
+        // Start of the last probed round, and how many rounds in a row failed
+        let mut last_probe: Option<Instant> = None;
+        let failures = Arc::new(AtomicU32::new(0));
         loop {
-            next += self.interval;
-            // spawn job so next tick unaffected by job duration
-            tokio::spawn(job());
-            sleep_until(next).await;
+            // Interval, offset and the target itself are read every round, so a
+            // reload applies from the next probe on
+            let spread = config.read().await.probe_spread.clone();
+            let Some((target, index, count)) = current(&targets, &key).await else {
+                return;
+            };
+            // A zero interval would never advance the round and probe in a
+            // tight loop; config validation rejects it, this keeps it that way
+            let interval = target
+                .interval_ms
+                .map(Duration::from_millis)
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(self.interval);
+            let offset = match target.offset_ms {
+                Some(offset_ms) => Duration::from_millis(offset_ms),
+                None => start_offset(index, count, interval, spread.as_ref()),
+            };
+            let round_start = *round.get_or_insert_with(|| {
+                // The first round is the current one if the offset was still
+                // ahead when the target was found
+                let elapsed = found_at.duration_since(self.epoch).as_nanos();
+                let start = self.epoch + Duration::from_nanos((elapsed - elapsed % interval.as_nanos().max(1)) as u64);
+                if start + offset < found_at { start + interval } else { start }
+            });
+            let start = round_start + offset;
+            sleep_until(start).await;
+
+            let Some((target, ..)) = current(&targets, &key).await else {
+                return;
+            };
+            // How late this probe is; on an overloaded host this is our own
+            // delay, not the network's
+            let drift = Instant::now().saturating_duration_since(start);
+            // Rounds missed entirely are skipped rather than run back to back
+            let skipped = (drift.as_nanos() / interval.as_nanos().max(1)) as u32;
+            metrics::observe_scheduler_tick(drift, skipped);
+            round = Some(round_start + interval * (skipped + 1));
+            // Backing off: rounds are still counted at the full rate, only
+            // probed less often, so the first success restores the rate.
+            // Failures count once their round finishes, so with a timeout
+            // above the interval a few more rounds start before it begins.
+            let backoff = target.backoff.as_ref().map(|b| b.interval(interval, failures.load(Ordering::Relaxed)));
+            metrics::set_backoff_interval(&key.0, key.1, backoff);
+            if let Some(backoff) = backoff
+                && let Some(last) = last_probe
+                && start + interval / 2 < last + backoff
+            {
+                metrics::round_backed_off(key.1);
+                continue;
+            }
+            last_probe = Some(start);
+            metrics::round_started(key.1);
+            // Spawned so that a slow probe doesn't hold up the next round
+            let run = job(target, interval);
+            let probed = probed.clone();
+            let failures = failures.clone();
+            tokio::spawn(async move {
+                match run.await {
+                    Some(true) => failures.store(0, Ordering::Relaxed),
+                    Some(false) => {
+                        failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // Shed, neither success nor failure
+                    None => {}
+                }
+                probed.store(true, Ordering::Relaxed);
+            });
         }
     }
 }
+
+fn key(target: &TargetConfig) -> TargetKey {
+    (target.name.clone(), target.probe_type())
+}
+
+/// The target as configured now, with its position among the enabled
+/// targets and their number; None once it was removed or disabled
+async fn current(targets: &RwLock<Vec<TargetConfig>>, key: &TargetKey) -> Option<(TargetConfig, usize, usize)> {
+    let targets = targets.read().await;
+    let enabled: Vec<&TargetConfig> = targets.iter().filter(|t| t.enabled.unwrap_or(true)).collect();
+    let index = enabled.iter().position(|t| t.name == key.0 && t.probe_type() == key.1)?;
+    Some((enabled[index].clone(), index, enabled.len()))
+}
+
+/// How far into its round the probe of the `index`th of `count` targets
+/// starts. At the start of the round without a `probe_spread` block;
+/// otherwise within `spread_percent` of the interval, so the targets don't
+/// hit the network, and the host, as one burst.
+pub fn start_offset(index: usize, count: usize, interval: Duration, spread: Option<&ProbeSpreadConfig>) -> Duration {
+    let Some(spread) = spread else {
+        return Duration::ZERO;
+    };
+    // Validated on load; clamped anyway since mul_f64 panics on negative,
+    // NaN and huge factors
+    let percent = if spread.spread_percent.is_nan() { 0.0 } else { spread.spread_percent.clamp(0.0, 100.0) };
+    let window = interval.mul_f64(percent / 100.0);
+    match spread.mode {
+        SpreadMode::Even => window.mul_f64(index as f64 / count.max(1) as f64),
+        // A fresh hasher key every call; good enough as a random source here
+        SpreadMode::Random => window.mul_f64(RandomState::new().hash_one(index) as f64 / u64::MAX as f64),
+    }
+}
@@ -0,0 +1,124 @@
+// Secrets referenced from the config and fetched from HashiCorp Vault when a
+// probe needs them. Each secret path is read at most once per
+// VAULT_SECRET_TTL_SECONDS, so rotated values are picked up without a reload.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+// After a failed read, a secret path isn't asked for again for this long;
+// probes meanwhile get the cached value, or the same error
+const VAULT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct CachedSecret {
+    // The last value read, and when
+    data: Option<(Instant, Value)>,
+    // The last failed read since then, and when
+    failure: Option<(Instant, String)>,
+}
+
+struct Vault {
+    client: Client,
+    // e.g. https://vault.internal:8200
+    addr: String,
+    ttl: Duration,
+    // One lock per path, held while that path is read: concurrent probes
+    // wait for the one read instead of each asking Vault, and other paths
+    // aren't held up by it
+    cache: std::sync::Mutex<HashMap<String, Arc<Mutex<CachedSecret>>>>,
+}
+
+static VAULT: Lazy<Result<Vault, String>> = Lazy::new(|| Vault::from_env().map_err(|e| format!("{:#}", e)));
+
+impl Vault {
+    /// VAULT_ADDR, VAULT_SECRET_TTL_SECONDS (default 300); the token is read per request
+    fn from_env() -> Result<Self> {
+        let addr = std::env::var("VAULT_ADDR").context("VAULT_ADDR is not set")?;
+        let ttl_sec: u64 = std::env::var("VAULT_SECRET_TTL_SECONDS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse()
+            .unwrap_or(300);
+        Ok(Self {
+            client: Client::builder().timeout(Duration::from_secs(10)).build()?,
+            addr: addr.trim_end_matches('/').to_string(),
+            ttl: Duration::from_secs(ttl_sec),
+            cache: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// VAULT_TOKEN, or the file named by VAULT_TOKEN_FILE (e.g. a Vault Agent
+    /// sink), re-read every time so a renewed token is used
+    async fn token() -> Result<String> {
+        if let Ok(file) = std::env::var("VAULT_TOKEN_FILE") {
+            return Ok(tokio::fs::read_to_string(&file).await
+                .with_context(|| format!("reading VAULT_TOKEN_FILE {}", file))?
+                .trim()
+                .to_string());
+        }
+        std::env::var("VAULT_TOKEN").context("neither VAULT_TOKEN nor VAULT_TOKEN_FILE is set")
+    }
+
+    async fn read(&self, path: &str) -> Result<Value> {
+        let mut request = self.client
+            .get(format!("{}/v1/{}", self.addr, path.trim_start_matches('/')))
+            .header("X-Vault-Token", Self::token().await?);
+        if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let body: Value = request.send().await?.error_for_status()?.json().await?;
+        // KV version 2 nests the secret one level deeper than version 1
+        let data = &body["data"];
+        Ok(match &data["data"] {
+            Value::Object(_) if data.get("metadata").is_some() => data["data"].clone(),
+            _ => data.clone(),
+        })
+    }
+
+    async fn field(&self, path: &str, field: &str) -> Result<String> {
+        let entry = self.cache.lock().unwrap().entry(path.to_string()).or_default().clone();
+        let mut cached = entry.lock().await;
+        let fresh = cached.data.as_ref().is_some_and(|(fetched, _)| fetched.elapsed() < self.ttl);
+        let failed_lately = cached.failure.as_ref().is_some_and(|(failed, _)| failed.elapsed() < VAULT_RETRY_DELAY);
+        if !fresh && !failed_lately {
+            match self.read(path).await {
+                Ok(data) => {
+                    cached.data = Some((Instant::now(), data));
+                    cached.failure = None;
+                }
+                Err(e) => {
+                    let e = format!("reading Vault secret {}: {:#}", path, e);
+                    // Vault being unreachable shouldn't fail probes that still
+                    // have the previous value
+                    if cached.data.is_some() {
+                        tracing::warn!("{}, using the cached value", e);
+                    }
+                    cached.failure = Some((Instant::now(), e));
+                }
+            }
+        }
+        let data = match (&cached.data, &cached.failure) {
+            (Some((_, data)), _) => data,
+            (None, Some((_, e))) => anyhow::bail!("{}", e),
+            (None, None) => anyhow::bail!("Vault secret {} missing from cache", path),
+        };
+        match &data[field] {
+            Value::String(value) => Ok(value.clone()),
+            Value::Null => anyhow::bail!("Vault secret {} has no field {}", path, field),
+            other => Ok(other.to_string()),
+        }
+    }
+}
+
+/// `field` of the Vault secret at `path`, e.g. `secret/data/latency-probe/api`
+pub async fn vault_field(path: &str, field: &str) -> Result<String> {
+    match &*VAULT {
+        Ok(vault) => vault.field(path, field).await,
+        Err(e) => anyhow::bail!("Vault is not configured: {}", e),
+    }
+}
@@ -0,0 +1,107 @@
+// Echo responder for `echo` probes, so a probe mesh needs nothing but
+// latency-probe on both ends. UDP datagrams are sent back to their source,
+// with our receive and send times filled in for one-way delay estimates;
+// over TCP the same happens to each length-prefixed frame.
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use crate::cli::ServerArgs;
+use crate::prober::echo;
+
+// Pause after a failed accept, e.g. while out of file descriptors, so the
+// loop doesn't spin on an error that persists for a while
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Answer echo probes until `shutdown` resolves
+pub async fn run(args: ServerArgs, shutdown: impl Future<Output = ()>) -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env()
+                         .add_directive("latency-probe=info".parse()?))
+        .init();
+
+    let addr = SocketAddr::new(args.bind, args.port);
+    let udp = UdpSocket::bind(addr).await.with_context(|| format!("cannot bind UDP {}", addr))?;
+    let tcp = match args.no_tcp {
+        true => None,
+        false => Some(TcpListener::bind(addr).await.with_context(|| format!("cannot bind TCP {}", addr))?),
+    };
+    println!("Echo responder listening on {} (UDP{})", addr, if tcp.is_some() { " and TCP" } else { "" });
+
+    tokio::select! {
+        result = serve_udp(udp, args.reflect_bytes) => result,
+        result = serve_tcp(tcp, args.reflect_bytes) => result,
+        _ = shutdown => {
+            println!("Shutting down echo responder");
+            Ok(())
+        }
+    }
+}
+
+async fn serve_udp(socket: UdpSocket, reflect_bytes: Option<usize>) -> Result<()> {
+    let mut buf = vec![0u8; echo::MAX_PAYLOAD_SIZE];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // e.g. ICMP port unreachable from an earlier reply
+            Err(e) => {
+                tracing::debug!("UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        let received_ns = echo::unix_nanos();
+        let len = reflect_bytes.map_or(len, |max| len.min(max));
+        echo::stamp_reply(&mut buf[..len], received_ns);
+        if let Err(e) = socket.send_to(&buf[..len], peer).await {
+            tracing::debug!("UDP echo to {} failed: {}", peer, e);
+        }
+    }
+}
+
+async fn serve_tcp(listener: Option<TcpListener>, reflect_bytes: Option<usize>) -> Result<()> {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    loop {
+        let (conn, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("TCP accept failed: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        tracing::debug!("TCP echo connection from {}", peer);
+        tokio::spawn(async move {
+            if let Err(e) = echo_frames(conn, reflect_bytes).await {
+                tracing::debug!("TCP echo connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Reflect frames until the peer closes the connection
+async fn echo_frames(mut conn: TcpStream, reflect_bytes: Option<usize>) -> std::io::Result<()> {
+    conn.set_nodelay(true)?;
+    let mut buf = vec![0u8; 4 + echo::MAX_PAYLOAD_SIZE];
+    loop {
+        let len = match conn.read_u32().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if len > echo::MAX_PAYLOAD_SIZE {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, format!("frame of {} bytes", len)));
+        }
+        conn.read_exact(&mut buf[4..4 + len]).await?;
+        let received_ns = echo::unix_nanos();
+        let reflected = reflect_bytes.map_or(len, |max| len.min(max));
+        buf[..4].copy_from_slice(&(reflected as u32).to_be_bytes());
+        echo::stamp_reply(&mut buf[4..4 + reflected], received_ns);
+        conn.write_all(&buf[..4 + reflected]).await?;
+    }
+}
@@ -0,0 +1,183 @@
+// Service-manager integration: Unix daemonization with pid/log files and a
+// Windows service control handler. Containers don't need any of this.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ServiceOptions {
+    /// Detach from the terminal and run in the background (Unix)
+    #[cfg_attr(not(unix), allow(dead_code))]
+    #[arg(long, env = "DAEMONIZE", global = true)]
+    pub daemonize: bool,
+    /// Write the process id to this file while running
+    #[arg(long, env = "PID_FILE", value_name = "FILE", global = true)]
+    pub pid_file: Option<PathBuf>,
+    /// Append output to this file when daemonized (Unix)
+    #[cfg_attr(not(unix), allow(dead_code))]
+    #[arg(long, env = "LOG_FILE", value_name = "FILE", global = true)]
+    pub log_file: Option<PathBuf>,
+    /// Run under the Windows service control manager
+    #[cfg_attr(not(windows), allow(dead_code))]
+    #[arg(long, env = "RUN_AS_WINDOWS_SERVICE", global = true)]
+    pub windows_service: bool,
+}
+
+/// Pid file that is removed again when the process shuts down cleanly
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| anyhow::anyhow!("Could not write pid file {}: {}", path.display(), e))?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Detach from the controlling terminal (double fork + setsid) and redirect
+/// stdio to the log file, or /dev/null if none is configured.
+/// Must run before the tokio runtime starts any threads.
+/// The working directory is kept so relative config paths keep working.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    // Open the log file before forking so errors still reach the terminal
+    let out = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Could not open log file {}: {}", path.display(), e))?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let dev_null = OpenOptions::new().read(true).open("/dev/null")?;
+
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(std::io::Error::last_os_error().into()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        match libc::fork() {
+            -1 => return Err(std::io::Error::last_os_error().into()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        libc::umask(0o027);
+
+        libc::dup2(dev_null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(out.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(out.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    Ok(())
+}
+
+/// Resolves when the process is asked to stop (Ctrl-C, or SIGTERM on Unix)
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(windows)]
+pub mod windows {
+    use std::ffi::OsString;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use tokio::sync::watch;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+    use crate::cli::RunArgs;
+
+    pub const SERVICE_NAME: &str = "latency-probe";
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    // The service entry point takes no arguments of ours
+    static RUN_ARGS: OnceLock<RunArgs> = OnceLock::new();
+
+    /// Hand the process over to the Windows service control manager.
+    /// Blocks until the service is stopped.
+    pub fn run(args: RunArgs) -> anyhow::Result<()> {
+        let _ = RUN_ARGS.set(args);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+        Ok(())
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            eprintln!("latency-probe service failed: {:?}", e);
+        }
+    }
+
+    fn run_service() -> anyhow::Result<()> {
+        let (stop_tx, mut stop_rx) = watch::channel(false);
+
+        let event_handler = move |control_event| -> ServiceControlHandlerResult {
+            match control_event {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    let _ = stop_tx.send(true);
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            }
+        };
+        let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+
+        let status = |state: ServiceState, controls: ServiceControlAccept| ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: controls,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        };
+        status_handle.set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ))?;
+
+        let args = RUN_ARGS.get().cloned().ok_or_else(|| anyhow::anyhow!("service started without arguments"))?;
+        let result = crate::build_runtime()?.block_on(crate::run(args, async move {
+            let _ = stop_rx.wait_for(|stopped| *stopped).await;
+        }));
+
+        status_handle.set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()))?;
+        result
+    }
+}
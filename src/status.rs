@@ -0,0 +1,137 @@
+// Last outcome per target for the /status endpoint: an event bus consumer
+// keeping the few fields worth a quick `curl | jq` when debugging a target,
+// without parsing the Prometheus exposition format.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use crate::config::TargetConfig;
+use crate::events::ProbeEvent;
+use crate::prober;
+use crate::timestamp::monotonic_ns;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TargetStatus {
+    pub target: String,
+    // Set for each address of a target with probe_all_addresses
+    pub address: Option<IpAddr>,
+    pub probe_type: &'static str,
+    // "success" or "failure"; null while the first probe runs
+    pub last_result: Option<&'static str>,
+    // Unix time in seconds of the last probe, success and failure
+    pub last_probe: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_failure: Option<u64>,
+    // Of the last successful probe
+    pub last_latency_ms: Option<f64>,
+    // Address the last successful probe reached, when the probe knows it
+    pub last_address: Option<String>,
+    // Reason label and error message of the last failure
+    pub last_failure_reason: Option<&'static str>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u64,
+    // Id of the probe running right now and how long it has been running
+    pub running_probe_id: Option<u64>,
+    pub running_ms: Option<f64>,
+    #[serde(skip)]
+    running_since_ns: u128,
+}
+
+impl TargetStatus {
+    /// Probe `probe_id` returned; an overlapping later probe keeps running
+    fn finished(&mut self, probe_id: u64) {
+        if self.running_probe_id == Some(probe_id) {
+            self.running_probe_id = None;
+        }
+    }
+}
+
+type StatusKey = (String, Option<IpAddr>, &'static str);
+
+// Keyed by (target, address, probe_type), sorted so the output is stable
+static STATUS: Lazy<Mutex<BTreeMap<StatusKey, TargetStatus>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Status of every target probed so far, sorted by target
+pub fn snapshot() -> Vec<TargetStatus> {
+    let now = monotonic_ns();
+    STATUS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|s| TargetStatus {
+            running_ms: s.running_probe_id.map(|_| now.saturating_sub(s.running_since_ns) as f64 / 1e6),
+            ..s.clone()
+        })
+        .collect()
+}
+
+/// Forget targets that are no longer configured as they were, and addresses
+/// they no longer resolve to
+pub fn retain_targets(targets: &[TargetConfig]) {
+    STATUS.lock().unwrap().retain(|(target, address, probe_type), _| prober::is_current(targets, target, *address, probe_type));
+}
+
+fn entry<'a>(
+    status: &'a mut BTreeMap<StatusKey, TargetStatus>,
+    target: String,
+    address: Option<IpAddr>,
+    probe_type: &'static str,
+) -> &'a mut TargetStatus {
+    status.entry((target.clone(), address, probe_type)).or_insert_with(|| TargetStatus {
+        target,
+        address,
+        probe_type,
+        ..Default::default()
+    })
+}
+
+pub async fn consume_events(mut rx: broadcast::Receiver<ProbeEvent>) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                crate::metrics::events_dropped("status", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        // Event timestamps are monotonic; the endpoint reports wall clock time
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut status = STATUS.lock().unwrap();
+        match event {
+            ProbeEvent::Started { target, address, probe_type, probe_id, timestamp_ns } => {
+                let s = entry(&mut status, target, address, probe_type);
+                s.running_probe_id = Some(probe_id);
+                s.running_since_ns = timestamp_ns;
+            }
+            ProbeEvent::Completed { target, address, probe_type, probe_id, latency, details, .. } => {
+                let s = entry(&mut status, target, address, probe_type);
+                s.finished(probe_id);
+                s.last_result = Some("success");
+                s.last_probe = Some(now);
+                s.last_success = Some(now);
+                s.last_latency_ms = Some(latency.as_secs_f64() * 1000.0);
+                if let Some(address) = details.address {
+                    s.last_address = Some(address.to_string());
+                }
+                s.consecutive_failures = 0;
+            }
+            ProbeEvent::Failed { target, address, probe_type, probe_id, reason, error, .. } => {
+                let s = entry(&mut status, target, address, probe_type);
+                s.finished(probe_id);
+                s.last_result = Some("failure");
+                s.last_probe = Some(now);
+                s.last_failure = Some(now);
+                s.last_failure_reason = Some(reason);
+                s.last_error = Some(error);
+                s.consecutive_failures += 1;
+            }
+            _ => {}
+        }
+    }
+}
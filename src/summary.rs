@@ -0,0 +1,113 @@
+// Prometheus summary for the prometheus crate, which only has histograms.
+// Quantiles are computed over the samples of a sliding time window when the
+// registry is gathered, so each series costs a fixed number of samples in the
+// TSDB however many quantiles or buckets a histogram would have needed.
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile, Summary};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Per series; at a 100ms probe interval this covers ~16 minutes
+const MAX_SAMPLES: usize = 10_000;
+
+#[derive(Default)]
+struct Series {
+    // (observed at, value), oldest first, none older than max_age
+    window: VecDeque<(Instant, f64)>,
+    // Since start, like a histogram's _count and _sum
+    count: u64,
+    sum: f64,
+}
+
+impl Series {
+    fn prune(&mut self, now: Instant, max_age: Duration) {
+        while self.window.front().is_some_and(|(at, _)| now.duration_since(*at) > max_age) {
+            self.window.pop_front();
+        }
+    }
+}
+
+/// A summary metric family partitioned by label values
+#[derive(Clone)]
+pub struct SummaryVec {
+    desc: Arc<Desc>,
+    quantiles: Arc<Vec<f64>>,
+    max_age: Duration,
+    series: Arc<Mutex<HashMap<Vec<String>, Series>>>,
+}
+
+impl SummaryVec {
+    pub fn new(name: &str, help: &str, label_names: &[&str], quantiles: &[f64], max_age: Duration) -> prometheus::Result<Self> {
+        let label_names = label_names.iter().map(|n| n.to_string()).collect();
+        Ok(Self {
+            desc: Arc::new(Desc::new(name.to_string(), help.to_string(), label_names, HashMap::new())?),
+            quantiles: Arc::new(quantiles.to_vec()),
+            max_age,
+            series: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn observe(&self, label_values: &[String], value: f64) {
+        let now = Instant::now();
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry(label_values.to_vec()).or_default();
+        series.prune(now, self.max_age);
+        if series.window.len() == MAX_SAMPLES {
+            series.window.pop_front();
+        }
+        series.window.push_back((now, value));
+        series.count += 1;
+        series.sum += value;
+    }
+
+    /// Drop the series whose label values don't pass `keep`
+    pub fn retain(&self, keep: impl Fn(&[String]) -> bool) {
+        self.series.lock().unwrap().retain(|label_values, _| keep(label_values));
+    }
+}
+
+impl Collector for SummaryVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = Instant::now();
+        let mut metrics = Vec::new();
+        for (label_values, series) in self.series.lock().unwrap().iter_mut() {
+            series.prune(now, self.max_age);
+            let mut sorted: Vec<f64> = series.window.iter().map(|(_, v)| *v).collect();
+            sorted.sort_by(f64::total_cmp);
+
+            let mut summary = Summary::default();
+            summary.set_sample_count(series.count);
+            summary.set_sample_sum(series.sum);
+            summary.set_quantile(self.quantiles.iter().map(|&q| {
+                let mut quantile = Quantile::default();
+                quantile.set_quantile(q);
+                // NaN when nothing was observed within max_age, as client_golang does
+                quantile.set_value(crate::history::percentile(&sorted, q).unwrap_or(f64::NAN));
+                quantile
+            }).collect());
+
+            let labels = self.desc.variable_labels.iter().zip(label_values).map(|(name, value)| {
+                let mut pair = LabelPair::default();
+                pair.set_name(name.clone());
+                pair.set_value(value.clone());
+                pair
+            }).collect();
+            let mut metric = Metric::from_label(labels);
+            metric.set_summary(summary);
+            metrics.push(metric);
+        }
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::SUMMARY);
+        family.set_metric(metrics);
+        vec![family]
+    }
+}
@@ -1,6 +1,8 @@
 use std::time::{Duration, SystemTime};
+#[cfg(target_os = "linux")]
 use libc::{clock_gettime, timespec, CLOCK_MONOTONIC_RAW};
 
+#[cfg(target_os = "linux")]
 pub fn monotonic_ns() -> u128 {
     unsafe {
         let mut ts: timespec = std::mem::zeroed();
@@ -15,3 +17,15 @@ pub fn monotonic_ns() -> u128 {
         }
     }
 }
+
+// CLOCK_MONOTONIC_RAW is Linux-only; elsewhere fall back to std's monotonic clock
+#[cfg(not(target_os = "linux"))]
+pub fn monotonic_ns() -> u128 {
+    static ANCHOR: once_cell::sync::Lazy<(std::time::Instant, u128)> = once_cell::sync::Lazy::new(|| {
+        let wall = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        (std::time::Instant::now(), wall.as_nanos())
+    });
+    ANCHOR.1 + ANCHOR.0.elapsed().as_nanos()
+}
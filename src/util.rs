@@ -1,28 +1,124 @@
 // Placeholder for helper functions, e.g. host/ip resolution, parsing, etc.
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-pub fn parse_host_port(s: &str, default_port: u16) -> (String, u16) {
-    if let Some(idx) = s.rfind(':') {
-        if let Ok(port) = s[idx+1..].parse::<u16>() {
-            return (s[..idx].to_string(), port);
+/// Which resolved addresses a target is probed over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    /// Whatever the resolver returns first
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl AddressFamily {
+    /// Drop or reorder resolved addresses according to the preference; the
+    /// resolver's order is kept within a family
+    pub fn arrange(self, mut addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        match self {
+            AddressFamily::Any => {}
+            AddressFamily::Ipv4 => addrs.retain(IpAddr::is_ipv4),
+            AddressFamily::Ipv6 => addrs.retain(IpAddr::is_ipv6),
+            AddressFamily::PreferIpv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+            AddressFamily::PreferIpv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
         }
+        addrs
+    }
+}
+
+/// A hostname that did not resolve to any usable address
+#[derive(Debug)]
+pub struct ResolveError(pub String);
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// `host` as an IP address, with or without IPv6 brackets; None for a name
+pub fn ip_literal(host: &str) -> Option<IpAddr> {
+    let literal = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    literal.parse().ok()
+}
+
+/// All addresses of `host` usable under `family`, most preferred first
+pub async fn resolve_host(host: &str, family: AddressFamily) -> Result<Vec<IpAddr>> {
+    let addrs = if let Some(ip) = ip_literal(host) {
+        vec![ip]
+    } else {
+        // If parsing fails, resolve via DNS
+        tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|e| ResolveError(format!("Could not resolve hostname {}: {}", host, e)))?
+            .map(|a| a.ip())
+            .collect()
+    };
+    let addrs = family.arrange(addrs);
+    if addrs.is_empty() {
+        return Err(ResolveError(format!("Could not resolve hostname: {} ({:?})", host, family)).into());
+    }
+    Ok(addrs)
+}
+
+/// Wildcard bind address of the same family as `ip`
+pub fn unspecified_for(ip: IpAddr) -> SocketAddr {
+    match ip {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// Non-cryptographic random value, good enough for SPIs, nonces and jitter
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(crate::timestamp::monotonic_ns());
+    hasher.finish()
+}
+
+/// RFC 1071 one's complement checksum, as used by IP, ICMP and TCP
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut out = [0u8; N];
+    for chunk in out.chunks_mut(8) {
+        let r = random_u64().to_le_bytes();
+        chunk.copy_from_slice(&r[..chunk.len()]);
     }
-    (s.to_string(), default_port)
+    out
 }
 
-pub async fn resolve_host_to_ip(host: &str) -> Result<IpAddr> {
-    // First try to parse as IP address
-    if let Ok(ip) = host.parse::<IpAddr>() {
-        return Ok(ip);
+/// `window` label value: 300 is "5m", 86400 "24h", 90 "90s"
+pub fn window_label(window_sec: u64) -> String {
+    if window_sec.is_multiple_of(3600) {
+        format!("{}h", window_sec / 3600)
+    } else if window_sec.is_multiple_of(60) {
+        format!("{}m", window_sec / 60)
+    } else {
+        format!("{}s", window_sec)
     }
-    
-    // If parsing fails, resolve via DNS
-    let addr = format!("{}:0", host);
-    let mut addrs = tokio::net::lookup_host(&addr).await?;
-    Ok(addrs
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Could not resolve hostname: {}", host))?
-        .ip())
 }
@@ -0,0 +1,193 @@
+// Protection for the /metrics endpoint: TLS and basic auth from a web config
+// file in the format of Prometheus' exporter-toolkit, so the same file works
+// for node_exporter and this probe, plus an optional bearer token.
+// https://prometheus.io/docs/prometheus/latest/configuration/https/
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, SupportedProtocolVersion};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WebConfig {
+    #[serde(default)]
+    pub tls_server_config: Option<TlsServerConfig>,
+    // user -> bcrypt hash of the password
+    #[serde(default)]
+    pub basic_auth_users: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsServerConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    // CA for client certificates, required with client_auth_type RequireAndVerifyClientCert
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+    // NoClientCert (default), VerifyClientCertIfGiven or RequireAndVerifyClientCert
+    #[serde(default)]
+    pub client_auth_type: Option<String>,
+    // TLS12 (default) or TLS13
+    #[serde(default)]
+    pub min_version: Option<String>,
+}
+
+/// Who may read the endpoint; everyone when no users or token are set
+pub struct Auth {
+    users: HashMap<String, String>,
+    // METRICS_BEARER_TOKEN, or METRICS_BEARER_TOKEN_FILE re-read per request
+    token: Option<String>,
+    token_file: Option<String>,
+    // user:password pairs already checked against their bcrypt hash, which is
+    // deliberately slow to compute on every scrape
+    verified: Mutex<HashSet<String>>,
+}
+
+impl WebConfig {
+    /// The web config file, or an unprotected endpoint without one
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading web config {}", path))?;
+        let config: Self = serde_yaml::from_str(&content).with_context(|| format!("parsing web config {}", path))?;
+        for (user, hash) in &config.basic_auth_users {
+            // Catch plain-text passwords at startup rather than on the first scrape
+            if hash.parse::<bcrypt::HashParts>().is_err() {
+                anyhow::bail!("web config {}: password of {:?} is not a bcrypt hash", path, user);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The acceptor for HTTPS, or None to serve plain HTTP
+    pub fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>> {
+        let Some(tls) = &self.tls_server_config else {
+            return Ok(None);
+        };
+        let certs = CertificateDer::pem_file_iter(&tls.cert_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("reading cert_file {}", tls.cert_file))?;
+        let key = PrivateKeyDer::from_pem_file(&tls.key_file)
+            .with_context(|| format!("reading key_file {}", tls.key_file))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        static TLS12_AND_UP: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13, &rustls::version::TLS12];
+        static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+        let versions = match tls.min_version.as_deref() {
+            None | Some("TLS12") => TLS12_AND_UP,
+            Some("TLS13") => TLS13_ONLY,
+            Some(other) => anyhow::bail!("min_version {:?} must be TLS12 or TLS13", other),
+        };
+        let builder = ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(versions)?;
+
+        let client_auth_type = tls.client_auth_type.as_deref().unwrap_or("NoClientCert");
+        let builder = match (client_auth_type, &tls.client_ca_file) {
+            ("NoClientCert", _) => builder.with_no_client_auth(),
+            ("VerifyClientCertIfGiven" | "RequireAndVerifyClientCert", Some(ca_file)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(ca_file).with_context(|| format!("reading client_ca_file {}", ca_file))? {
+                    roots.add(cert.with_context(|| format!("reading client_ca_file {}", ca_file))?)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if client_auth_type == "VerifyClientCertIfGiven" {
+                    verifier.allow_unauthenticated().build()?
+                } else {
+                    verifier.build()?
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            ("VerifyClientCertIfGiven" | "RequireAndVerifyClientCert", None) => {
+                anyhow::bail!("client_auth_type {} needs client_ca_file", client_auth_type)
+            }
+            (other, _) => anyhow::bail!(
+                "client_auth_type {:?} must be NoClientCert, VerifyClientCertIfGiven or RequireAndVerifyClientCert",
+                other,
+            ),
+        };
+        let config = builder.with_single_cert(certs, key).context("loading the TLS certificate")?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// Basic auth users from the file and the bearer token from
+    /// METRICS_BEARER_TOKEN or METRICS_BEARER_TOKEN_FILE
+    pub fn auth(&self) -> Auth {
+        Auth {
+            users: self.basic_auth_users.clone(),
+            token: std::env::var("METRICS_BEARER_TOKEN").ok(),
+            token_file: std::env::var("METRICS_BEARER_TOKEN_FILE").ok(),
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Auth {
+    pub fn is_open(&self) -> bool {
+        self.users.is_empty() && self.token.is_none() && self.token_file.is_none()
+    }
+
+    /// Whether the Authorization header grants access
+    pub async fn allows(&self, authorization: Option<&str>) -> bool {
+        if self.is_open() {
+            return true;
+        }
+        let Some(authorization) = authorization else {
+            return false;
+        };
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return match self.bearer_token().await {
+                Some(expected) => constant_time_eq(token.trim().as_bytes(), expected.as_bytes()),
+                None => false,
+            };
+        }
+        let Some(credentials) = authorization.strip_prefix("Basic ")
+            .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        else {
+            return false;
+        };
+        let Some((user, password)) = credentials.split_once(':') else {
+            return false;
+        };
+        let Some(hash) = self.users.get(user) else {
+            return false;
+        };
+        if self.verified.lock().unwrap().contains(&credentials) {
+            return true;
+        }
+        let (password, hash) = (password.to_string(), hash.clone());
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        if valid {
+            self.verified.lock().unwrap().insert(credentials);
+        }
+        valid
+    }
+
+    async fn bearer_token(&self) -> Option<String> {
+        match &self.token_file {
+            Some(file) => match tokio::fs::read_to_string(file).await {
+                Ok(token) => Some(token.trim().to_string()),
+                Err(e) => {
+                    tracing::warn!("Cannot read METRICS_BEARER_TOKEN_FILE {}: {}", file, e);
+                    None
+                }
+            },
+            None => self.token.clone(),
+        }
+    }
+}
+
+/// Compare without returning early, so response times don't reveal how much
+/// of a token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}